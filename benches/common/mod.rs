@@ -0,0 +1,48 @@
+//! Allocation counting shared by the benches.
+//!
+//! Only allocations made by Rust code are counted: Lua allocates its tables
+//! and strings with its own allocator, which isn't the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+pub struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Runs `f` once and returns the number of allocations and reallocations it
+/// made.
+pub fn allocations<R, F: FnOnce() -> R>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = f();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(result);
+    after - before
+}
+
+/// Checks that `f` makes no more allocations than `baseline`, so that
+/// regressions show up even when timings are noisy.
+pub fn assert_allocations<R, F: FnOnce() -> R>(name: &str, baseline: usize, f: F) {
+    let count = allocations(f);
+    assert!(
+        count <= baseline,
+        "{}: {} allocations, baseline is {}", name, count, baseline,
+    );
+}
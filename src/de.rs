@@ -1,21 +1,501 @@
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::result::Result as StdResult;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
 use serde;
 use serde::de::IntoDeserializer;
 
-use rlua::{Value, TablePairs, TableSequence};
+use rlua::{Value, MultiValue, Table, TablePairs, TableSequence, Error as LuaError};
+
+use {EnumRepr, KeyTransform, ARRAY_MARKER, DEFAULT_MAX_DEPTH};
+use error::{Error, Result, PathSegment};
+use ser::key_order;
+use value::integral_float;
+
+
+/// Options controlling how Lua values are deserialized.
+#[derive(Clone, Default)]
+pub struct DeserializerOptions {
+    /// Deserialize structs by looking up each declared field with
+    /// `Table::raw_get` before iterating over the remaining pairs of the
+    /// table, instead of handing every pair to the visitor in table order.
+    ///
+    /// Keys which are not declared fields, such as field aliases or unknown
+    /// fields rejected with `#[serde(deny_unknown_fields)]`, are still seen
+    /// by the visitor, after the declared fields, so the result is the same.
+    pub struct_field_lookup: bool,
+    /// Accept Lua strings where a number is expected and parse them.
+    ///
+    /// Leading and trailing whitespace is ignored and an optional `+` or
+    /// `-` sign is accepted. Integer targets only accept decimal digits:
+    /// radix prefixes such as `0x2a`, exponents and fractional parts such
+    /// as `42.0` are rejected. Float targets accept anything `f64::from_str`
+    /// does.
+    pub coerce_numbers_from_str: bool,
+    /// Accept numbers where a string is expected, formatted in decimal, e.g.
+    /// so that a table with integer keys deserializes into a
+    /// `HashMap<String, T>`.
+    pub coerce_str_from_numbers: bool,
+    /// Accept a number as an enum value, selecting the unit variant with
+    /// that zero-based index in declaration order.
+    ///
+    /// Both integers and integral floats (as produced by Lua 5.1, where all
+    /// numbers are floats) are accepted. Fractional numbers are an error.
+    pub variant_index: bool,
+    /// Variants to select from a boolean, as `(if_true, if_false)`, by enum
+    /// name, for Lua data modelling a choice between two variants as a flag.
+    /// For example mapping `"Mode"` to `("Fast", "Safe")` deserializes `true`
+    /// into `Mode::Fast`.
+    pub bool_variants: HashMap<&'static str, (&'static str, &'static str)>,
+    /// Variant to read a table into, by enum name, when it isn't of the form
+    /// `{ Variant = payload }`, taking the whole table as its payload. For
+    /// example mapping `"Source"` to `"Inline"` reads `{ text = "..." }` as
+    /// `Source::Inline { text: "..." }`. Only for the default, externally
+    /// tagged representation.
+    pub default_variants: HashMap<&'static str, &'static str>,
+    /// Transforms every string map key before it is matched against struct
+    /// field names or deserialized, e.g. to convert `camelCase` keys back to
+    /// `snake_case`. This is the inverse of `SerializerOptions::key_transform`.
+    ///
+    /// When set, `struct_field_lookup` is ignored since the Lua key of a
+    /// field can't be derived from its name.
+    pub key_transform: Option<KeyTransform>,
+    /// Keys to read the elements of tuple structs from, by struct name, when
+    /// they are given as a table with named fields instead of a sequence.
+    ///
+    /// For example mapping `"Point"` to `&["x", "y"]` lets `struct Point(i32,
+    /// i32)` be deserialized from both `{ 1, 2 }` and `{ x = 1, y = 2 }`.
+    /// Tables with a sequence part are always read positionally.
+    pub tuple_struct_keys: HashMap<&'static str, &'static [&'static str]>,
+    /// What to do when a Lua integer doesn't fit into the target integer type.
+    pub on_int_overflow: OverflowPolicy,
+    /// Ignore elements of a sequence left over once the target, such as a
+    /// tuple, has read all it needs, instead of failing with an invalid
+    /// length error.
+    pub ignore_extra_seq_elements: bool,
+    /// Fail on tables read as a sequence which also hold keys outside of
+    /// it, such as `foo` in `{ 1, 2, foo = "bar" }`, instead of silently
+    /// ignoring them. The `n` field is allowed with `seq_length_field`.
+    pub strict_seq: bool,
+    /// Deserialize maps from a sequence of `{ key, value }` pairs, in any
+    /// order, as written by `SerializerOptions::map_as_sorted_pairs`.
+    ///
+    /// Structs are read from pairs too, as written by
+    /// `SerializerOptions::struct_as_ordered_pairs`, when the table has a
+    /// sequence part, and from a keyed table otherwise.
+    pub map_from_pairs: bool,
+    /// Read tables without a sequence part, where a sequence is expected, as
+    /// a sequence of their `(key, value)` entries, e.g. a keyed table into a
+    /// `Vec<(String, i32)>`. Entries come in the order of
+    /// `SerializerOptions::map_as_sorted_pairs`: numeric keys first in
+    /// ascending order, then string keys in byte order, then any others.
+    pub map_entries_as_seq: bool,
+    /// Also accept enums written positionally as `{ "Variant", payload }`,
+    /// or `{ "Variant" }` for unit variants, besides `{ Variant = payload }`.
+    /// Same as `enum_repr: EnumRepr::Positional`, which replaces it.
+    #[deprecated(note = "use `enum_repr: EnumRepr::Positional` instead")]
+    pub positional_enums: bool,
+    /// How enum variants are represented. Variant names as strings are
+    /// accepted for unit variants with any representation.
+    pub enum_repr: EnumRepr,
+    /// Fail when a Lua integer deserialized into `f32` or `f64` can't be
+    /// represented exactly, e.g. `16_777_217` into an `f32`, instead of
+    /// rounding it.
+    pub strict_float_precision: bool,
+    /// Accept a non-table payload for struct variants with a single field,
+    /// as the value of that field, e.g. `{ Move = 5 }` for `Move { x: u32 }`.
+    pub scalar_struct_variant_payload: bool,
+    /// Maximum nesting of tables, to fail cleanly on deeply nested input
+    /// instead of overflowing the stack, such as a table containing itself
+    /// read into a recursive type. `DEFAULT_MAX_DEPTH` if `None`; use
+    /// `Some(usize::MAX)` to lift the limit for data known to be deep.
+    pub max_depth: Option<usize>,
+    /// Maximum total number of sequence elements and map entries read, to
+    /// bound the work done on untrusted input. Ignored values, such as
+    /// unknown struct fields, are not read. Unlimited if `None`.
+    pub max_elements: Option<usize>,
+    /// Read sequences holding an integer `n` field as being `n` elements
+    /// long, as written by `table.pack` and `SerializerOptions::seq_length_field`,
+    /// so that nil elements are read as such instead of ending the sequence.
+    /// Elements past `n` are ignored, and an `n` more than 1024 past the
+    /// table's raw length fails rather than reading that many nils.
+    pub seq_length_field: bool,
+    /// Fail on maps with distinct keys which format to the same string, such
+    /// as `1` and `"1"`, instead of silently keeping only one of the entries
+    /// when they are coerced to the same key with `coerce_str_from_numbers`
+    /// or `coerce_numbers_from_str`.
+    pub reject_key_collisions: bool,
+    /// Read the tables written by `SerializerOptions::compact`: structs from
+    /// sequences of their field values in declaration order, and enum
+    /// variants from their zero-based index or `{ index, payload }`.
+    pub compact: bool,
+    /// Read nil as an empty sequence or map where one is expected, e.g. a nil
+    /// argument into a `Vec`. Struct fields set to nil are absent from their
+    /// table, so they need `#[serde(default)]` instead.
+    pub nil_as_empty_collection: bool,
+    /// Read a boolean, number or string where a sequence is expected as a
+    /// sequence of that one element, for data which may give either a list
+    /// or a single value, e.g. `tags = "x"` besides `tags = { "x", "y" }`.
+    ///
+    /// nil is not a scalar: it fails as usual, or is read as an empty
+    /// sequence with `nil_as_empty_collection`.
+    pub scalar_as_singleton_seq: bool,
+}
+
+
+/// How to handle a Lua integer which is out of range of the target type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail with an invalid value error.
+    #[default]
+    Error,
+    /// Clamp to the minimum or maximum value of the target type.
+    Saturate,
+    /// Truncate to the target width, as with an `as` cast.
+    Wrap,
+}
+
+trait FromLuaInteger: Sized {
+    fn from_integer(n: i64, policy: OverflowPolicy) -> Result<Self>;
+}
+
+macro_rules! impl_from_lua_integer {
+    ($($ty:ident)*) => {
+        $(
+            impl FromLuaInteger for $ty {
+                fn from_integer(n: i64, policy: OverflowPolicy) -> Result<$ty> {
+                    match $ty::try_from(n) {
+                        Ok(n) => Ok(n),
+                        Err(_) => match policy {
+                            OverflowPolicy::Error => Err(Error::NumberOverflow(format!(
+                                "invalid value: integer `{}`, expected {}", n, stringify!($ty),
+                            ))),
+                            OverflowPolicy::Saturate if n < 0 => Ok($ty::MIN),
+                            OverflowPolicy::Saturate => Ok($ty::MAX),
+                            OverflowPolicy::Wrap => Ok(n as $ty),
+                        },
+                    }
+                }
+            }
+        )*
+    }
+}
+
+impl_from_lua_integer!(i8 i16 i32 i64 u8 u16 u32 u64);
+
+impl fmt::Debug for DeserializerOptions {
+    #[allow(deprecated)]
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DeserializerOptions")
+            .field("struct_field_lookup", &self.struct_field_lookup)
+            .field("coerce_numbers_from_str", &self.coerce_numbers_from_str)
+            .field("coerce_str_from_numbers", &self.coerce_str_from_numbers)
+            .field("variant_index", &self.variant_index)
+            .field("bool_variants", &self.bool_variants)
+            .field("default_variants", &self.default_variants)
+            .field("key_transform", &self.key_transform.as_ref().map(|_| "Fn"))
+            .field("tuple_struct_keys", &self.tuple_struct_keys)
+            .field("on_int_overflow", &self.on_int_overflow)
+            .field("ignore_extra_seq_elements", &self.ignore_extra_seq_elements)
+            .field("strict_seq", &self.strict_seq)
+            .field("map_from_pairs", &self.map_from_pairs)
+            .field("map_entries_as_seq", &self.map_entries_as_seq)
+            .field("positional_enums", &self.positional_enums)
+            .field("enum_repr", &self.enum_repr)
+            .field("strict_float_precision", &self.strict_float_precision)
+            .field("scalar_struct_variant_payload", &self.scalar_struct_variant_payload)
+            .field("max_depth", &self.max_depth)
+            .field("max_elements", &self.max_elements)
+            .field("seq_length_field", &self.seq_length_field)
+            .field("reject_key_collisions", &self.reject_key_collisions)
+            .field("compact", &self.compact)
+            .field("nil_as_empty_collection", &self.nil_as_empty_collection)
+            .field("scalar_as_singleton_seq", &self.scalar_as_singleton_seq)
+            .finish()
+    }
+}
+
+
+/// Conversion of a Lua value to another type applied while deserializing,
+/// as reported by `from_value_with_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coercion {
+    /// Path from the deserialized value to the converted value. Conversions
+    /// of map keys have the path of the map.
+    pub path: Vec<PathSegment>,
+    /// Lua type of the value, e.g. `"string"`.
+    pub from: &'static str,
+    /// What it was converted to, e.g. `"integer"`.
+    pub to: &'static str,
+}
+
+
+/// Options along with the state of a deserialization shared by all nested
+/// deserializers, used to enforce `max_depth` and `max_elements` and to
+/// collect coercions.
+struct State {
+    options: DeserializerOptions,
+    depth: Cell<usize>,
+    elements: Cell<usize>,
+    /// Coercions applied so far, if they are reported.
+    coercions: Option<RefCell<Vec<Coercion>>>,
+}
+
+impl State {
+    fn new(options: DeserializerOptions) -> Self {
+        State { options, depth: Cell::new(0), elements: Cell::new(0), coercions: None }
+    }
+
+    /// Records converting a value of Lua type `from` to `to`.
+    fn coerced(&self, from: &'static str, to: &'static str) {
+        if let Some(ref coercions) = self.coercions {
+            coercions.borrow_mut().push(Coercion { path: Vec::new(), from, to });
+        }
+    }
+
+    /// Number of coercions recorded so far, to pass to `at`.
+    fn coercions_len(&self) -> usize {
+        self.coercions.as_ref().map_or(0, |coercions| coercions.borrow().len())
+    }
+
+    /// Prepends the segment of a table entry to the path of the error from
+    /// deserializing it, or of the coercions recorded since `start`.
+    fn at<T, F>(&self, start: usize, result: Result<T>, segment: F) -> Result<T>
+        where F: FnOnce() -> PathSegment
+    {
+        let t = match result {
+            Ok(t) => t,
+            Err(err) => return Err(Error::at(segment(), err)),
+        };
+        if let Some(ref coercions) = self.coercions {
+            let mut coercions = coercions.borrow_mut();
+            if coercions.len() > start {
+                let segment = segment();
+                for coercion in &mut coercions[start..] {
+                    coercion.path.insert(0, segment.clone());
+                }
+            }
+        }
+        Ok(t)
+    }
+
+    /// Records entering a table until the returned guard is dropped.
+    fn enter(self: &Rc<Self>) -> Result<DepthGuard> {
+        let depth = self.depth.get() + 1;
+        let max_depth = self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        if depth > max_depth {
+            return Err(serde::de::Error::custom(format_args!(
+                "recursion limit exceeded: more than {} nested tables", max_depth,
+            )));
+        }
+        self.depth.set(depth);
+        Ok(DepthGuard(self.clone()))
+    }
+
+    /// Records reading a sequence element or map entry.
+    fn count_element(&self) -> Result<()> {
+        let elements = self.elements.get() + 1;
+        if let Some(max_elements) = self.options.max_elements {
+            if elements > max_elements {
+                return Err(serde::de::Error::custom(format_args!(
+                    "maximum number of {} elements exceeded", max_elements,
+                )));
+            }
+        }
+        self.elements.set(elements);
+        Ok(())
+    }
+}
+
+impl ::std::ops::Deref for State {
+    type Target = DeserializerOptions;
 
-use error::{Error, Result};
+    fn deref(&self) -> &DeserializerOptions {
+        &self.options
+    }
+}
+
+struct DepthGuard(Rc<State>);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        self.0.depth.set(self.0.depth.get() - 1);
+    }
+}
 
 
+/// Deserializer of a Lua value with the default options.
 pub struct Deserializer<'lua> {
     pub value: Value<'lua>,
 }
 
-impl<'lua, 'de> serde::Deserializer<'de> for Deserializer<'lua> {
+impl<'lua> Deserializer<'lua> {
+    pub fn new(value: Value<'lua>) -> Self {
+        Deserializer { value }
+    }
+
+    /// Creates a deserializer of `value` with the given options.
+    pub fn with_options(value: Value<'lua>, options: DeserializerOptions) -> ConfiguredDeserializer<'lua> {
+        ConfiguredDeserializer::with_options(value, options)
+    }
+}
+
+macro_rules! forward_to_configured {
+    ($($method:ident($($arg:ident: $ty:ty),*))*) => {
+        $(
+            #[inline]
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value>
+                where V: serde::de::Visitor<'lua>
+            {
+                serde::Deserializer::$method(ConfiguredDeserializer::new(self.value), $($arg,)* visitor)
+            }
+        )*
+    }
+}
+
+impl<'lua> serde::Deserializer<'lua> for Deserializer<'lua> {
+    type Error = Error;
+
+    forward_to_configured! {
+        deserialize_any() deserialize_bool() deserialize_char()
+        deserialize_i8() deserialize_i16() deserialize_i32() deserialize_i64() deserialize_i128()
+        deserialize_u8() deserialize_u16() deserialize_u32() deserialize_u64() deserialize_u128()
+        deserialize_f32() deserialize_f64() deserialize_str() deserialize_string()
+        deserialize_bytes() deserialize_byte_buf() deserialize_option() deserialize_unit()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_seq() deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map()
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+        deserialize_identifier() deserialize_ignored_any()
+    }
+}
+
+
+/// Deserializer of a Lua value with `DeserializerOptions`, which are passed
+/// on to the deserializers of nested values.
+pub struct ConfiguredDeserializer<'lua> {
+    value: Value<'lua>,
+    options: Rc<State>,
+}
+
+impl<'lua> ConfiguredDeserializer<'lua> {
+    pub fn new(value: Value<'lua>) -> Self {
+        Self::with_options(value, DeserializerOptions::default())
+    }
+
+    pub fn with_options(value: Value<'lua>, options: DeserializerOptions) -> Self {
+        ConfiguredDeserializer { value, options: Rc::new(State::new(options)) }
+    }
+
+    fn from_parts(value: Value<'lua>, options: &Rc<State>) -> Self {
+        ConfiguredDeserializer { value, options: options.clone() }
+    }
+
+    /// Returns the integer to deserialize into an integer type, or `None` if
+    /// the value should be handed to the visitor as is.
+    fn integer(&self) -> Result<Option<i64>> {
+        match self.value {
+            Value::Integer(n) => Ok(Some(n)),
+            // A common mistake in hand-written data, so name it explicitly.
+            Value::Number(n) if n.fract() != 0.0 && n.is_finite() => {
+                Err(serde::de::Error::custom(format_args!(
+                    "expected integer, got float {} with fractional part", n,
+                )))
+            },
+            // Integral floats are written as `3.0` in Lua source or come from
+            // float arithmetic.
+            Value::Number(n) => match integral_float(n) {
+                Some(n) => {
+                    self.options.coerced("float", "integer");
+                    Ok(Some(n))
+                },
+                None => Err(Error::NumberOverflow(format!(
+                    "expected integer, got float {} out of integer range", n,
+                ))),
+            },
+            Value::String(ref v) if self.options.coerce_numbers_from_str => {
+                let s = v.to_str()?;
+                match s.trim().parse::<i64>() {
+                    Ok(n) => {
+                        self.options.coerced("string", "integer");
+                        Ok(Some(n))
+                    },
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s), &"a decimal integer string",
+                    )),
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks that an integer is exactly representable in the float type,
+    /// given by `exact` which rounds through it, if required by the options.
+    fn check_float_precision(&self, ty: &str, exact: fn(i64) -> bool) -> Result<()> {
+        match self.value {
+            Value::Integer(n) if self.options.strict_float_precision && !exact(n) => {
+                Err(serde::de::Error::custom(format_args!(
+                    "integer {} can't be represented exactly as {}", n, ty,
+                )))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn deserialize_float<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        match self.value {
+            Value::Integer(n) => {
+                self.options.coerced("integer", "float");
+                visitor.visit_f64(n as f64)
+            },
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::String(ref v) if self.options.coerce_numbers_from_str => {
+                let s = v.to_str()?;
+                match s.trim().parse::<f64>() {
+                    Ok(n) => {
+                        self.options.coerced("string", "float");
+                        visitor.visit_f64(n)
+                    },
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s), &"a number string",
+                    )),
+                }
+            },
+            _ => serde::Deserializer::deserialize_any(self, visitor),
+        }
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($method:ident, $ty:ident, $visit:ident) => {
+        #[inline]
+        fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where V: serde::de::Visitor<'lua>
+        {
+            match self.integer()? {
+                Some(n) => visitor.$visit($ty::from_integer(n, self.options.on_int_overflow)?),
+                None => self.deserialize_any(visitor),
+            }
+        }
+    }
+}
+
+impl<'lua> serde::Deserializer<'lua> for ConfiguredDeserializer<'lua> {
     type Error = Error;
 
     #[inline]
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+        where V: serde::de::Visitor<'lua>
     {
         match self.value {
             Value::Nil => visitor.visit_unit(),
@@ -24,23 +504,22 @@ impl<'lua, 'de> serde::Deserializer<'de> for Deserializer<'lua> {
             Value::Number(v) => visitor.visit_f64(v),
             Value::String(v) => visitor.visit_str(v.to_str()?),
             Value::Table(v) => {
-                let len = v.len()? as usize;
-                let mut deserializer = MapDeserializer(v.pairs(), None);
-                let map = visitor.visit_map(&mut deserializer)?;
-                let remaining = deserializer.0.count();
-                if remaining == 0 {
-                    Ok(map)
-                } else {
-                    Err(serde::de::Error::invalid_length(len, &"fewer elements in array"))
+                let _guard = self.options.enter()?;
+                if is_marked_array(&v)? || is_sequence(&v) {
+                    let mut deserializer = SeqDeserializer::new(v, self.options)?;
+                    let seq = visitor.visit_seq(&mut deserializer)?;
+                    deserializer.end()?;
+                    return Ok(seq);
                 }
+                visit_table_map(v, self.options, visitor)
             },
-            _ => Err(serde::de::Error::custom("invalid value type")),
+            ref value => Err(Error::UnsupportedType(type_name(value))),
         }
     }
 
     #[inline]
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+        where V: serde::de::Visitor<'lua>
     {
         match self.value {
             Value::Nil => visitor.visit_none(),
@@ -50,338 +529,3744 @@ impl<'lua, 'de> serde::Deserializer<'de> for Deserializer<'lua> {
 
     #[inline]
     fn deserialize_enum<V>(
-        self, _name: &str, _variants: &'static [&'static str], visitor: V
+        self, name: &str, variants: &'static [&'static str], visitor: V
     ) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
-    {
-        let (variant, value) = match self.value {
-            Value::Table(value) => {
-                let mut iter = value.pairs::<String, Value>();
-                let (variant, value) = match iter.next() {
-                    Some(v) => v?,
-                    None => return Err(serde::de::Error::invalid_value(
-                        serde::de::Unexpected::Map,
-                        &"map with a single key",
-                    )),
+        where V: serde::de::Visitor<'lua>
+    {
+        let _guard = match self.value {
+            Value::Table(_) => Some(self.options.enter()?),
+            _ => None,
+        };
+        #[allow(deprecated)]
+        let positional = self.options.positional_enums || self.options.enum_repr == EnumRepr::Positional;
+        let tagged = match (&self.options.enum_repr, &self.value) {
+            (_, Value::Table(value)) if self.options.compact => {
+                let variant = variant_by_index(variants, value.raw_get(1)?)?;
+                Some((variant, Some(value.raw_get(2)?)))
+            },
+            (EnumRepr::Internal { tag }, Value::Table(value)) => {
+                Some((variant_tag(value, tag)?, Some(self.value.clone())))
+            },
+            (EnumRepr::Adjacent { tag, content }, Value::Table(value)) => {
+                let payload = match value.raw_get(content.as_str())? {
+                    Value::Nil => None,
+                    payload => Some(payload),
                 };
+                Some((variant_tag(value, tag)?, payload))
+            },
+            _ => None,
+        };
+        let (variant, value) = match tagged {
+            Some(tagged) => tagged,
+            None => match self.value {
+                Value::Table(value) if positional && value.raw_len() > 0 => {
+                    let variant = value.raw_get::<_, String>(1)?;
+                    match value.raw_len() {
+                        1 => (variant, None),
+                        2 => (variant, Some(value.raw_get(2)?)),
+                        _ => return Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Seq,
+                            &"sequence of a variant name and a payload",
+                        )),
+                    }
+                },
+                Value::Table(value)
+                    if self.options.default_variants.contains_key(name)
+                        && !is_external_variant(&value, variants)? =>
+                {
+                    (self.options.default_variants[name].to_owned(), Some(Value::Table(value)))
+                },
+                Value::Table(value) => {
+                    let mut iter = value.pairs::<String, Value>();
+                    let (variant, value) = match iter.next() {
+                        Some(v) => v?,
+                        None => return Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Map,
+                            &"map with a single key",
+                        )),
+                    };
 
-                if iter.next().is_some() {
-                    return Err(serde::de::Error::invalid_value(
-                        serde::de::Unexpected::Map,
-                        &"map with a single key",
-                    ))
+                    if iter.next().is_some() {
+                        return Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Map,
+                            &"map with a single key",
+                        ))
+                    }
+                    (variant, Some(value))
                 }
-                (variant, Some(value))
-            }
-            Value::String(variant) => (variant.to_str()?.to_owned(), None),
-            _ => return Err(serde::de::Error::custom("bad enum value")),
+                Value::String(variant) => (variant.to_str()?.to_owned(), None),
+                Value::Integer(index) if self.options.variant_index || self.options.compact => {
+                    (variant_by_index(variants, index)?, None)
+                },
+                Value::Number(index) if self.options.variant_index || self.options.compact => {
+                    if index.fract() != 0.0 {
+                        return Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Float(index), &"an integral variant index",
+                        ));
+                    }
+                    (variant_by_index(variants, index as i64)?, None)
+                },
+                Value::Boolean(flag) if self.options.bool_variants.contains_key(name) => {
+                    let (if_true, if_false) = self.options.bool_variants[name];
+                    ((if flag { if_true } else { if_false }).to_owned(), None)
+                },
+                _ => return Err(serde::de::Error::custom("bad enum value")),
+            },
         };
 
-        visitor.visit_enum(EnumDeserializer { variant, value })
+        visitor.visit_enum(EnumDeserializer { variant, value, options: self.options })
     }
 
     #[inline]
     fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+        where V: serde::de::Visitor<'lua>
     {
+        if self.options.scalar_as_singleton_seq {
+            if let Value::Boolean(_) | Value::Integer(_) | Value::Number(_) | Value::String(_) = self.value {
+                self.options.coerced(type_name(&self.value), "sequence");
+                let mut deserializer = ValuesDeserializer(vec![self.value].into_iter(), self.options);
+                return visitor.visit_seq(&mut deserializer);
+            }
+        }
         match self.value {
+            Value::Table(v) if self.options.map_entries_as_seq && v.raw_len() == 0 => {
+                let _guard = self.options.enter()?;
+                let mut entries = v.pairs::<Value, Value>().collect::<StdResult<Vec<_>, _>>()?;
+                entries.sort_by(|a, b| key_order(&a.0, &b.0));
+                let mut deserializer = EntriesDeserializer {
+                    iter: entries.into_iter(),
+                    options: self.options,
+                };
+                visitor.visit_seq(&mut deserializer)
+            },
             Value::Table(v) => {
-                let len = v.len()? as usize;
-                let mut deserializer = SeqDeserializer(v.sequence_values());
+                let _guard = self.options.enter()?;
+                if self.options.strict_seq {
+                    check_sequence_keys(&v, self.options.seq_length_field)?;
+                }
+                let ignore_extra = self.options.ignore_extra_seq_elements;
+                let mut deserializer = SeqDeserializer::new(v, self.options)?;
                 let seq = visitor.visit_seq(&mut deserializer)?;
-                let remaining = deserializer.0.count();
-                if remaining == 0 {
-                    Ok(seq)
-                } else {
-                    Err(serde::de::Error::invalid_length(len, &"fewer elements in array"))
+                if !ignore_extra {
+                    deserializer.end()?;
                 }
+                Ok(seq)
             }
-            _ => Err(serde::de::Error::custom("invalid value type")),
+            Value::Nil if self.options.nil_as_empty_collection => visitor.visit_seq(
+                serde::de::value::SeqDeserializer::new(::std::iter::empty::<()>())
+            ),
+            ref value => Err(Error::UnsupportedType(type_name(value))),
+        }
+    }
+
+    #[inline]
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        // Hand the value over to `LazyValueVisitor` or `WithSourceVisitor`
+        // through `LAZY_VALUE`, since a visitor can only be given serde's
+        // data model types, along with the token to take it.
+        match name {
+            LAZY_VALUE_TOKEN => hand_over_value(self.value, |token| visitor.visit_u64(token)),
+            WITH_SOURCE_TOKEN => hand_over_value(self.value.clone(), |token| {
+                visitor.visit_seq(WithSourceAccess { token: Some(token), deserializer: Some(self) })
+            }),
+            _ => visitor.visit_newtype_struct(self),
+        }
+    }
+
+    #[inline]
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        match self.value {
+            Value::Table(v) if self.options.map_from_pairs => {
+                let _guard = self.options.enter()?;
+                let mut deserializer = PairsDeserializer {
+                    iter: v.sequence_values(),
+                    value: None,
+                    key: Value::Nil,
+                    options: self.options,
+                };
+                visitor.visit_map(&mut deserializer)
+            },
+            Value::Table(v) => {
+                let _guard = self.options.enter()?;
+                visit_table_map(v, self.options, visitor)
+            },
+            Value::Nil if self.options.nil_as_empty_collection => visitor.visit_map(
+                serde::de::value::MapDeserializer::new(::std::iter::empty::<((), ())>())
+            ),
+            _ => self.deserialize_any(visitor),
         }
     }
 
     #[inline]
     fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+        where V: serde::de::Visitor<'lua>
     {
         self.deserialize_seq(visitor)
     }
 
     #[inline]
-    fn deserialize_tuple_struct<V>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+    fn deserialize_tuple_struct<V>(self, name: &'static str, len: usize, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
+        if let Value::Table(ref v) = self.value {
+            let keys = self.options.tuple_struct_keys.get(name);
+            if let (Some(keys), 0) = (keys, v.raw_len()) {
+                if keys.len() != len {
+                    return Err(serde::de::Error::custom(format_args!(
+                        "{} keys configured for tuple struct {} with {} elements",
+                        keys.len(), name, len,
+                    )));
+                }
+                let _guard = self.options.enter()?;
+                let mut deserializer = KeyedTupleDeserializer {
+                    table: v.clone(),
+                    keys: keys.iter(),
+                    options: self.options.clone(),
+                };
+                return visitor.visit_seq(&mut deserializer);
+            }
+        }
         self.deserialize_seq(visitor)
     }
 
-    forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
-        byte_buf unit unit_struct newtype_struct
-        map struct identifier ignored_any
+    #[inline]
+    fn deserialize_struct<V>(
+        self, _name: &'static str, fields: &'static [&'static str], visitor: V
+    ) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        if let Value::Table(ref table) = self.value {
+            if self.options.map_from_pairs && !self.options.compact && table.raw_len() > 0 {
+                return self.deserialize_map(visitor);
+            }
+        }
+        match self.value {
+            // Read by position, holes included, so no sequence length is needed.
+            Value::Table(table) if self.options.compact => {
+                let _guard = self.options.enter()?;
+                let mut deserializer = SeqDeserializer {
+                    iter: SeqIter::Counted { table, next: 1, len: fields.len() as i64 },
+                    consumed: 0,
+                    options: self.options,
+                };
+                visitor.visit_seq(&mut deserializer)
+            },
+            Value::Table(table)
+                if self.options.struct_field_lookup && self.options.key_transform.is_none() =>
+            {
+                let _guard = self.options.enter()?;
+                let mut deserializer = StructDeserializer::new(table, fields, self.options);
+                let map = visitor.visit_map(&mut deserializer)?;
+                deserializer.rest.end()?;
+                Ok(map)
+            },
+            Value::Table(table) => {
+                let _guard = self.options.enter()?;
+                visit_table_map(table, self.options, visitor)
+            },
+            _ => self.deserialize_any(visitor),
+        }
     }
-}
-
-
-struct SeqDeserializer<'lua>(TableSequence<'lua, Value<'lua>>);
 
-impl<'lua, 'de> serde::de::SeqAccess<'de> for SeqDeserializer<'lua> {
-    type Error = Error;
+    deserialize_integer!(deserialize_i8, i8, visit_i8);
+    deserialize_integer!(deserialize_i16, i16, visit_i16);
+    deserialize_integer!(deserialize_i32, i32, visit_i32);
+    deserialize_integer!(deserialize_i64, i64, visit_i64);
+    deserialize_integer!(deserialize_u8, u8, visit_u8);
+    deserialize_integer!(deserialize_u16, u16, visit_u16);
+    deserialize_integer!(deserialize_u32, u32, visit_u32);
+    deserialize_integer!(deserialize_u64, u64, visit_u64);
 
-    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
-        where T: serde::de::DeserializeSeed<'de>
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
-        match self.0.next() {
-            Some(value) => seed.deserialize(Deserializer { value: value? })
-                               .map(Some),
-            None => Ok(None)
+        if let Value::Table(ref table) = self.value {
+            let (high, low) = int128_words(table)?;
+            return visitor.visit_i128((i128::from(high) << 64) | i128::from(low as u64));
+        }
+        if let Value::String(ref v) = self.value {
+            return visitor.visit_i128(parse_int128(v.to_str()?, "i128")?);
+        }
+        match self.integer()? {
+            Some(n) => visitor.visit_i128(n.into()),
+            None => self.deserialize_any(visitor),
         }
     }
 
-    fn size_hint(&self) -> Option<usize> {
-        match self.0.size_hint() {
-            (lower, Some(upper)) if lower == upper => Some(upper),
-            _ => None,
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        if let Value::Table(ref table) = self.value {
+            let (high, low) = int128_words(table)?;
+            return visitor.visit_u128((u128::from(high as u64) << 64) | u128::from(low as u64));
+        }
+        if let Value::String(ref v) = self.value {
+            return visitor.visit_u128(parse_int128(v.to_str()?, "u128")?);
+        }
+        match self.integer()? {
+            Some(n) => match u128::try_from(n) {
+                Ok(n) => visitor.visit_u128(n),
+                Err(_) => Err(Error::NumberOverflow(format!(
+                    "invalid value: integer `{}`, expected u128", n,
+                ))),
+            },
+            None => self.deserialize_any(visitor),
         }
     }
-}
-
-
-struct MapDeserializer<'lua>(
-    TablePairs<'lua, Value<'lua>, Value<'lua>>,
-    Option<Value<'lua>>
-);
 
-impl<'lua, 'de> serde::de::MapAccess<'de> for MapDeserializer<'lua> {
-    type Error = Error;
-
-    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
-        where T: serde::de::DeserializeSeed<'de>
+    #[inline]
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
-        match self.0.next() {
-            Some(item) => {
-                let (key, value) = item?;
-                self.1 = Some(value);
-                let key_de = Deserializer { value: key };
-                seed.deserialize(key_de).map(Some)
+        self.check_float_precision("f32", |n| n as f32 as i128 == i128::from(n))?;
+        match self.value {
+            // Parse as `f32` directly, since rounding to `f64` first may give
+            // a different result.
+            Value::String(ref v) if self.options.coerce_numbers_from_str => {
+                let s = v.to_str()?;
+                match s.trim().parse::<f32>() {
+                    Ok(n) => {
+                        self.options.coerced("string", "float");
+                        visitor.visit_f32(n)
+                    },
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s), &"a number string",
+                    )),
+                }
             },
-            None => Ok(None),
+            _ => self.deserialize_float(visitor),
         }
     }
 
-    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
-        where T: serde::de::DeserializeSeed<'de>
+    #[inline]
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
-        match self.1.take() {
-            Some(value) => seed.deserialize(Deserializer { value }),
-            None => Err(serde::de::Error::custom("value is missing")),
-        }
+        self.check_float_precision("f64", |n| n as f64 as i128 == i128::from(n))?;
+        self.deserialize_float(visitor)
     }
 
-    fn size_hint(&self) -> Option<usize> {
-        match self.0.size_hint() {
-            (lower, Some(upper)) if lower == upper => Some(upper),
-            _ => None,
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let s = match self.value {
+            Value::String(ref v) => v.to_str()?,
+            _ => return self.deserialize_any(visitor),
+        };
+        let mut chars = s.chars();
+        match (chars.next(), chars.count()) {
+            (Some(c), 0) => visitor.visit_char(c),
+            (None, _) => Err(serde::de::Error::custom(
+                "expected a single character, got an empty string",
+            )),
+            (Some(_), rest) => Err(serde::de::Error::custom(format_args!(
+                "expected a single character, got a string of {} characters", rest + 1,
+            ))),
         }
     }
-}
-
-
-struct EnumDeserializer<'lua> {
-    variant: String,
-    value: Option<Value<'lua>>,
-}
 
-impl<'lua, 'de> serde::de::EnumAccess<'de> for EnumDeserializer<'lua> {
-    type Error = Error;
-    type Variant = VariantDeserializer<'lua>;
-
-    fn variant_seed<T>(self, seed: T) -> Result<(T::Value, Self::Variant)>
-        where T: serde::de::DeserializeSeed<'de>
+    #[inline]
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
-        let variant = self.variant.into_deserializer();
-        let variant_access = VariantDeserializer { value: self.value };
-        seed.deserialize(variant).map(|v| (v, variant_access))
+        self.deserialize_string(visitor)
     }
-}
-
-
-struct VariantDeserializer<'lua> {
-    value: Option<Value<'lua>>,
-}
-
-impl<'lua, 'de> serde::de::VariantAccess<'de> for VariantDeserializer<'lua> {
-    type Error = Error;
 
-    fn unit_variant(self) -> Result<()> {
-        match self.value {
-            Some(_) => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::NewtypeVariant,
-                &"unit variant",
-            )),
-            None => Ok(())
+    #[inline]
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        if self.options.coerce_str_from_numbers {
+            if let Some(s) = number_string(&self.value) {
+                self.options.coerced(type_name(&self.value), "string");
+                return visitor.visit_string(s);
+            }
         }
+        self.deserialize_any(visitor)
     }
 
-    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
-        where T: serde::de::DeserializeSeed<'de>
+    #[inline]
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
         match self.value {
-            Some(value) => seed.deserialize(Deserializer { value }),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"newtype variant",
-            ))
+            Value::String(ref v) => visitor.visit_bytes(v.as_bytes()),
+            _ => self.deserialize_any(visitor),
         }
     }
 
-    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+    #[inline]
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
         match self.value {
-            Some(value) => serde::Deserializer::deserialize_seq(
-                Deserializer { value }, visitor
-            ),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"tuple variant",
-            ))
+            Value::String(ref v) => visitor.visit_byte_buf(v.as_bytes().to_vec()),
+            _ => self.deserialize_any(visitor),
         }
     }
 
-    fn struct_variant<V>(
-        self, _fields: &'static [&'static str], visitor: V
-    ) -> Result<V::Value>
-        where V: serde::de::Visitor<'de>
+    #[inline]
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
     {
         match self.value {
-            Some(value) => serde::Deserializer::deserialize_map(
-                Deserializer { value }, visitor
-            ),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"struct variant",
-            ))
+            Value::String(_) => self.deserialize_any(visitor),
+            _ => Err(Error::KeyMustBeString),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rlua::Lua;
+    /// Ignored values, such as unknown struct fields, are dropped without
+    /// looking into them, so large tables cost nothing and don't count
+    /// towards `max_elements`.
+    #[inline]
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        visitor.visit_unit()
+    }
 
-    use from_value;
+    forward_to_deserialize_any! {
+        <W: Visitor<'lua>>
+        bool unit unit_struct
+    }
+}
+
+
+/// Whether a table is of the form `{ Variant = payload }` for one of
+/// `variants`.
+fn is_external_variant(table: &Table, variants: &[&str]) -> Result<bool> {
+    let mut pairs = table.clone().pairs::<Value, Value>();
+    let named = match pairs.next() {
+        Some(pair) => match pair?.0 {
+            Value::String(ref key) => variants.iter().any(|v| v.as_bytes() == key.as_bytes()),
+            _ => false,
+        },
+        None => false,
+    };
+    Ok(named && pairs.next().is_none())
+}
+
+
+/// Deserializes a value, also returning the coercions applied, for
+/// `from_value_with_report`.
+pub(crate) fn deserialize_with_report<'lua, T>(
+    value: Value<'lua>, options: DeserializerOptions
+) -> Result<(T, Vec<Coercion>)>
+    where T: serde::Deserialize<'lua>
+{
+    let state = Rc::new(State {
+        coercions: Some(RefCell::new(Vec::new())),
+        ..State::new(options)
+    });
+    let t = T::deserialize(ConfiguredDeserializer::from_parts(value, &state))?;
+    let coercions = state.coercions.as_ref().map_or_else(Vec::new, |c| c.replace(Vec::new()));
+    Ok((t, coercions))
+}
+
+
+/// Deserializes a struct field by field, see `from_value_partial`.
+///
+/// A field failing to deserialize makes the visitor fail as a whole, so
+/// deserialization starts over without that field until it succeeds, once
+/// per failing field.
+pub(crate) fn deserialize_partial<'lua, T>(value: Value<'lua>) -> (T, Vec<Error>)
+    where T: Default + serde::Deserialize<'lua>
+{
+    let mut failed = Vec::new();
+    let mut errors = Vec::new();
+    loop {
+        let field = Cell::new(None);
+        let deserializer = PartialDeserializer {
+            value: value.clone(),
+            failed: &failed,
+            field: &field,
+        };
+        match T::deserialize(deserializer) {
+            Ok(t) => return (t, errors),
+            Err(err) => {
+                errors.push(err);
+                match field.get() {
+                    Some(field) => failed.push(field),
+                    None => return (T::default(), errors),
+                }
+            },
+        }
+    }
+}
+
+
+/// Deserializer of a struct which leaves out the fields in `failed` and sets
+/// `field` to the field being read when deserialization fails. Values other
+/// than structs are deserialized as usual.
+struct PartialDeserializer<'a, 'lua> {
+    value: Value<'lua>,
+    failed: &'a [&'static str],
+    field: &'a Cell<Option<&'static str>>,
+}
+
+impl<'a, 'lua> serde::Deserializer<'lua> for PartialDeserializer<'a, 'lua> {
+    type Error = Error;
+
+    forward_to_configured! {
+        deserialize_any() deserialize_bool() deserialize_char()
+        deserialize_i8() deserialize_i16() deserialize_i32() deserialize_i64() deserialize_i128()
+        deserialize_u8() deserialize_u16() deserialize_u32() deserialize_u64() deserialize_u128()
+        deserialize_f32() deserialize_f64() deserialize_str() deserialize_string()
+        deserialize_bytes() deserialize_byte_buf() deserialize_option() deserialize_unit()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_seq() deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map()
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+        deserialize_identifier() deserialize_ignored_any()
+    }
+
+    fn deserialize_struct<V>(
+        self, name: &'static str, fields: &'static [&'static str], visitor: V
+    ) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let table = match self.value {
+            Value::Table(table) => table,
+            value => {
+                let deserializer = ConfiguredDeserializer::new(value);
+                return serde::Deserializer::deserialize_struct(deserializer, name, fields, visitor);
+            },
+        };
+        let options = Rc::new(State::new(DeserializerOptions::default()));
+        let _guard = options.enter()?;
+        visitor.visit_map(PartialFields {
+            table,
+            fields: fields.iter(),
+            failed: self.failed,
+            field: self.field,
+            value: None,
+            options,
+        })
+    }
+}
+
+
+/// Reads the declared fields of a struct by name, except those which failed.
+/// Other keys of the table are not read.
+struct PartialFields<'a, 'lua> {
+    table: Table<'lua>,
+    fields: ::std::slice::Iter<'static, &'static str>,
+    failed: &'a [&'static str],
+    field: &'a Cell<Option<&'static str>>,
+    value: Option<(&'static str, Value<'lua>)>,
+    options: Rc<State>,
+}
+
+impl<'a, 'lua> serde::de::MapAccess<'lua> for PartialFields<'a, 'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        for field in &mut self.fields {
+            if self.failed.contains(field) {
+                continue;
+            }
+            match self.table.raw_get::<_, Value>(*field)? {
+                Value::Nil => continue,
+                value => {
+                    self.value = Some((field, value));
+                    return seed.deserialize(field.into_deserializer()).map(Some);
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        let (field, value) = match self.value.take() {
+            Some(entry) => entry,
+            None => return Err(serde::de::Error::custom("value is missing")),
+        };
+        self.field.set(Some(field));
+        let start = self.options.coercions_len();
+        let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+        let value = self.options.at(start, result, || PathSegment::Key(field.to_owned()))?;
+        self.field.set(None);
+        Ok(value)
+    }
+}
+
+
+/// Reads the `{ high, low }` words of a 128-bit integer, as written with
+/// `Int128Encoding::Words`.
+fn int128_words(table: &Table) -> Result<(i64, i64)> {
+    let words = (table.raw_len(), table.raw_get(1)?, table.raw_get(2)?);
+    match words {
+        (2, Value::Integer(high), Value::Integer(low)) => Ok((high, low)),
+        _ => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Seq, &"a { high, low } sequence of two integers",
+        )),
+    }
+}
+
+/// Parses a 128-bit integer in decimal, as written with
+/// `Int128Encoding::String`.
+fn parse_int128<T>(s: &str, ty: &str) -> Result<T>
+    where T: ::std::str::FromStr<Err = ::std::num::ParseIntError>
+{
+    let trimmed = s.trim();
+    trimmed.parse().map_err(|err: ::std::num::ParseIntError| {
+        use std::num::IntErrorKind::{PosOverflow, NegOverflow};
+        // Unsigned types reject negative numbers as invalid digits.
+        let negative = trimmed.len() > 1 && trimmed.starts_with('-')
+            && trimmed[1..].bytes().all(|b| b.is_ascii_digit());
+        if negative || matches!(err.kind(), PosOverflow | NegOverflow) {
+            Error::NumberOverflow(format!("invalid value: `{}` is out of range of {}", s, ty))
+        } else {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(s), &"a decimal integer string",
+            )
+        }
+    })
+}
+
+
+/// Formats a number in decimal, for `coerce_str_from_numbers`.
+fn number_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::Integer(n) => Some(n.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+
+/// Turns a float map key with an integral value into an integer, so that it
+/// can be deserialized into an integer key type. Lua 5.3 already does this
+/// for table keys, but Lua 5.1 only has floats.
+fn integral_key(key: Value) -> Value {
+    match key {
+        Value::Number(n) => match integral_float(n) {
+            Some(n) => Value::Integer(n),
+            None => key,
+        },
+        key => key,
+    }
+}
+
+/// Name of the type of a value, as given by Lua's `type` except that light
+/// userdata is told apart.
+fn type_name(value: &Value) -> &'static str {
+    match *value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "boolean",
+        Value::LightUserData(_) => "light userdata",
+        Value::UserData(_) => "userdata",
+        Value::Integer(_) | Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        Value::Error(_) => "error",
+    }
+}
+
+/// Fails if the table has keys besides those of the sequence read from it,
+/// and `n` if it gives the length of the sequence.
+///
+/// Without `n`, the sequence ends at the first nil as with `SeqIter`, rather
+/// than at `raw_len`, which may be past a hole, so that elements after a hole
+/// are reported instead of silently dropped.
+fn check_sequence_keys(table: &Table, length_field: bool) -> Result<()> {
+    let len = match table.raw_get("n")? {
+        Value::Integer(n) if length_field && n >= 0 => n,
+        _ => {
+            let mut len = 0;
+            while !matches!(table.raw_get::<_, Value>(len + 1)?, Value::Nil) {
+                len += 1;
+            }
+            len
+        },
+    };
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        match key {
+            Value::Integer(i) if i >= 1 && i <= len => (),
+            Value::String(ref s) if length_field && s.as_bytes() == b"n" => (),
+            key => return Err(serde::de::Error::custom(format_args!(
+                "unexpected key `{}` in sequence", key_segment(&key),
+            ))),
+        }
+    }
+    Ok(())
+}
+
+/// Path segment for the entry of a table with the given key.
+fn key_segment(key: &Value) -> PathSegment {
+    match *key {
+        Value::Integer(i) => PathSegment::Index(i),
+        Value::String(ref s) => PathSegment::Key(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        ref key => PathSegment::Key(number_string(key).unwrap_or_else(|| format!("<{}>", type_name(key)))),
+    }
+}
+
+/// Visits a table as a map of its entries.
+fn visit_table_map<'lua, V>(table: Table<'lua>, options: Rc<State>, visitor: V) -> Result<V::Value>
+    where V: serde::de::Visitor<'lua>
+{
+    let mut deserializer = MapDeserializer::new(table, options);
+    let map = visitor.visit_map(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(map)
+}
+
+/// Whether a non-empty table holds nothing but a sequence, with keys from 1
+/// to its length. Empty tables are taken as maps.
+fn is_sequence(table: &Table) -> bool {
+    let len = table.raw_len() as usize;
+    len > 0 && table.clone().pairs::<Value, Value>().take(len + 1).count() == len
+}
+
+/// Whether the table was marked as an array by `SerializerOptions::mark_arrays`.
+fn is_marked_array(table: &Table) -> Result<bool> {
+    match table.get_metatable() {
+        Some(metatable) => Ok(metatable.raw_get::<_, Option<bool>>(ARRAY_MARKER)? == Some(true)),
+        None => Ok(false),
+    }
+}
+
+/// Reads the variant name from the `tag` field of an internally or adjacently
+/// tagged enum.
+fn variant_tag(table: &Table, tag: &str) -> Result<String> {
+    match table.raw_get(tag)? {
+        Value::String(variant) => Ok(variant.to_str()?.to_owned()),
+        Value::Nil => Err(serde::de::Error::missing_field("variant tag")),
+        _ => Err(serde::de::Error::custom(format_args!("variant tag `{}` is not a string", tag))),
+    }
+}
+
+fn variant_by_index(variants: &'static [&'static str], index: i64) -> Result<String> {
+    if index < 0 {
+        return Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Signed(index), &"a non-negative variant index",
+        ));
+    }
+    match variants.get(index as usize) {
+        Some(variant) => Ok((*variant).to_owned()),
+        None => Err(serde::de::Error::unknown_variant(&index.to_string(), variants)),
+    }
+}
+
+
+/// A Lua value captured as is during deserialization, to be deserialized
+/// later, e.g. once a tag elsewhere in the data tells into which type.
+///
+/// Only `Deserializer` can produce a `LazyValue`; other deserializers fail,
+/// including those serde buffers values in for `#[serde(flatten)]` fields,
+/// internally tagged and untagged enums, so a `LazyValue` can't be used
+/// there.
+/// As Lua values can't outlive nor be shortened to another lifetime than the
+/// one of their Lua context, a struct holding a `LazyValue<'lua>` must tie
+/// `'de` to `'lua` when deriving `Deserialize`:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// #[serde(bound(deserialize = "'lua: 'de"))]
+/// struct Message<'lua> {
+///     kind: String,
+///     #[serde(borrow)]
+///     payload: LazyValue<'lua>,
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LazyValue<'lua>(Value<'lua>);
+
+const LAZY_VALUE_TOKEN: &str = "$rlua_serde::LazyValue";
+
+thread_local! {
+    /// Points to the `Option<Value>` a `LazyValue` or `WithSource` is being
+    /// deserialized from, while `ConfiguredDeserializer::deserialize_newtype_struct`
+    /// runs, along with the token its visitor is given to take it.
+    static LAZY_VALUE: Cell<(*mut (), u64)> = const { Cell::new((ptr::null_mut(), 0)) };
+    /// Token of the next hand-over, so that no two of them share one.
+    static NEXT_TOKEN: Cell<u64> = const { Cell::new(1) };
+}
+
+impl<'lua> LazyValue<'lua> {
+    pub fn new(value: Value<'lua>) -> Self {
+        LazyValue(value)
+    }
+
+    pub fn value(&self) -> &Value<'lua> {
+        &self.0
+    }
+
+    pub fn into_value(self) -> Value<'lua> {
+        self.0
+    }
+
+    /// Deserializes the captured value with default options.
+    pub fn deserialize_into<T: serde::Deserialize<'lua>>(self) -> StdResult<T, LuaError> {
+        ::from_value(self.0)
+    }
+}
+
+impl<'lua> serde::Deserialize<'lua> for LazyValue<'lua> {
+    fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+        where D: serde::Deserializer<'lua>
+    {
+        deserializer.deserialize_newtype_struct(LAZY_VALUE_TOKEN, LazyValueVisitor(PhantomData))
+    }
+}
+
+struct LazyValueVisitor<'lua>(PhantomData<Value<'lua>>);
+
+impl<'lua> serde::de::Visitor<'lua> for LazyValueVisitor<'lua> {
+    type Value = LazyValue<'lua>;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("a Lua value")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, token: u64) -> StdResult<LazyValue<'lua>, E> {
+        take_lazy_value(token, "LazyValue").map(LazyValue)
+    }
+
+    /// Called by deserializers which replay buffered values.
+    fn visit_newtype_struct<D>(self, _deserializer: D) -> StdResult<LazyValue<'lua>, D::Error>
+        where D: serde::Deserializer<'lua>
+    {
+        Err(not_handed_over("LazyValue"))
+    }
+}
+
+/// Points `LAZY_VALUE` to `value` under a fresh token while `f` runs with
+/// that token, restoring the previous pointer and token when it returns or
+/// unwinds, so that `LAZY_VALUE` never points to a slot which is gone.
+fn hand_over_value<'lua, R, F>(value: Value<'lua>, f: F) -> R
+    where F: FnOnce(u64) -> R
+{
+    struct Restore((*mut (), u64));
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LAZY_VALUE.with(|cell| cell.set(self.0));
+        }
+    }
+
+    let token = NEXT_TOKEN.with(|next| next.replace(next.get() + 1));
+    let mut slot = Some(value);
+    let ptr = &mut slot as *mut Option<Value<'lua>> as *mut ();
+    // Declared after `slot`, so dropped before it.
+    let _restore = Restore(LAZY_VALUE.with(|cell| cell.replace((ptr, token))));
+    f(token)
+}
+
+/// Takes the value handed over through `LAZY_VALUE` under `token`, for a
+/// visitor of a `'lua` lifetime.
+///
+/// Only the visitor given the token by `hand_over_value`, while it runs, can
+/// take the value. A token replayed by a deserializer which buffered it, or
+/// a hand-over made for another visitor further up, doesn't match.
+fn take_lazy_value<'lua, E: serde::de::Error>(token: u64, ty: &str) -> StdResult<Value<'lua>, E> {
+    let (slot, current) = LAZY_VALUE.with(Cell::get);
+    if slot.is_null() || token != current {
+        return Err(not_handed_over(ty));
+    }
+    LAZY_VALUE.with(|cell| cell.set((ptr::null_mut(), 0)));
+    // Safety: `slot` was set by `hand_over_value` with `token` for the
+    // duration of `ConfiguredDeserializer<'lua>::deserialize_newtype_struct`,
+    // which implements `serde::Deserializer<'lua>` and gives the token only
+    // to the visitor it was called with, a visitor of that `'lua`, so the
+    // value has the lifetime of the visitor calling this and outlives this
+    // call.
+    let value = unsafe { (*(slot as *mut Option<Value<'lua>>)).take() };
+    value.ok_or_else(|| E::custom("Lua value already taken"))
+}
+
+fn not_handed_over<E: serde::de::Error>(ty: &str) -> E {
+    E::custom(format_args!(
+        "{} can only be deserialized directly by rlua_serde's Deserializer, \
+         not through a buffering one such as for #[serde(flatten)]", ty,
+    ))
+}
+
+
+/// A value deserialized from a table along with the table itself, e.g. to
+/// keep the parts of a config table which `T` doesn't know about and write
+/// them back unmodified.
+///
+/// `T` is deserialized with the options of the enclosing deserializer. serde
+/// gives a field no access to the table of its parent, so rather than a raw
+/// table field inside `T`, wrap `T` where it is used:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// #[serde(bound(deserialize = "'lua: 'de"))]
+/// struct Config<'lua> {
+///     #[serde(borrow)]
+///     server: WithSource<'lua, Server>,
+/// }
+/// ```
+///
+/// As with `LazyValue`, the table is tied to the Lua context, so structs
+/// holding a `WithSource<'lua, T>` must tie `'de` to `'lua` and only
+/// `Deserializer` can produce one, not through `#[serde(flatten)]` nor
+/// internally tagged and untagged enums.
+#[derive(Clone, Debug)]
+pub struct WithSource<'lua, T> {
+    pub source: Table<'lua>,
+    pub value: T,
+}
+
+const WITH_SOURCE_TOKEN: &str = "$rlua_serde::WithSource";
+
+impl<'lua, T: serde::Deserialize<'lua>> serde::Deserialize<'lua> for WithSource<'lua, T> {
+    fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+        where D: serde::Deserializer<'lua>
+    {
+        deserializer.deserialize_newtype_struct(WITH_SOURCE_TOKEN, WithSourceVisitor(PhantomData))
+    }
+}
+
+struct WithSourceVisitor<'lua, T>(PhantomData<(Value<'lua>, T)>);
+
+impl<'lua, T: serde::Deserialize<'lua>> serde::de::Visitor<'lua> for WithSourceVisitor<'lua, T> {
+    type Value = WithSource<'lua, T>;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("a Lua table")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> StdResult<WithSource<'lua, T>, A::Error>
+        where A: serde::de::SeqAccess<'lua>
+    {
+        let token = match seq.next_element()? {
+            Some(token) => token,
+            None => return Err(serde::de::Error::invalid_length(0, &self)),
+        };
+        let source = match take_lazy_value(token, "WithSource")? {
+            Value::Table(table) => table,
+            _ => return Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::Other("non-table value"), &self,
+            )),
+        };
+        match seq.next_element()? {
+            Some(value) => Ok(WithSource { source, value }),
+            None => Err(serde::de::Error::invalid_length(1, &self)),
+        }
+    }
+
+    /// Called by deserializers which replay buffered values.
+    fn visit_newtype_struct<D>(self, _deserializer: D) -> StdResult<WithSource<'lua, T>, D::Error>
+        where D: serde::Deserializer<'lua>
+    {
+        Err(not_handed_over("WithSource"))
+    }
+}
+
+/// Hands `WithSourceVisitor` the token to take the table and then the
+/// deserializer of the table for `T`, which keeps the options.
+struct WithSourceAccess<'lua> {
+    token: Option<u64>,
+    deserializer: Option<ConfiguredDeserializer<'lua>>,
+}
+
+impl<'lua> serde::de::SeqAccess<'lua> for WithSourceAccess<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        if let Some(token) = self.token.take() {
+            return seed.deserialize(token.into_deserializer()).map(Some);
+        }
+        match self.deserializer.take() {
+            Some(deserializer) => seed.deserialize(deserializer).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// Elements of a sequence, either up to the first nil or up to the length in
+/// its `n` field.
+enum SeqIter<'lua> {
+    Sequence(TableSequence<'lua, Value<'lua>>),
+    Counted { table: Table<'lua>, next: i64, len: i64 },
+}
+
+impl<'lua> Iterator for SeqIter<'lua> {
+    type Item = StdResult<Value<'lua>, LuaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            SeqIter::Sequence(ref mut iter) => iter.next(),
+            SeqIter::Counted { ref table, ref mut next, len } => {
+                if *next > len {
+                    return None;
+                }
+                *next += 1;
+                Some(table.raw_get(*next - 1))
+            },
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            SeqIter::Sequence(ref iter) => iter.size_hint(),
+            SeqIter::Counted { next, len, .. } => {
+                let remaining = (len - next + 1).max(0) as usize;
+                (remaining, Some(remaining))
+            },
+        }
+    }
+}
+
+
+/// How far the `n` field of a sequence may point past its raw length, so
+/// that trailing nils are read but an `n` of a billion doesn't make us read
+/// a billion nils.
+const MAX_TRAILING_NILS: i64 = 1024;
+
+struct SeqDeserializer<'lua> {
+    iter: SeqIter<'lua>,
+    consumed: usize,
+    options: Rc<State>,
+}
+
+impl<'lua> SeqDeserializer<'lua> {
+    fn new(table: Table<'lua>, options: Rc<State>) -> Result<Self> {
+        let len = if options.seq_length_field { table.raw_get("n")? } else { Value::Nil };
+        let iter = match len {
+            Value::Integer(len) if len >= 0 => {
+                let raw_len = table.raw_len();
+                if len > raw_len.saturating_add(MAX_TRAILING_NILS) {
+                    return Err(serde::de::Error::custom(format_args!(
+                        "sequence length field n = {} is more than {} past its raw length {}",
+                        len, MAX_TRAILING_NILS, raw_len,
+                    )));
+                }
+                SeqIter::Counted { table, next: 1, len }
+            },
+            _ => SeqIter::Sequence(table.sequence_values()),
+        };
+        Ok(SeqDeserializer { iter, consumed: 0, options })
+    }
+
+    /// Checks that the visitor consumed every element of the sequence.
+    fn end(self) -> Result<()> {
+        let consumed = self.consumed;
+        let remaining = self.iter.count();
+        if remaining == 0 {
+            Ok(())
+        } else {
+            Err(serde::de::Error::invalid_length(
+                consumed + remaining,
+                &format!("{} elements in sequence", consumed).as_str(),
+            ))
+        }
+    }
+}
+
+impl<'lua> serde::de::SeqAccess<'lua> for SeqDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.iter.next() {
+            Some(value) => {
+                self.options.count_element()?;
+                self.consumed += 1;
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value?, &self.options));
+                let index = self.consumed as i64;
+                self.options.at(start, result, || PathSegment::Index(index)).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        match self.iter.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(upper),
+            _ => None,
+        }
+    }
+}
+
+
+struct ValuesDeserializer<'lua>(::std::vec::IntoIter<Value<'lua>>, Rc<State>);
+
+impl<'lua> serde::de::SeqAccess<'lua> for ValuesDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.0.next() {
+            Some(value) => {
+                self.1.count_element()?;
+                seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.1)).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+
+/// The fields of a tuple struct read from the keys configured in
+/// `tuple_struct_keys`.
+struct KeyedTupleDeserializer<'lua> {
+    table: Table<'lua>,
+    keys: ::std::slice::Iter<'static, &'static str>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::SeqAccess<'lua> for KeyedTupleDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.keys.next() {
+            Some(&key) => {
+                self.options.count_element()?;
+                let value = self.table.raw_get(key)?;
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+                self.options.at(start, result, || PathSegment::Key(key.to_owned())).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.keys.len())
+    }
+}
+
+/// The entries of a table as a sequence of `(key, value)` pairs.
+struct EntriesDeserializer<'lua> {
+    iter: ::std::vec::IntoIter<(Value<'lua>, Value<'lua>)>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::SeqAccess<'lua> for EntriesDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.options.count_element()?;
+                let segment = key_segment(&key);
+                let entry = EntryDeserializer { key: integral_key(key), value, options: self.options.clone() };
+                let start = self.options.coercions_len();
+                self.options.at(start, seed.deserialize(entry), || segment).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+
+/// A table entry as a `(key, value)` pair.
+struct EntryDeserializer<'lua> {
+    key: Value<'lua>,
+    value: Value<'lua>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::Deserializer<'lua> for EntryDeserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let pair = vec![self.key, self.value];
+        visitor.visit_seq(&mut ValuesDeserializer(pair.into_iter(), self.options))
+    }
+
+    forward_to_deserialize_any! {
+        <W: Visitor<'lua>>
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+
+struct MapDeserializer<'lua> {
+    iter: TablePairs<'lua, Value<'lua>, Value<'lua>>,
+    value: Option<Value<'lua>>,
+    /// Key of `value`, for the path of its errors.
+    key: Value<'lua>,
+    consumed: usize,
+    /// String forms of the keys seen so far, for `reject_key_collisions`.
+    seen_keys: Option<HashSet<Vec<u8>>>,
+    /// Keys to pass over, already read by `StructDeserializer`.
+    skip: &'static [&'static str],
+    options: Rc<State>,
+}
+
+impl<'lua> MapDeserializer<'lua> {
+    fn new(table: Table<'lua>, options: Rc<State>) -> Self {
+        let seen_keys = if options.reject_key_collisions { Some(HashSet::new()) } else { None };
+        MapDeserializer {
+            iter: table.pairs(),
+            value: None,
+            key: Value::Nil,
+            consumed: 0,
+            seen_keys,
+            skip: &[],
+            options,
+        }
+    }
+
+    /// Whether `key` is one of the keys to pass over.
+    fn skipped(&self, key: &Value) -> bool {
+        match *key {
+            Value::String(ref key) => self.skip.iter().any(|skip| skip.as_bytes() == key.as_bytes()),
+            _ => false,
+        }
+    }
+
+    /// Fails if `key` has the same string form as a key seen before.
+    fn check_collision(&mut self, key: &Value) -> Result<()> {
+        let seen_keys = match self.seen_keys {
+            Some(ref mut seen_keys) => seen_keys,
+            None => return Ok(()),
+        };
+        let key = match *key {
+            Value::String(ref s) => s.as_bytes().to_vec(),
+            ref key => match number_string(key) {
+                Some(s) => s.into_bytes(),
+                None => return Ok(()),
+            },
+        };
+        if seen_keys.insert(key.clone()) {
+            return Ok(());
+        }
+        Err(serde::de::Error::custom(format_args!(
+            "keys colliding as `{}` in map", String::from_utf8_lossy(&key),
+        )))
+    }
+
+    /// Checks that the visitor consumed every entry of the map.
+    fn end(self) -> Result<()> {
+        let consumed = self.consumed;
+        let remaining = self.iter.count();
+        if remaining == 0 {
+            Ok(())
+        } else {
+            Err(serde::de::Error::invalid_length(
+                consumed + remaining,
+                &format!("{} entries in map", consumed).as_str(),
+            ))
+        }
+    }
+}
+
+impl<'lua> serde::de::MapAccess<'lua> for MapDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        let mut next = self.iter.next();
+        if !self.skip.is_empty() {
+            while let Some(Ok((ref key, _))) = next {
+                if !self.skipped(key) {
+                    break;
+                }
+                next = self.iter.next();
+            }
+        }
+        match next {
+            Some(item) => {
+                let (key, value) = item?;
+                self.options.count_element()?;
+                self.check_collision(&key)?;
+                self.value = Some(value);
+                self.key = key.clone();
+                self.consumed += 1;
+                match (key, &self.options.key_transform) {
+                    (Value::String(ref key), Some(transform)) => {
+                        let key_de = transform(key.to_str()?).into_deserializer();
+                        seed.deserialize(key_de).map(Some)
+                    },
+                    (key, _) => {
+                        let key_de = ConfiguredDeserializer::from_parts(integral_key(key), &self.options);
+                        seed.deserialize(key_de).map(Some)
+                    },
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.value.take() {
+            Some(value) => {
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+                self.options.at(start, result, || key_segment(&self.key))
+            },
+            None => Err(serde::de::Error::custom("value is missing")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        match self.iter.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(upper),
+            _ => None,
+        }
+    }
+}
+
+
+struct PairsDeserializer<'lua> {
+    iter: TableSequence<'lua, Table<'lua>>,
+    value: Option<Value<'lua>>,
+    /// Key of `value`, for the path of its errors.
+    key: Value<'lua>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::MapAccess<'lua> for PairsDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.iter.next() {
+            Some(pair) => {
+                let pair = pair?;
+                self.options.count_element()?;
+                self.value = Some(pair.raw_get(2)?);
+                let key = integral_key(pair.raw_get(1)?);
+                self.key = key.clone();
+                seed.deserialize(ConfiguredDeserializer::from_parts(key, &self.options)).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.value.take() {
+            Some(value) => {
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+                self.options.at(start, result, || key_segment(&self.key))
+            },
+            None => Err(serde::de::Error::custom("value is missing")),
+        }
+    }
+}
+
+
+/// Reads the declared fields of a struct by name, then any other keys of the
+/// table from `rest`, so that the visitor sees the same entries as when
+/// iterating over the table.
+struct StructDeserializer<'lua> {
+    table: Table<'lua>,
+    fields: ::std::slice::Iter<'static, &'static str>,
+    value: Option<Value<'lua>>,
+    /// Field of `value`, for the path of its errors.
+    field: &'static str,
+    /// Keys other than the declared fields, such as aliases and unknown
+    /// fields, once all of them have been read.
+    rest: MapDeserializer<'lua>,
+    options: Rc<State>,
+}
+
+impl<'lua> StructDeserializer<'lua> {
+    fn new(table: Table<'lua>, fields: &'static [&'static str], options: Rc<State>) -> Self {
+        let mut rest = MapDeserializer::new(table.clone(), options.clone());
+        rest.skip = fields;
+        StructDeserializer { table, fields: fields.iter(), value: None, field: "", rest, options }
+    }
+}
+
+impl<'lua> serde::de::MapAccess<'lua> for StructDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        for field in &mut self.fields {
+            // Absent and nil fields are left for the visitor to report as
+            // missing, exactly as if they had not been seen during iteration.
+            match self.table.raw_get::<_, Value>(*field)? {
+                Value::Nil => continue,
+                value => {
+                    self.options.count_element()?;
+                    self.value = Some(value);
+                    self.field = field;
+                    let key_de = field.into_deserializer();
+                    return seed.deserialize(key_de).map(Some);
+                },
+            }
+        }
+        self.rest.next_key_seed(seed)
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.value.take() {
+            Some(value) => {
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+                self.options.at(start, result, || PathSegment::Key(self.field.to_owned()))
+            },
+            None => self.rest.next_value_seed(seed),
+        }
+    }
+}
+
+
+/// Deserializes an enum from a table, choosing the variant by which fields
+/// are present instead of by a tag.
+///
+/// `variants` lists each candidate variant together with the fields it
+/// requires. The first variant whose required fields are all present (not
+/// nil) in the table is selected and the whole table is used as its payload.
+pub struct FieldTaggedDeserializer<'lua> {
+    variant: &'static str,
+    value: Value<'lua>,
+    options: Rc<State>,
+}
+
+impl<'lua> FieldTaggedDeserializer<'lua> {
+    pub fn new(
+        value: Value<'lua>, variants: &[(&'static str, &[&str])]
+    ) -> Result<Self> {
+        Self::with_options(value, variants, DeserializerOptions::default())
+    }
+
+    pub fn with_options(
+        value: Value<'lua>, variants: &[(&'static str, &[&str])],
+        options: DeserializerOptions,
+    ) -> Result<Self> {
+        let table = match value {
+            Value::Table(ref table) => table,
+            _ => return Err(serde::de::Error::custom("expected a table")),
+        };
+        for &(variant, fields) in variants {
+            let mut matches = true;
+            for field in fields {
+                if !table.contains_key(*field)? {
+                    matches = false;
+                    break;
+                }
+            }
+            if matches {
+                return Ok(FieldTaggedDeserializer {
+                    variant,
+                    value,
+                    options: Rc::new(State::new(options)),
+                });
+            }
+        }
+        Err(serde::de::Error::custom("no variant matches the fields of the table"))
+    }
+}
+
+impl<'lua> serde::Deserializer<'lua> for FieldTaggedDeserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        visitor.visit_enum(EnumDeserializer {
+            variant: self.variant.to_owned(),
+            value: Some(self.value),
+            options: self.options,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        <W: Visitor<'lua>>
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+
+/// Deserializes a tuple or sequence from multiple values, such as the
+/// results of a Lua function call, one element per value.
+pub struct MultiValueDeserializer<'lua> {
+    values: Vec<Value<'lua>>,
+    options: Rc<State>,
+}
+
+impl<'lua> MultiValueDeserializer<'lua> {
+    pub fn new(values: MultiValue<'lua>) -> Self {
+        Self::with_options(values, DeserializerOptions::default())
+    }
+
+    pub fn with_options(values: MultiValue<'lua>, options: DeserializerOptions) -> Self {
+        MultiValueDeserializer { values: values.into_vec(), options: Rc::new(State::new(options)) }
+    }
+}
+
+impl<'lua> serde::Deserializer<'lua> for MultiValueDeserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let len = self.values.len();
+        let mut deserializer = ValuesDeserializer(self.values.into_iter(), self.options);
+        let value = visitor.visit_seq(&mut deserializer)?;
+        let remaining = deserializer.0.len();
+        if remaining == 0 {
+            Ok(value)
+        } else {
+            Err(serde::de::Error::invalid_length(
+                len,
+                &format!("{} values", len - remaining).as_str(),
+            ))
+        }
+    }
+
+    forward_to_deserialize_any! {
+        <W: Visitor<'lua>>
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+
+struct EnumDeserializer<'lua> {
+    variant: String,
+    value: Option<Value<'lua>>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::EnumAccess<'lua> for EnumDeserializer<'lua> {
+    type Error = Error;
+    type Variant = VariantDeserializer<'lua>;
+
+    fn variant_seed<T>(self, seed: T) -> Result<(T::Value, Self::Variant)>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant.as_str()))?;
+        let variant_access = VariantDeserializer {
+            variant: self.variant,
+            value: self.value,
+            options: self.options,
+        };
+        Ok((variant, variant_access))
+    }
+}
+
+
+struct VariantDeserializer<'lua> {
+    variant: String,
+    value: Option<Value<'lua>>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::VariantAccess<'lua> for VariantDeserializer<'lua> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            // The whole table is the payload of internally tagged variants.
+            Some(Value::Table(_)) if matches!(self.options.enum_repr, EnumRepr::Internal { .. }) => {
+                Ok(())
+            },
+            Some(_) => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::NewtypeVariant,
+                &"unit variant",
+            )),
+            None => Ok(())
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        let variant = self.variant;
+        let start = self.options.coercions_len();
+        let result = match self.value {
+            Some(value) => seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options)),
+            // Content is left out when the payload serializes to nil, e.g. `None`.
+            None if matches!(self.options.enum_repr, EnumRepr::Adjacent { .. }) => {
+                seed.deserialize(ConfiguredDeserializer::from_parts(Value::Nil, &self.options))
+            },
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"newtype variant",
+            ))
+        };
+        self.options.at(start, result, || PathSegment::Variant(variant))
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let variant = self.variant;
+        let start = self.options.coercions_len();
+        let result = match self.value {
+            Some(value) => serde::Deserializer::deserialize_seq(
+                ConfiguredDeserializer::from_parts(value, &self.options), visitor
+            ),
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"tuple variant",
+            ))
+        };
+        self.options.at(start, result, || PathSegment::Variant(variant))
+    }
+
+    fn struct_variant<V>(
+        self, fields: &'static [&'static str], visitor: V
+    ) -> Result<V::Value>
+        where V: serde::de::Visitor<'lua>
+    {
+        let variant = self.variant;
+        let start = self.options.coercions_len();
+        let result = match self.value {
+            Some(value) if fields.len() == 1 && self.options.scalar_struct_variant_payload
+                && !matches!(value, Value::Table(_)) =>
+            {
+                visitor.visit_map(FieldDeserializer {
+                    field: fields[0],
+                    value: Some(value),
+                    options: self.options.clone(),
+                })
+            },
+            Some(value) if self.options.compact => serde::Deserializer::deserialize_struct(
+                ConfiguredDeserializer::from_parts(value, &self.options), "", fields, visitor
+            ),
+            Some(value) => serde::Deserializer::deserialize_map(
+                ConfiguredDeserializer::from_parts(value, &self.options), visitor
+            ),
+            // Struct variants without fields may be written like unit variants.
+            None if fields.is_empty() => visitor.visit_map(
+                serde::de::value::MapDeserializer::new(::std::iter::empty::<((), ())>())
+            ),
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"struct variant",
+            ))
+        };
+        self.options.at(start, result, || PathSegment::Variant(variant))
+    }
+}
+
+/// A map with a single entry, for a struct variant given by the value of its
+/// only field. The entry is over once its value is read.
+struct FieldDeserializer<'lua> {
+    field: &'static str,
+    value: Option<Value<'lua>>,
+    options: Rc<State>,
+}
+
+impl<'lua> serde::de::MapAccess<'lua> for FieldDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.value {
+            Some(_) => seed.deserialize(self.field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+        where T: serde::de::DeserializeSeed<'lua>
+    {
+        match self.value.take() {
+            Some(value) => {
+                let start = self.options.coercions_len();
+                let result = seed.deserialize(ConfiguredDeserializer::from_parts(value, &self.options));
+                self.options.at(start, result, || PathSegment::Key(self.field.to_owned()))
+            },
+            None => Err(serde::de::Error::custom("value is missing")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rlua::{Lua, MultiValue, Value};
+
+    use serde;
+
+    use {
+        to_value, from_value, from_value_with, from_value_by_fields, from_value_partial,
+        externally_tagged, from_multi_value, from_value_seed, from_value_then, from_value_ref,
+        to_registry, from_registry, from_value_with_report,
+        ContextExt,
+    };
+    use super::{DeserializerOptions, OverflowPolicy, WithSource, Coercion};
+    use error::PathSegment;
+
+    #[test]
+    fn test_struct() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Test {
+            int: u32,
+            seq: Vec<String>,
+            map: std::collections::HashMap<i32, i32>,
+            empty: Vec<()>,
+        }
+
+        let expected = Test {
+            int: 1,
+            seq: vec!["a".to_owned(), "b".to_owned()],
+            map: vec![(1, 2), (4, 1)].into_iter().collect(),
+            empty: vec![]
+        };
+
+        println!("{:?}", expected);
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                a = {}
+                a.int = 1
+                a.seq = {"a", "b"}
+                a.map = {2, [4]=1}
+                a.empty = {}
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+        });
+    }
+
+    #[test]
+    fn test_tuple() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Rgb(u8, u8, u8);
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let expected = Rgb(1, 2, 3);
+            let value = lua.load(
+                r#"
+                a = {1, 2, 3}
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+
+            let expected = (1, 2, 3);
+            let value = lua.load(
+                r#"
+                a = {1, 2, 3}
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+        });
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            Unit,
+            Newtype(u32),
+            Tuple(u32, u32),
+            Struct { a: u32 },
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let expected = E::Unit;
+            let value = lua.load(
+                r#"
+                return "Unit"
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+
+
+            let expected = E::Newtype(1);
+            let value = lua.load(
+                r#"
+                a = {}
+                a["Newtype"] = 1
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+
+            let expected = E::Tuple(1, 2);
+            let value = lua.load(
+                r#"
+                a = {}
+                a["Tuple"] = {1, 2}
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+
+            let expected = E::Struct { a: 1 };
+            let value = lua.load(
+                r#"
+                a = {}
+                a["Struct"] = {}
+                a["Struct"]["a"] = 1
+                return a
+            "#).eval().unwrap();
+            let got = from_value(value).unwrap();
+            assert_eq!(expected, got);
+        });
+    }
+
+    #[test]
+    fn test_option_vec() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                return {}
+            "#).eval().unwrap();
+            let got: Option<Vec<u32>> = from_value(value).unwrap();
+            assert_eq!(Some(vec![]), got);
+
+            let value = lua.load(r#"
+                return nil
+            "#).eval().unwrap();
+            let got: Option<Vec<u32>> = from_value(value).unwrap();
+            assert_eq!(None, got);
+        });
+    }
+
+    #[test]
+    fn test_deserializer_literal() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load("return { x = 1, y = 2 }").eval().unwrap();
+            let got = <Point as serde::Deserialize>::deserialize(super::Deserializer { value }).unwrap();
+            assert_eq!(got, Point { x: 1, y: 2 });
+        });
+    }
+
+    #[test]
+    fn test_struct_field_lookup() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Flat {
+            a: u32,
+            b: String,
+            c: Option<bool>,
+            #[serde(default)]
+            d: f64,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let options = DeserializerOptions {
+                struct_field_lookup: true,
+                ..DeserializerOptions::default()
+            };
+
+            let value: rlua::Value = lua.load(r#"
+                return { a = 1, b = "x", extra = {} }
+            "#).eval().unwrap();
+            let expected = Flat { a: 1, b: "x".to_owned(), c: None, d: 0.0 };
+            let got: Flat = from_value(value.clone()).unwrap();
+            assert_eq!(expected, got);
+            let got: Flat = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(expected, got);
+
+            let value: rlua::Value = lua.load(r#"
+                return { b = "x" }
+            "#).eval().unwrap();
+            let err = from_value::<Flat>(value.clone()).unwrap_err().to_string();
+            let lookup_err = from_value_with::<Flat>(value, options.clone()).unwrap_err().to_string();
+            assert!(err.contains("missing field `a`"), "{}", err);
+            assert_eq!(err, lookup_err);
+
+            // Fields inherited through `__index` are not entries of the table.
+            let value: rlua::Value = lua.load(r#"
+                return setmetatable({ b = "x" }, { __index = { a = 1 } })
+            "#).eval().unwrap();
+            assert!(from_value::<Flat>(value.clone()).is_err());
+            assert!(from_value_with::<Flat>(value, options.clone()).is_err());
+        });
+    }
+
+    #[test]
+    fn test_struct_field_lookup_extra_keys() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(deny_unknown_fields)]
+        struct Strict {
+            a: u32,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Aliased {
+            #[serde(alias = "colour")]
+            color: String,
+        }
+
+        let options = DeserializerOptions {
+            struct_field_lookup: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value: rlua::Value = lua.load("return { a = 1, b = 2 }").eval().unwrap();
+            assert!(from_value::<Strict>(value.clone()).is_err());
+            assert!(from_value_with::<Strict>(value, options.clone()).is_err());
+            let value: rlua::Value = lua.load("return { a = 1 }").eval().unwrap();
+            assert_eq!(from_value_with::<Strict>(value, options.clone()).unwrap(), Strict { a: 1 });
+
+            let value: rlua::Value = lua.load(r#"return { colour = "red" }"#).eval().unwrap();
+            let expected = Aliased { color: "red".to_owned() };
+            assert_eq!(from_value::<Aliased>(value.clone()).unwrap(), expected);
+            assert_eq!(from_value_with::<Aliased>(value, options.clone()).unwrap(), expected);
+            let value: rlua::Value = lua.load(r#"return { color = "red", colour = "blue" }"#).eval().unwrap();
+            assert!(from_value::<Aliased>(value.clone()).is_err());
+            assert!(from_value_with::<Aliased>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_coerce_integers_from_str() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let options = DeserializerOptions {
+                coerce_numbers_from_str: true,
+                ..DeserializerOptions::default()
+            };
+            let string = |s: &str| rlua::Value::String(lua.create_string(s).unwrap());
+
+            let got: i64 = from_value_with(string("42"), options.clone()).unwrap();
+            assert_eq!(42, got);
+            let got: u32 = from_value_with(string(" 42 "), options.clone()).unwrap();
+            assert_eq!(42, got);
+            let got: i32 = from_value_with(string("-42"), options.clone()).unwrap();
+            assert_eq!(-42, got);
+            assert!(from_value_with::<i64>(string("42.0"), options.clone()).is_err());
+            assert!(from_value_with::<i64>(string("0x2a"), options.clone()).is_err());
+            assert!(from_value_with::<u8>(string("-1"), options.clone()).is_err());
+
+            let got: f64 = from_value_with(string("4.5"), options).unwrap();
+            assert_eq!(4.5, got);
+
+            assert!(from_value::<i64>(string("42")).is_err());
+        });
+    }
+
+    #[test]
+    fn test_field_tagged_enum() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Shape {
+            Circle { radius: f64 },
+            Rect { width: f64, height: f64 },
+        }
+
+        let variants: &[(&str, &[&str])] = &[
+            ("Circle", &["radius"]),
+            ("Rect", &["width"]),
+        ];
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                return { radius = 2.5 }
+            "#).eval().unwrap();
+            let got: Shape = from_value_by_fields(value, variants).unwrap();
+            assert_eq!(Shape::Circle { radius: 2.5 }, got);
+
+            let value = lua.load(r#"
+                return { width = 1.5, height = 3.5 }
+            "#).eval().unwrap();
+            let got: Shape = from_value_by_fields(value, variants).unwrap();
+            assert_eq!(Shape::Rect { width: 1.5, height: 3.5 }, got);
+
+            let value = lua.load(r#"
+                return { side = 1 }
+            "#).eval().unwrap();
+            assert!(from_value_by_fields::<Shape>(value, variants).is_err());
+        });
+    }
+
+    #[test]
+    fn test_enum_variant_index() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            A,
+            B,
+            C,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let options = DeserializerOptions {
+                variant_index: true,
+                ..DeserializerOptions::default()
+            };
+
+            let got: E = from_value_with(rlua::Value::Integer(1), options.clone()).unwrap();
+            assert_eq!(E::B, got);
+            let value = lua.load("return 2.0").eval().unwrap();
+            let got: E = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(E::C, got);
+
+            assert!(from_value_with::<E>(rlua::Value::Number(1.5), options.clone()).is_err());
+            let err = from_value_with::<E>(rlua::Value::Integer(3), options.clone()).unwrap_err();
+            assert!(err.to_string().contains("unknown variant `3`, expected one of `A`, `B`, `C`"), "{}", err);
+            let err = from_value_with::<E>(rlua::Value::Integer(-1), options).unwrap_err();
+            assert!(err.to_string().contains("integer `-1`, expected a non-negative variant index"), "{}", err);
+            assert!(from_value::<E>(rlua::Value::Integer(1)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_path() {
+        use std::path::PathBuf;
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let path = PathBuf::from("/usr/share/lua/5.3/init.lua");
+            let value = to_value(lua, &path).unwrap();
+            match value {
+                rlua::Value::String(ref s) => assert_eq!("/usr/share/lua/5.3/init.lua", s.to_str().unwrap()),
+                _ => panic!("expected a string, got {:?}", value),
+            }
+            let got: PathBuf = from_value(value).unwrap();
+            assert_eq!(path, got);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::PathBuf;
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let path = PathBuf::from(OsStr::from_bytes(b"/tmp/\xff.lua"));
+            assert!(to_value(lua, &path).is_err());
+
+            let value = rlua::Value::String(lua.create_string(&b"/tmp/\xff.lua"[..]).unwrap());
+            assert!(from_value::<PathBuf>(value).is_err());
+        });
+    }
+
+    #[test]
+    fn test_partial() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(default)]
+        struct Config {
+            name: String,
+            port: u16,
+            verbose: bool,
+        }
+
+        impl Default for Config {
+            fn default() -> Config {
+                Config { name: "default".to_owned(), port: 8080, verbose: false }
+            }
+        }
+
+        #[derive(Deserialize, Default, PartialEq, Debug)]
+        struct Required {
+            name: String,
+            port: u16,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                return { name = "service", port = "not a port", verbose = true, unknown = 1 }
+            "#).eval().unwrap();
+            let (got, errors) = from_value_partial::<Config>(value);
+            let expected = Config { name: "service".to_owned(), port: 8080, verbose: true };
+            assert_eq!(expected, got);
+            assert_eq!(1, errors.len());
+            assert!(errors[0].to_string().contains("port: invalid type"), "{}", errors[0]);
+
+            let value = lua.load(r#"
+                return { name = 1, port = -1, verbose = true }
+            "#).eval().unwrap();
+            let (got, errors) = from_value_partial::<Config>(value);
+            let expected = Config { name: "default".to_owned(), port: 8080, verbose: true };
+            assert_eq!(expected, got);
+            assert_eq!(2, errors.len());
+
+            let value = lua.load(r#"
+                return { port = 1 }
+            "#).eval().unwrap();
+            let (got, errors) = from_value_partial::<Config>(value);
+            let expected = Config { name: "default".to_owned(), port: 1, verbose: false };
+            assert_eq!(expected, got);
+            assert!(errors.is_empty());
+
+            let (got, errors) = from_value_partial::<Config>(Value::Boolean(true));
+            assert_eq!(Config::default(), got);
+            assert_eq!(1, errors.len());
+
+            // Without `#[serde(default)]`, a failed field is missing.
+            let value = lua.load(r#"
+                return { name = "service", port = "not a port" }
+            "#).eval().unwrap();
+            let (got, errors) = from_value_partial::<Required>(value);
+            assert_eq!(Required::default(), got);
+            assert_eq!(2, errors.len());
+            assert!(errors[1].to_string().contains("missing field `port`"), "{}", errors[1]);
+        });
+    }
+
+    #[test]
+    fn test_tuple_struct_keys() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Point(i32, i32);
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let mut options = DeserializerOptions::default();
+            options.tuple_struct_keys.insert("Point", &["x", "y"]);
+
+            let value = lua.load(r#"
+                return { 1, 2 }
+            "#).eval().unwrap();
+            let got: Point = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(Point(1, 2), got);
+
+            let value: rlua::Value = lua.load(r#"
+                return { y = 2, x = 1 }
+            "#).eval().unwrap();
+            let got: Point = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(Point(1, 2), got);
+
+            assert!(from_value::<Point>(value).is_err());
+
+            // Keys are read raw, and errors point at the key.
+            let value = lua.load(r#"
+                return setmetatable({ x = 1 }, { __index = function() return 2 end })
+            "#).eval().unwrap();
+            let deserializer = super::Deserializer::with_options(value, options.clone());
+            let err = <Point as serde::Deserialize>::deserialize(deserializer).unwrap_err();
+            assert_eq!(err.path(), &[PathSegment::Key("y".to_owned())][..]);
+
+            let value = lua.load(r#"
+                return { x = 1, y = "two" }
+            "#).eval().unwrap();
+            let deserializer = super::Deserializer::with_options(value, options);
+            let err = <Point as serde::Deserialize>::deserialize(deserializer).unwrap_err();
+            assert_eq!(err.path(), &[PathSegment::Key("y".to_owned())][..]);
+        });
+    }
+
+    #[test]
+    fn test_seq_length_mismatch() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                return { 1, 2, 3 }
+            "#).eval().unwrap();
+            let err = from_value::<(u8, u8)>(value).unwrap_err().to_string();
+            assert!(err.contains("invalid length 3, expected 2 elements in sequence"), "{}", err);
+
+            let value = lua.load(r#"
+                return { 1, 2 }
+            "#).eval().unwrap();
+            let err = from_value::<(u8, u8, u8)>(value).unwrap_err().to_string();
+            assert!(err.contains("invalid length 2, expected a tuple of size 3"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_int_overflow() {
+        let options = |on_int_overflow| DeserializerOptions {
+            on_int_overflow,
+            ..DeserializerOptions::default()
+        };
+
+        let value = rlua::Value::Integer(300);
+        let err = from_value_with::<u8>(value.clone(), options(OverflowPolicy::Error)).unwrap_err();
+        assert!(err.to_string().contains("integer `300`, expected u8"), "{}", err);
+        let got: u8 = from_value_with(value.clone(), options(OverflowPolicy::Saturate)).unwrap();
+        assert_eq!(255, got);
+        let got: u8 = from_value_with(value, options(OverflowPolicy::Wrap)).unwrap();
+        assert_eq!(44, got);
+
+        let value = rlua::Value::Integer(-1);
+        let got: u32 = from_value_with(value.clone(), options(OverflowPolicy::Saturate)).unwrap();
+        assert_eq!(0, got);
+        let got: u64 = from_value_with(value, options(OverflowPolicy::Wrap)).unwrap();
+        assert_eq!(u64::MAX, got);
+    }
+
+    #[test]
+    fn test_transparent() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        #[serde(transparent)]
+        struct Meters(f64);
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Inner {
+            x: u32,
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        #[serde(transparent)]
+        struct Wrapper {
+            inner: Inner,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load("return 1.5").eval().unwrap();
+            assert_eq!(Meters(1.5), from_value(value).unwrap());
+            let value = lua.load("return { x = 7 }").eval().unwrap();
+            assert_eq!(Wrapper { inner: Inner { x: 7 } }, from_value(value).unwrap());
+
+            let expected = Wrapper { inner: Inner { x: 7 } };
+            let value = to_value(lua, &expected).unwrap();
+            assert_eq!(expected, from_value(value).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_externally_tagged() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Shape {
+            Circle { radius: u32 },
+            Rect { w: u32, h: u32 },
+            Empty,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"return { type = "Circle", radius = 5 }"#).eval().unwrap();
+            let value = externally_tagged(lua, value, "type").unwrap();
+            assert_eq!(Shape::Circle { radius: 5 }, from_value(value).unwrap());
+
+            let value = lua.load(r#"return { kind = "Rect", w = 2, h = 3 }"#).eval().unwrap();
+            let value = externally_tagged(lua, value, "kind").unwrap();
+            assert_eq!(Shape::Rect { w: 2, h: 3 }, from_value(value).unwrap());
+
+            let value = lua.load(r#"return { type = "Empty" }"#).eval().unwrap();
+            let value = externally_tagged(lua, value, "type").unwrap();
+            assert_eq!(Shape::Empty, from_value(value).unwrap());
+
+            let value = lua.load(r#"return { radius = 5 }"#).eval().unwrap();
+            assert!(externally_tagged(lua, value, "type").is_err());
+        });
+    }
+
+    #[test]
+    fn test_multi_value() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let values: MultiValue = lua.load(r#"return 1, "two", true"#).eval().unwrap();
+            let got: (u32, String, bool) = from_multi_value(values).unwrap();
+            assert_eq!((1, "two".to_owned(), true), got);
+
+            let values: MultiValue = lua.load(r#"return 1, 2, 3"#).eval().unwrap();
+            let got: Vec<u32> = from_multi_value(values).unwrap();
+            assert_eq!(vec![1, 2, 3], got);
+
+            let values: MultiValue = lua.load(r#"return 1, "two""#).eval().unwrap();
+            let err = from_multi_value::<(u32, String, bool)>(values).unwrap_err();
+            assert!(err.to_string().contains("invalid length 2"), "{}", err);
+
+            let values: MultiValue = lua.load(r#"return 1, "two", true, 4"#).eval().unwrap();
+            let err = from_multi_value::<(u32, String, bool)>(values).unwrap_err();
+            assert!(err.to_string().contains("invalid length 4, expected 3 values"), "{}", err);
+        });
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_binary_value() {
+        use {to_binary_value, from_binary_value};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Test {
+            int: u32,
+            name: String,
+            seq: Vec<Option<f64>>,
+        }
+
+        let expected = Test { int: 7, name: "seven".to_owned(), seq: vec![Some(0.5), None] };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_binary_value(lua, &expected).unwrap();
+            match value {
+                rlua::Value::String(_) => (),
+                _ => panic!("expected a string"),
+            }
+            let got: Test = from_binary_value(value).unwrap();
+            assert_eq!(expected, got);
+
+            assert!(from_binary_value::<Test>(rlua::Value::Integer(1)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_ignore_extra_seq_elements() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value: rlua::Value = lua.load("return { 1, 2, 3, 4 }").eval().unwrap();
+            assert!(from_value::<(u8, u8)>(value.clone()).is_err());
+
+            let options = DeserializerOptions {
+                ignore_extra_seq_elements: true,
+                ..DeserializerOptions::default()
+            };
+            let got: (u8, u8) = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!((1, 2), got);
+            let got: Vec<u8> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(vec![1, 2, 3, 4], got);
+
+            let value = lua.load("return { 1 }").eval().unwrap();
+            assert!(from_value_with::<(u8, u8)>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_value_seed() {
+        use std::fmt;
+        use serde::de::{DeserializeSeed, Deserializer, SeqAccess, Visitor};
+
+        /// Deserializes a sequence of strings into indices of an arena.
+        struct Intern<'a>(&'a mut Vec<String>);
+
+        impl<'a, 'de> DeserializeSeed<'de> for Intern<'a> {
+            type Value = Vec<usize>;
+
+            fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Vec<usize>, D::Error> {
+                deserializer.deserialize_seq(self)
+            }
+        }
+
+        impl<'a, 'de> Visitor<'de> for Intern<'a> {
+            type Value = Vec<usize>;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a sequence of strings")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<usize>, A::Error> {
+                let mut indices = Vec::new();
+                while let Some(s) = seq.next_element::<String>()? {
+                    let idx = match self.0.iter().position(|interned| *interned == s) {
+                        Some(idx) => idx,
+                        None => {
+                            self.0.push(s);
+                            self.0.len() - 1
+                        },
+                    };
+                    indices.push(idx);
+                }
+                Ok(indices)
+            }
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let mut arena = vec!["b".to_owned()];
+            let value = lua.load(r#"return { "a", "b", "a", "c" }"#).eval().unwrap();
+            let got = from_value_seed(value, Intern(&mut arena)).unwrap();
+            assert_eq!(vec![1, 0, 1, 2], got);
+            assert_eq!(vec!["b", "a", "c"], arena);
+        });
+    }
+
+    #[test]
+    fn test_fractional_integer() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            timeout: u32,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load("return { timeout = 1.5 }").eval().unwrap();
+            let err = from_value::<Config>(value).unwrap_err();
+            match err {
+                rlua::Error::ExternalError(ref err) => {
+                    assert_eq!("timeout: expected integer, got float 1.5 with fractional part", err.to_string());
+                },
+                ref err => panic!("unexpected error {:?}", err),
+            }
+
+            let value = rlua::Value::Number(-4.25);
+            let err = from_value::<i64>(value).unwrap_err();
+            assert!(err.to_string().contains("got float -4.25 with fractional part"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_lazy_value() {
+        use super::LazyValue;
+
+        #[derive(Deserialize)]
+        #[serde(bound(deserialize = "'lua: 'de"))]
+        struct Message<'lua> {
+            kind: String,
+            #[serde(borrow)]
+            payload: LazyValue<'lua>,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Move {
+            x: i32,
+            y: i32,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                return {
+                    { kind = "move", payload = { x = 1, y = -2 } },
+                    { kind = "say", payload = "hello" },
+                }
+            "#).eval().unwrap();
+            let messages: Vec<Message> = from_value(value).unwrap();
+            assert_eq!(2, messages.len());
+
+            for message in messages {
+                match message.kind.as_str() {
+                    "move" => {
+                        let got: Move = message.payload.deserialize_into().unwrap();
+                        assert_eq!(Move { x: 1, y: -2 }, got);
+                    },
+                    "say" => {
+                        let got: String = message.payload.deserialize_into().unwrap();
+                        assert_eq!("hello", got);
+                    },
+                    kind => panic!("unexpected kind {}", kind),
+                }
+            }
+
+            let err = serde::de::value::UnitDeserializer::<serde::de::value::Error>::new();
+            assert!(<LazyValue as serde::Deserialize>::deserialize(err).is_err());
+        });
+    }
+
+    #[test]
+    fn test_lazy_value_buffered() {
+        use super::LazyValue;
+
+        #[derive(Deserialize)]
+        #[serde(bound(deserialize = "'lua: 'de"))]
+        struct Payload<'lua> {
+            #[serde(borrow)]
+            payload: LazyValue<'lua>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        #[serde(bound(deserialize = "'lua: 'de"))]
+        struct Flattened<'lua> {
+            kind: String,
+            #[serde(flatten, borrow)]
+            rest: Payload<'lua>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        #[serde(tag = "kind", bound(deserialize = "'lua: 'de"))]
+        enum Tagged<'lua> {
+            Move {
+                #[serde(borrow)]
+                payload: LazyValue<'lua>,
+            },
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        #[serde(bound(deserialize = "'lua: 'de"))]
+        struct FlattenedSource<'lua> {
+            kind: String,
+            #[serde(flatten, borrow)]
+            rest: WithSource<'lua, Payload<'lua>>,
+        }
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"return { kind = "Move", payload = { x = 1 } }"#).eval().unwrap();
+            let expected = "not through a buffering one";
+
+            let err = from_value::<Flattened>(value.clone()).err().unwrap();
+            assert!(err.to_string().contains(expected), "{}", err);
+            let err = from_value::<Tagged>(value.clone()).err().unwrap();
+            assert!(err.to_string().contains(expected), "{}", err);
+            let err = from_value::<FlattenedSource>(value.clone()).err().unwrap();
+            assert!(err.to_string().contains(expected), "{}", err);
+
+            // A token is only good while its hand-over runs.
+            let payload: Payload = from_value(value).unwrap();
+            assert_eq!(payload.payload.deserialize_into::<HashMap<String, i32>>().unwrap()["x"], 1);
+            for token in 0..4 {
+                let replayed = serde::de::value::U64Deserializer::<serde::de::value::Error>::new(token);
+                let err = <LazyValue as serde::Deserialize>::deserialize(replayed).err().unwrap();
+                assert!(err.to_string().contains(expected), "{}", err);
+            }
+            assert!(super::LAZY_VALUE.with(|cell| cell.get()).0.is_null());
+        });
+    }
+
+    #[test]
+    fn test_lazy_value_unwind() {
+        use std::{fmt, panic};
+
+        struct Panicking;
+
+        impl<'de> serde::de::Visitor<'de> for Panicking {
+            type Value = ();
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("anything")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, _token: u64) -> Result<(), E> {
+                panic!("visitor panicked before taking the value")
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load("return { 1 }").eval().unwrap();
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                let deserializer = super::ConfiguredDeserializer::new(value);
+                serde::Deserializer::deserialize_newtype_struct(deserializer, super::LAZY_VALUE_TOKEN, Panicking)
+            }));
+            assert!(result.is_err());
+            assert!(super::LAZY_VALUE.with(|cell| cell.get()).0.is_null());
+        });
+    }
+
+    #[test]
+    fn test_positional_enum() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            Unit,
+            Newtype(u32),
+            Tuple(u32, u32),
+            Struct { a: u32 },
+        }
+
+        let options = DeserializerOptions {
+            enum_repr: ::EnumRepr::Positional,
+            ..DeserializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let cases = vec![
+                (r#"return { "Newtype", 5 }"#, E::Newtype(5)),
+                (r#"return { "Tuple", { 1, 2 } }"#, E::Tuple(1, 2)),
+                (r#"return { "Struct", { a = 3 } }"#, E::Struct { a: 3 }),
+                (r#"return { "Unit" }"#, E::Unit),
+                (r#"return { Newtype = 6 }"#, E::Newtype(6)),
+                (r#"return "Unit""#, E::Unit),
+            ];
+            for (source, expected) in cases {
+                let value = lua.load(source).eval().unwrap();
+                assert_eq!(expected, from_value_with(value, options.clone()).unwrap());
+            }
+
+            let value = lua.load(r#"return { "Newtype", 5 }"#).eval().unwrap();
+            assert!(from_value::<E>(value).is_err());
+            let value = lua.load(r#"return { "Tuple", 1, 2 }"#).eval().unwrap();
+            assert!(from_value_with::<E>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_default_fn() {
+        fn default_port() -> u16 {
+            8080
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Server {
+            host: String,
+            #[serde(default = "default_port")]
+            port: u16,
+        }
+
+        let lookup = DeserializerOptions {
+            struct_field_lookup: true,
+            ..DeserializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let sources = vec![
+                r#"return { host = "a" }"#,
+                r#"return { host = "a", port = nil }"#,
+                r#"local port; return { host = "a", port = port }"#,
+            ];
+            for source in sources {
+                let expected = Server { host: "a".to_owned(), port: 8080 };
+                let value: rlua::Value = lua.load(source).eval().unwrap();
+                assert_eq!(expected, from_value(value.clone()).unwrap());
+                assert_eq!(expected, from_value_with(value, lookup.clone()).unwrap());
+            }
+
+            let value = lua.load(r#"return { host = "a", port = 1 }"#).eval().unwrap();
+            assert_eq!(Server { host: "a".to_owned(), port: 1 }, from_value(value).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_strict_float_precision() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Test {
+            x: f32,
+        }
+
+        let options = DeserializerOptions {
+            strict_float_precision: true,
+            ..DeserializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value: rlua::Value = lua.load("return { x = 16777217 }").eval().unwrap();
+            assert_eq!(Test { x: 16_777_216.0 }, from_value(value.clone()).unwrap());
+            let err = from_value_with::<Test>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("integer 16777217 can't be represented exactly as f32"), "{}", err);
+
+            let value = lua.load("return { x = 16777216 }").eval().unwrap();
+            assert_eq!(Test { x: 16_777_216.0 }, from_value_with(value, options.clone()).unwrap());
+
+            let value = rlua::Value::Integer((1 << 53) + 1);
+            assert!(from_value_with::<f64>(value, options.clone()).is_err());
+            let value = rlua::Value::Integer(i64::MAX);
+            assert!(from_value_with::<f64>(value, options.clone()).is_err());
+            let value = rlua::Value::Integer(1 << 53);
+            assert_eq!(9_007_199_254_740_992.0, from_value_with::<f64>(value, options).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_seq_element_index() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = lua.load(r#"
+                local t = {}
+                for i = 1, 100 do t[i] = i end
+                t[50] = "fifty"
+                return t
+            "#).eval().unwrap();
+            let err = from_value::<Vec<u32>>(value).unwrap_err();
+            assert!(err.to_string().contains("[50]: invalid type: string \"fifty\""), "{}", err);
+
+            let value = lua.load(r#"return { { 1 }, { 2, true } }"#).eval().unwrap();
+            let err = from_value::<Vec<Vec<u32>>>(value).unwrap_err();
+            assert!(err.to_string().contains("[2][2]: invalid type: boolean"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_integral_float_keys() {
+        use std::collections::HashMap;
+
+        let options = DeserializerOptions {
+            map_from_pairs: true,
+            ..DeserializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            // Pair keys aren't table keys, so they stay floats as in Lua 5.1.
+            let value = lua.load(r#"return { { 1.0, "a" }, { 2^40, "b" } }"#).eval().unwrap();
+            let got: HashMap<u64, String> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(Some(&"a".to_owned()), got.get(&1));
+            assert_eq!(Some(&"b".to_owned()), got.get(&(1 << 40)));
+
+            let value = lua.load(r#"return { { 1.5, "a" } }"#).eval().unwrap();
+            assert!(from_value_with::<HashMap<u64, String>>(value, options).is_err());
+
+            let value = lua.load(r#"return { [1.0] = "a", [3] = "c" }"#).eval().unwrap();
+            let got: HashMap<u64, String> = from_value(value).unwrap();
+            assert_eq!(2, got.len());
+        });
+    }
+
+    #[test]
+    fn test_scalar_struct_variant_payload() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            Move { x: u32 },
+            Pair { a: u32, b: u32 },
+        }
+
+        let options = DeserializerOptions {
+            scalar_struct_variant_payload: true,
+            ..DeserializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value: rlua::Value = lua.load("return { Move = 5 }").eval().unwrap();
+            assert!(from_value::<E>(value.clone()).is_err());
+            assert_eq!(E::Move { x: 5 }, from_value_with(value, options.clone()).unwrap());
+
+            let value = lua.load("return { Move = { x = 6 } }").eval().unwrap();
+            assert_eq!(E::Move { x: 6 }, from_value_with(value, options.clone()).unwrap());
+
+            let value = lua.load("return { Pair = 5 }").eval().unwrap();
+            assert!(from_value_with::<E>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_depth() {
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"
+                local t = {}
+                for i = 1, 10000 do t = { t } end
+                return t
+            "#).eval().unwrap();
+            let options = DeserializerOptions { max_depth: Some(128), ..DeserializerOptions::default() };
+            let err = from_value_with::<::serde_json::Value>(value, options).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded: more than 128 nested tables"), "{}", err);
+
+            let value = lua.load("return {{1}, {2, 3}}").eval().unwrap();
+            let options = DeserializerOptions { max_depth: Some(2), ..DeserializerOptions::default() };
+            let got: Vec<Vec<i32>> = from_value_with(value, options).unwrap();
+            assert_eq!(got, vec![vec![1], vec![2, 3]]);
+
+            let value = lua.load("return {{{1}}}").eval().unwrap();
+            let options = DeserializerOptions { max_depth: Some(2), ..DeserializerOptions::default() };
+            assert!(from_value_with::<Vec<Vec<Vec<i32>>>>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_default_max_depth() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Node {
+            name: String,
+            next: Option<Box<Node>>,
+        }
+
+        Lua::new().context(|lua| {
+            let nested = |depth| -> Value {
+                lua.load(&format!(
+                    "local t = {{}} for i = 2, {} do t = {{ t }} end return t", depth,
+                )).eval().unwrap()
+            };
+            assert!(from_value::<::serde_json::Value>(nested(128)).is_ok());
+            let err = from_value::<::serde_json::Value>(nested(129)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+
+            let value = lua.load("local t = { name = 'loop' } t.next = t return t").eval().unwrap();
+            let err = from_value::<Node>(value).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_max_elements() {
+        Lua::new().context(|lua| {
+            let options = DeserializerOptions { max_elements: Some(5), ..DeserializerOptions::default() };
+
+            let value = lua.load("return {{1, 2}, {3}}").eval().unwrap();
+            let got: Vec<Vec<i32>> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, vec![vec![1, 2], vec![3]]);
+
+            let value = lua.load("return {{1, 2}, {3, 4}}").eval().unwrap();
+            let err = from_value_with::<Vec<Vec<i32>>>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("maximum number of 5 elements exceeded"), "{}", err);
+
+            let value = lua.load("return {a = 1, b = 2, c = 3, d = 4, e = 5, f = 6}").eval().unwrap();
+            assert!(from_value_with::<HashMap<String, i32>>(value, options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_unit() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Unit;
+
+        Lua::new().context(|lua| {
+            let value = to_value(lua, ()).unwrap();
+            assert!(matches!(value, Value::Nil));
+            let () = from_value(value).unwrap();
+
+            let () = from_value(to_value(lua, Unit).unwrap()).unwrap();
+            let got: Unit = from_value(to_value(lua, ()).unwrap()).unwrap();
+            assert_eq!(got, Unit);
+
+            assert!(from_value::<()>(Value::Integer(0)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_with_source() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Server {
+            host: String,
+            port: u16,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(bound(deserialize = "'lua: 'de"))]
+        struct Config<'lua> {
+            name: String,
+            #[serde(borrow)]
+            server: WithSource<'lua, Server>,
+        }
+
+        Lua::new().context(|lua| {
+            let value = lua.load(r#"
+                return {
+                    name = "app",
+                    server = { host = "localhost", port = "8080", tls = { cert = "x" } },
+                }
+            "#).eval().unwrap();
+            let options = DeserializerOptions {
+                coerce_numbers_from_str: true,
+                ..DeserializerOptions::default()
+            };
+            let config: Config = from_value_with(value, options).unwrap();
+            assert_eq!(config.name, "app");
+            assert_eq!(config.server.value, Server { host: "localhost".to_owned(), port: 8080 });
+            let tls: rlua::Table = config.server.source.get("tls").unwrap();
+            assert_eq!(tls.get::<_, String>("cert").unwrap(), "x");
+
+            let value = lua.load(r#"return { name = "app", server = "localhost" }"#).eval().unwrap();
+            assert!(from_value::<Config>(value).is_err());
+
+            // Nested hand-overs each get their own table.
+            let value = lua.load(r#"
+                return { name = "app", server = { host = "h", port = 1 }, extra = 1 }
+            "#).eval().unwrap();
+            let outer: WithSource<Config> = from_value(value).unwrap();
+            assert_eq!(outer.source.get::<_, i32>("extra").unwrap(), 1);
+            assert_eq!(outer.value.server.source.get::<_, String>("host").unwrap(), "h");
+            assert!(super::LAZY_VALUE.with(|cell| cell.get()).0.is_null());
+        });
+    }
+
+    #[test]
+    fn test_seq_length_field() {
+        use ser::SerializerOptions;
+        use to_value_with;
+
+        Lua::new().context(|lua| {
+            let seq = vec![Some(1), None, Some(3), None];
+            let ser_options = SerializerOptions {
+                seq_length_field: true,
+                ..SerializerOptions::default()
+            };
+            let options = DeserializerOptions {
+                seq_length_field: true,
+                ..DeserializerOptions::default()
+            };
+            let value = to_value_with(lua, &seq, ser_options).unwrap();
+            let got: Vec<Option<i32>> = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(got, seq);
+            let got: Vec<Option<i32>> = from_value(value).unwrap();
+            assert_eq!(got, vec![Some(1)]);
+
+            let value = lua.load("return table.pack(nil, 'b', nil)").eval().unwrap();
+            let got: (Option<String>, String, Option<String>) = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, (None, "b".to_owned(), None));
+
+            let value = lua.load("return { 1, 2, 3, n = 2 }").eval().unwrap();
+            let got: Vec<i32> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, vec![1, 2]);
+
+            let value = lua.load("return { n = 1000 }").eval().unwrap();
+            let got: Vec<Option<i32>> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got.len(), 1000);
+
+            let value = lua.load("return { 1, 2, n = 1000000000 }").eval().unwrap();
+            let err = from_value_with::<Vec<Option<i32>>>(value, options).unwrap_err();
+            assert!(err.to_string().contains("n = 1000000000"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_key_collisions() {
+        use std::collections::BTreeMap;
+
+        Lua::new().context(|lua| {
+            let value = lua.load(r#"return { b = 2, c = 3, a = 1 }"#).eval().unwrap();
+            let got: BTreeMap<String, i32> = from_value(value).unwrap();
+            let keys: Vec<&str> = got.keys().map(String::as_str).collect();
+            assert_eq!(keys, vec!["a", "b", "c"]);
+
+            let value: Value = lua.load(r#"return { [1] = "int", ["1"] = "str", [2] = "two" }"#).eval().unwrap();
+            let options = DeserializerOptions {
+                coerce_str_from_numbers: true,
+                ..DeserializerOptions::default()
+            };
+            let got: BTreeMap<String, String> = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(got.len(), 2);
+            assert_eq!(got["2"], "two");
+
+            let options = DeserializerOptions { reject_key_collisions: true, ..options };
+            let err = from_value_with::<BTreeMap<String, String>>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("keys colliding as `1`"), "{}", err);
+
+            let value = lua.load(r#"return { [1] = "int", ["2"] = "str" }"#).eval().unwrap();
+            let got: BTreeMap<String, String> = from_value_with(value, options).unwrap();
+            assert_eq!(got["1"], "int");
+            assert_eq!(got["2"], "str");
+        });
+    }
+
+    #[test]
+    fn test_json_number_fidelity() {
+        use serde_json;
+        use ser::SerializerOptions;
+        use to_value_with;
+
+        Lua::new().context(|lua| {
+            let value = lua.load("return { int = 1, float = 1.5, integral_float = 2.0 }").eval().unwrap();
+            let json: serde_json::Value = from_value(value).unwrap();
+            assert!(json["int"].as_number().unwrap().is_i64());
+            assert_eq!(json["int"], 1);
+            assert!(json["float"].as_number().unwrap().is_f64());
+            assert_eq!(json["float"], 1.5);
+            assert!(json["integral_float"].as_number().unwrap().is_f64());
+
+            let options = SerializerOptions { mark_arrays: true, ..SerializerOptions::default() };
+            let value = to_value_with(lua, (vec![1, 2], vec![0.5]), options).unwrap();
+            let json: serde_json::Value = from_value(value).unwrap();
+            assert!(json[0][1].as_number().unwrap().is_i64());
+            assert!(json[1][0].as_number().unwrap().is_f64());
+
+            let value = to_value(lua, serde_json::json!({ "n": 3, "x": 3.0 })).unwrap();
+            let json: serde_json::Value = from_value(value).unwrap();
+            assert!(json["n"].is_i64());
+            assert!(json["x"].is_f64());
+        });
+    }
+
+    #[test]
+    fn test_from_value_then() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Volume {
+            level: u8,
+            name: String,
+        }
+
+        fn validate(volume: &mut Volume) -> Result<(), String> {
+            if volume.level > 100 {
+                return Err(format!("level {} is above 100", volume.level));
+            }
+            volume.name = volume.name.trim().to_owned();
+            Ok(())
+        }
+
+        Lua::new().context(|lua| {
+            let value = lua.load(r#"return { level = 50, name = " main " }"#).eval().unwrap();
+            let got: Volume = from_value_then(value, validate).unwrap();
+            assert_eq!(got, Volume { level: 50, name: "main".to_owned() });
+
+            let value = lua.load(r#"return { level = 150, name = "main" }"#).eval().unwrap();
+            let err = from_value_then(value, validate).unwrap_err();
+            match err {
+                rlua::Error::ExternalError(ref err) => {
+                    assert_eq!(err.to_string(), "level 150 is above 100");
+                },
+                _ => panic!("unexpected error: {}", err),
+            }
+
+            let value = lua.load(r#"return { level = "high", name = "main" }"#).eval().unwrap();
+            assert!(from_value_then(value, validate).is_err());
+        });
+    }
+
+    #[test]
+    fn test_numeric_cross_types() {
+        use rlua::Value;
+
+        assert_eq!(3, from_value::<i64>(Value::Integer(3)).unwrap());
+        assert_eq!(3.0, from_value::<f64>(Value::Integer(3)).unwrap());
+        assert_eq!(3.0, from_value::<f32>(Value::Integer(3)).unwrap());
+        assert_eq!(-7.0, from_value::<f64>(Value::Integer(-7)).unwrap());
+
+        assert_eq!(3.5, from_value::<f64>(Value::Number(3.5)).unwrap());
+        assert_eq!(3.5, from_value::<f32>(Value::Number(3.5)).unwrap());
+        assert_eq!(3, from_value::<i64>(Value::Number(3.0)).unwrap());
+        assert_eq!(3, from_value::<u8>(Value::Number(3.0)).unwrap());
+        assert_eq!(-3, from_value::<i32>(Value::Number(-3.0)).unwrap());
+
+        let err = from_value::<i64>(Value::Number(3.5)).unwrap_err();
+        assert!(err.to_string().contains("with fractional part"), "{}", err);
+        let err = from_value::<i64>(Value::Number(1e20)).unwrap_err();
+        assert!(err.to_string().contains("out of integer range"), "{}", err);
+        assert!(from_value::<i64>(Value::Number(f64::INFINITY)).is_err());
+        assert!(from_value::<i64>(Value::Number(f64::NAN)).is_err());
+        assert!(from_value::<u8>(Value::Number(300.0)).is_err());
+        assert!(from_value::<u8>(Value::Number(-1.0)).is_err());
+
+        assert!(from_value::<f64>(Value::Boolean(true)).is_err());
+        assert!(from_value::<i64>(Value::Boolean(true)).is_err());
+    }
+
+    #[test]
+    fn test_bytes() {
+        use std::fmt;
+
+        #[derive(Debug, PartialEq)]
+        struct Blob(Vec<u8>);
+
+        impl<'de> serde::Deserialize<'de> for Blob {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct BlobVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for BlobVisitor {
+                    type Value = Blob;
+
+                    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                        fmt.write_str("bytes")
+                    }
+
+                    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Blob, E> {
+                        Ok(Blob(v))
+                    }
+
+                    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Blob, E> {
+                        Ok(Blob(v.to_vec()))
+                    }
+                }
+
+                deserializer.deserialize_byte_buf(BlobVisitor)
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"return "\0\255\128abc""#).eval().unwrap();
+            let expected = vec![0, 255, 128, b'a', b'b', b'c'];
+            let got: Blob = from_value(value.clone()).unwrap();
+            assert_eq!(got, Blob(expected.clone()));
+            assert!(from_value::<String>(value.clone()).is_err());
+            // Only byte buffers take strings, not every sequence.
+            assert!(from_value::<Vec<u8>>(value).is_err());
+            let value: Value = lua.load("return '12'").eval().unwrap();
+            assert!(from_value::<Vec<i32>>(value.clone()).is_err());
+            assert!(from_value::<(i32, i32)>(value).is_err());
+
+            let value = lua.load("return { 1, 2, 3 }").eval().unwrap();
+            let got: Vec<u8> = from_value(value).unwrap();
+            assert_eq!(got, vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_eval_into() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Window {
+            title: String,
+            size: (u32, u32),
+        }
+
+        Lua::new().context(|lua| {
+            let got: Window = lua.eval_into(r#"
+                local width = 640
+                return { title = "main", size = { width, width * 3 / 4 } }
+            "#).unwrap();
+            assert_eq!(got, Window { title: "main".to_owned(), size: (640, 480) });
+
+            let err = lua.eval_into::<Window>("return {").unwrap_err();
+            assert!(matches!(err, rlua::Error::SyntaxError { .. }), "{}", err);
+            let err = lua.eval_into::<Window>("error('boom')").unwrap_err();
+            assert!(matches!(err, rlua::Error::RuntimeError(_)), "{}", err);
+            let err = lua.eval_into::<Window>("return { title = 1 }").unwrap_err();
+            assert!(matches!(err, rlua::Error::ExternalError(_)), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_adjacent_enum() {
+        use ser::SerializerOptions;
+        use {to_value_with, EnumRepr};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum E {
+            Unit,
+            Newtype(Option<u32>),
+            Tuple(u32, String),
+            Struct { a: u32 },
+            Empty {},
+        }
+
+        let repr = EnumRepr::Adjacent { tag: "kind".to_owned(), content: "value".to_owned() };
+        let ser_options = SerializerOptions { enum_repr: repr.clone(), ..SerializerOptions::default() };
+        let options = DeserializerOptions { enum_repr: repr, ..DeserializerOptions::default() };
+
+        Lua::new().context(|lua| {
+            let values = vec![
+                E::Unit,
+                E::Newtype(Some(1)),
+                E::Newtype(None),
+                E::Tuple(2, "b".to_owned()),
+                E::Struct { a: 3 },
+                E::Empty {},
+            ];
+            for value in values {
+                let serialized = to_value_with(lua, &value, ser_options.clone()).unwrap();
+                let got: E = from_value_with(serialized, options.clone()).unwrap();
+                assert_eq!(got, value);
+            }
+
+            let value = to_value_with(lua, E::Unit, ser_options.clone()).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(r#"
+                assert(value.kind == "Unit")
+                assert(next(value, next(value)) == nil)
+            "#).exec().unwrap();
+
+            let value = lua.load(r#"return { kind = "Unit" }"#).eval().unwrap();
+            assert_eq!(from_value_with::<E>(value, options.clone()).unwrap(), E::Unit);
+            let value = lua.load(r#"return { kind = "Struct", value = { a = 5 } }"#).eval().unwrap();
+            assert_eq!(from_value_with::<E>(value, options.clone()).unwrap(), E::Struct { a: 5 });
+            let value = lua.load(r#"return { kind = "Unit", value = 1 }"#).eval().unwrap();
+            assert!(from_value_with::<E>(value, options.clone()).is_err());
+            let value = lua.load(r#"return { kind = "Tuple" }"#).eval().unwrap();
+            assert!(from_value_with::<E>(value, options.clone()).is_err());
+            let value = lua.load(r#"return { value = 1 }"#).eval().unwrap();
+            assert!(from_value_with::<E>(value, options).is_err());
+        });
+    }
 
     #[test]
-    fn test_struct() {
+    fn test_any_sequence_detection() {
+        use serde_json::{self, Value as Json};
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let cases = [
+                ("{}", serde_json::json!({})),
+                ("{1, 2, 3}", serde_json::json!([1, 2, 3])),
+                ("{a = 1}", serde_json::json!({"a": 1})),
+                ("{1, 2, a = 3}", serde_json::json!({"1": 1, "2": 2, "a": 3})),
+            ];
+            for &(source, ref expected) in cases.iter() {
+                let value = lua.load(&format!("return {}", source)).eval().unwrap();
+                let options = DeserializerOptions {
+                    coerce_str_from_numbers: true,
+                    ..DeserializerOptions::default()
+                };
+                let got: Json = from_value_with(value, options).unwrap();
+                assert_eq!(&got, expected, "{}", source);
+            }
+
+            let value = lua.load("return {10, 20}").eval().unwrap();
+            let got: HashMap<i64, i64> = from_value(value).unwrap();
+            assert_eq!(got, vec![(1, 10), (2, 20)].into_iter().collect());
+        });
+    }
+
+    #[test]
+    fn test_structured_errors() {
+        use error::Error;
+        use super::Deserializer;
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            port: u16,
+        }
+
+        Lua::new().context(|lua| {
+            let error = |source: &str| {
+                let value = lua.load(source).eval().unwrap();
+                <Config as serde::Deserialize>::deserialize(Deserializer::new(value)).unwrap_err()
+            };
+            assert!(matches!(error("return { port = 70000 }").kind(), Error::NumberOverflow(_)));
+            assert!(matches!(error("return { port = 1e30 }").kind(), Error::NumberOverflow(_)));
+            assert!(matches!(error("return { port = print }").kind(), Error::UnsupportedType("function")));
+            let err = error("return { port = coroutine.create(print) }");
+            assert!(matches!(err.kind(), Error::UnsupportedType("thread")));
+            assert_eq!(err.to_string(), "port: cannot deserialize Lua thread");
+            assert!(matches!(error("return { [1] = 80 }"), Error::KeyMustBeString));
+            assert!(matches!(error("return { port = 'x' }").kind(), Error::Custom(_)));
+            assert!(matches!(error("return {}"), Error::Custom(_)));
+
+            let err = rlua::Error::from(error("return { port = 70000 }"));
+            assert_eq!(err.to_string(), "external error: port: invalid value: integer `70000`, expected u16");
+
+            // The kind is kept through the functions returning `rlua::Error`.
+            let kind = |source: &str| {
+                let value = lua.load(source).eval().unwrap();
+                match from_value::<Config>(value).unwrap_err() {
+                    rlua::Error::ExternalError(ref err) => match err.downcast_ref::<Error>().map(Error::kind) {
+                        Some(&Error::NumberOverflow(_)) => "overflow",
+                        Some(&Error::KeyMustBeString) => "key",
+                        Some(err) => panic!("unexpected error: {}", err),
+                        None => panic!("not a deserialization error: {}", err),
+                    },
+                    err => panic!("unexpected error: {}", err),
+                }
+            };
+            assert_eq!(kind("return { port = 70000 }"), "overflow");
+            assert_eq!(kind("return { [1] = 80 }"), "key");
+        });
+    }
+
+    #[test]
+    fn test_bool_variants() {
         #[derive(Deserialize, PartialEq, Debug)]
-        struct Test {
-            int: u32,
-            seq: Vec<String>,
-            map: std::collections::HashMap<i32, i32>,
-            empty: Vec<()>,
+        enum Mode {
+            Fast,
+            Safe,
         }
 
-        let expected = Test {
-            int: 1,
-            seq: vec!["a".to_owned(), "b".to_owned()],
-            map: vec![(1, 2), (4, 1)].into_iter().collect(),
-            empty: vec![]
-        };
+        let mut options = DeserializerOptions::default();
+        options.bool_variants.insert("Mode", ("Fast", "Safe"));
 
-        println!("{:?}", expected);
-        let lua = Lua::new();
-        lua.context(|lua| {
-            let value = lua.load(r#"
-                a = {}
-                a.int = 1
-                a.seq = {"a", "b"}
-                a.map = {2, [4]=1}
-                a.empty = {}
-                return a
-            "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+        let got: Mode = from_value_with(Value::Boolean(true), options.clone()).unwrap();
+        assert_eq!(got, Mode::Fast);
+        let got: Mode = from_value_with(Value::Boolean(false), options.clone()).unwrap();
+        assert_eq!(got, Mode::Safe);
+
+        assert!(from_value::<Mode>(Value::Boolean(true)).is_err());
+        options.bool_variants.insert("Mode", ("Fast", "Slow"));
+        assert!(from_value_with::<Mode>(Value::Boolean(false), options).is_err());
+    }
+
+    #[test]
+    fn test_error_path() {
+        use error::{Error, PathSegment};
+        use super::Deserializer;
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            bar: Bar,
+        }
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Bar {
+            baz: Vec<u32>,
+            shapes: HashMap<String, Shape>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        enum Shape {
+            Circle { radius: f64 },
+        }
+
+        Lua::new().context(|lua| {
+            let error = |source: &str| {
+                let value = lua.load(source).eval().unwrap();
+                <Config as serde::Deserialize>::deserialize(Deserializer::new(value)).unwrap_err()
+            };
+
+            let err = error(r#"return { bar = { baz = { 1, 2, "three" }, shapes = {} } }"#);
+            assert_eq!(err.path(), &[
+                PathSegment::Key("bar".to_owned()),
+                PathSegment::Key("baz".to_owned()),
+                PathSegment::Index(3),
+            ]);
+            assert!(matches!(err.kind(), Error::Custom(_)));
+            assert!(err.to_string().starts_with("bar.baz[3]: invalid type: string \"three\""), "{}", err);
+
+            let err = error(r#"return { bar = { baz = {}, shapes = { a = { Circle = { radius = "x" } } } } }"#);
+            assert!(err.to_string().starts_with("bar.shapes.a.Circle.radius: invalid type"), "{}", err);
+
+            let err = error(r#"return { bar = { baz = {} } }"#);
+            assert!(err.to_string().starts_with("bar: missing field `shapes`"), "{}", err);
+            let err = error(r#"return { bar = 1 }"#);
+            assert!(err.to_string().starts_with("bar: invalid type"), "{}", err);
+
+            let value = lua.load(r#"return { bar = { baz = { [1] = 1, [2] = "x" } } }"#).eval().unwrap();
+            let err = from_value::<HashMap<String, Bar>>(value).unwrap_err();
+            assert!(err.to_string().contains("bar.baz[2]: invalid type"), "{}", err);
         });
     }
 
     #[test]
-    fn test_tuple() {
+    fn test_ignored_any() {
         #[derive(Deserialize, PartialEq, Debug)]
-        struct Rgb(u8, u8, u8);
+        struct Config {
+            name: String,
+        }
 
-        let lua = Lua::new();
-        lua.context(|lua| {
-            let expected = Rgb(1, 2, 3);
-            let value = lua.load(
-                r#"
-                a = {1, 2, 3}
-                return a
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"
+                local big = {}
+                for i = 1, 100000 do big[i] = { i, tostring(i) } end
+                return { name = "main", big = big, nested = { big = big } }
             "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
 
-            let expected = (1, 2, 3);
-            let value = lua.load(
-                r#"
-                a = {1, 2, 3}
-                return a
-            "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+            // Walking the ignored tables would exceed the limit.
+            let options = DeserializerOptions {
+                max_elements: Some(10),
+                ..DeserializerOptions::default()
+            };
+            let got: Config = from_value_with(value, options).unwrap();
+            assert_eq!(got, Config { name: "main".to_owned() });
         });
     }
 
     #[test]
-    fn test_enum() {
+    fn test_from_value_ref() {
         #[derive(Deserialize, PartialEq, Debug)]
-        enum E {
-            Unit,
-            Newtype(u32),
-            Tuple(u32, u32),
-            Struct { a: u32 },
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Named {
+            name: String,
+        }
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load("return { name = 'origin' }").eval().unwrap();
+            assert!(from_value_ref::<Point>(&value).is_err());
+            let got: Named = from_value_ref(&value).unwrap();
+            assert_eq!(got, Named { name: "origin".to_owned() });
+
+            let got: HashMap<String, String> = from_value(value).unwrap();
+            assert_eq!(got["name"], "origin");
+        });
+    }
+
+    #[test]
+    fn test_map_entries_as_seq() {
+        let options = DeserializerOptions {
+            map_entries_as_seq: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load("return { b = 2, a = 1, c = 3 }").eval().unwrap();
+            let got: Vec<(String, i32)> = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(got, vec![("a".to_owned(), 1), ("b".to_owned(), 2), ("c".to_owned(), 3)]);
+            assert!(from_value::<Vec<(String, i32)>>(value).unwrap().is_empty());
+
+            let value: Value = lua.load("return { [10] = 'x', [-1] = 'y', z = 'z' }").eval().unwrap();
+            let got: Vec<(String, String)> = from_value_with(value, DeserializerOptions {
+                coerce_str_from_numbers: true,
+                ..options.clone()
+            }).unwrap();
+            let keys: Vec<&str> = got.iter().map(|entry| entry.0.as_str()).collect();
+            assert_eq!(keys, vec!["-1", "10", "z"]);
+
+            let value: Value = lua.load("return { { 'a', 1 } }").eval().unwrap();
+            let got: Vec<(String, i32)> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, vec![("a".to_owned(), 1)]);
+
+            let value: Value = lua.load("return { a = 'one' }").eval().unwrap();
+            let err = from_value_with::<Vec<(String, i32)>>(value, options).unwrap_err();
+            assert!(err.to_string().contains("a: invalid type"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_unsupported_types() {
+        struct Handle;
+
+        impl rlua::UserData for Handle {}
+
+        Lua::new().context(|lua| {
+            let values = vec![
+                (lua.load("return print").eval().unwrap(), "function"),
+                (lua.load("return coroutine.create(print)").eval().unwrap(), "thread"),
+                (Value::UserData(lua.create_userdata(Handle).unwrap()), "userdata"),
+                (Value::LightUserData(rlua::LightUserData(::std::ptr::null_mut())), "light userdata"),
+            ];
+            for (value, ty) in values {
+                let err = from_value::<::serde_json::Value>(value).unwrap_err();
+                assert!(err.to_string().contains(&format!("cannot deserialize Lua {}", ty)), "{}", err);
+            }
+
+            let value = lua.load("return { name = 'x', callback = print }").eval().unwrap();
+            let err = from_value::<HashMap<String, String>>(value).unwrap_err();
+            assert!(err.to_string().contains("callback: cannot deserialize Lua function"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_strict_seq() {
+        let options = DeserializerOptions {
+            strict_seq: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"return { 1, 2, foo = "bar" }"#).eval().unwrap();
+            assert_eq!(from_value::<Vec<i32>>(value.clone()).unwrap(), vec![1, 2]);
+            let err = from_value_with::<Vec<i32>>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("unexpected key `foo` in sequence"), "{}", err);
+
+            let value: Value = lua.load("return { 1, 2, [4] = 4 }").eval().unwrap();
+            let err = from_value_with::<Vec<i32>>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("unexpected key `[4]` in sequence"), "{}", err);
+
+            let value: Value = lua.load("return { 1, 2, 3 }").eval().unwrap();
+            assert_eq!(from_value_with::<Vec<i32>>(value, options.clone()).unwrap(), vec![1, 2, 3]);
+
+            // The length of a table with a hole may be 3, but only 1 is read.
+            let value: Value = lua.load("return { 1, nil, 3 }").eval().unwrap();
+            assert_eq!(from_value::<Vec<i32>>(value.clone()).unwrap(), vec![1]);
+            let err = from_value_with::<Vec<i32>>(value, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("unexpected key `[3]` in sequence"), "{}", err);
+
+            let value: Value = lua.load("return table.pack(1, nil, 3)").eval().unwrap();
+            let got: Vec<Option<i32>> = from_value_with(value, DeserializerOptions {
+                seq_length_field: true,
+                ..options
+            }).unwrap();
+            assert_eq!(got, vec![Some(1), None, Some(3)]);
+        });
+    }
+
+    #[test]
+    fn test_registry() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Config {
+            name: String,
+            limits: Vec<u32>,
         }
 
+        let config = Config { name: "main".to_owned(), limits: vec![1, 2, 3] };
         let lua = Lua::new();
+        let stored = lua.context(|lua| to_registry(lua, &config).unwrap());
+
         lua.context(|lua| {
-            let expected = E::Unit;
-            let value = lua.load(
-                r#"
-                return "Unit"
-            "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+            lua.load("local t = {} for i = 1, 1000 do t[i] = {i} end collectgarbage()")
+                .exec().unwrap();
+            lua.expire_registry_values();
+            assert_eq!(from_registry(lua, &stored).unwrap(), config);
+        });
 
+        Lua::new().context(|lua| {
+            match from_registry(lua, &stored) {
+                Err(::rlua::Error::MismatchedRegistryKey) => (),
+                got => panic!("unexpected {:?}", got),
+            }
+        });
 
-            let expected = E::Newtype(1);
-            let value = lua.load(
-                r#"
-                a = {}
-                a["Newtype"] = 1
-                return a
-            "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+        lua.context(|lua| lua.remove_registry_value(stored.into_key()).unwrap());
+    }
 
-            let expected = E::Tuple(1, 2);
-            let value = lua.load(
-                r#"
-                a = {}
-                a["Tuple"] = {1, 2}
-                return a
-            "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+    #[test]
+    fn test_f64_from_integer() {
+        use std::fmt;
 
-            let expected = E::Struct { a: 1 };
-            let value = lua.load(
-                r#"
-                a = {}
-                a["Struct"] = {}
-                a["Struct"]["a"] = 1
-                return a
+        // Only accepts floats, like untyped wrappers which prefer them.
+        struct FloatVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FloatVisitor {
+            type Value = f64;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a float")
+            }
+
+            fn visit_f64<E>(self, v: f64) -> ::std::result::Result<f64, E> {
+                Ok(v)
+            }
+        }
+
+        Lua::new().context(|lua| {
+            for &(source, expected) in &[("return 3", 3.0), ("return -7", -7.0), ("return 1.5", 1.5)] {
+                let value: Value = lua.load(source).eval().unwrap();
+                let deserializer = super::Deserializer::new(value);
+                let got = serde::Deserializer::deserialize_f64(deserializer, FloatVisitor).unwrap();
+                assert_eq!(got, expected);
+            }
+
+            let value: Value = lua.load("return 3").eval().unwrap();
+            let deserializer = super::Deserializer::new(value);
+            assert!(serde::Deserializer::deserialize_any(deserializer, FloatVisitor).is_err());
+        });
+    }
+
+    #[test]
+    fn test_coercion_report() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Server {
+            port: u16,
+            weight: f64,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Config {
+            name: String,
+            servers: Vec<Server>,
+        }
+
+        let options = DeserializerOptions {
+            coerce_numbers_from_str: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"
+                return {
+                    name = "main",
+                    servers = { { port = 80, weight = 1.5 }, { port = "8080", weight = 2 } },
+                }
             "#).eval().unwrap();
-            let got = from_value(value).unwrap();
-            assert_eq!(expected, got);
+            let (config, coercions) = from_value_with_report::<Config>(value, options.clone()).unwrap();
+            assert_eq!(config.servers[1], Server { port: 8080, weight: 2.0 });
+
+            let path = |field: &str| vec![
+                PathSegment::Key("servers".to_owned()),
+                PathSegment::Index(2),
+                PathSegment::Key(field.to_owned()),
+            ];
+            // Fields are read in the order of the table.
+            let mut coercions = coercions;
+            coercions.sort_by_key(|coercion| coercion.to);
+            assert_eq!(coercions, vec![
+                Coercion { path: path("weight"), from: "integer", to: "float" },
+                Coercion { path: path("port"), from: "string", to: "integer" },
+            ]);
+
+            let value: Value = lua.load("return { name = 'main', servers = {} }").eval().unwrap();
+            let (_, coercions) = from_value_with_report::<Config>(value, options).unwrap();
+            assert!(coercions.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_nil_as_empty_collection() {
+        let options = DeserializerOptions {
+            nil_as_empty_collection: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let got: Vec<i32> = from_value_with(Value::Nil, options.clone()).unwrap();
+            assert!(got.is_empty());
+            let got: HashMap<String, i32> = from_value_with(Value::Nil, options.clone()).unwrap();
+            assert!(got.is_empty());
+
+            let err = from_value::<Vec<i32>>(Value::Nil).unwrap_err();
+            assert!(err.to_string().contains("cannot deserialize Lua nil"), "{}", err);
+            assert!(from_value::<HashMap<String, i32>>(Value::Nil).is_err());
+
+            let value: Value = lua.load("return table.pack({ 1 }, nil)").eval().unwrap();
+            let got: (Vec<i32>, Vec<i32>) = from_value_with(value, DeserializerOptions {
+                seq_length_field: true,
+                ..options
+            }).unwrap();
+            assert_eq!(got, (vec![1], vec![]));
+        });
+    }
+
+    #[test]
+    fn test_char() {
+        Lua::new().context(|lua| {
+            let value = Value::String(lua.create_string("a").unwrap());
+            assert_eq!(from_value::<char>(value).unwrap(), 'a');
+            let value = Value::String(lua.create_string("é").unwrap());
+            assert_eq!(from_value::<char>(value).unwrap(), 'é');
+
+            let value = Value::String(lua.create_string("").unwrap());
+            let err = from_value::<char>(value).unwrap_err();
+            assert!(err.to_string().contains("expected a single character, got an empty string"), "{}", err);
+
+            let value = Value::String(lua.create_string("ab").unwrap());
+            let err = from_value::<char>(value).unwrap_err();
+            assert!(err.to_string().contains("got a string of 2 characters"), "{}", err);
+
+            assert!(from_value::<char>(Value::Integer(1)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_newtype_struct_round_trip() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Meters(f64);
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Path(Vec<Meters>);
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Route(Path);
+
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+        struct Id(u32);
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Trip {
+            route: Route,
+            stops: HashMap<Id, Meters>,
+        }
+
+        Lua::new().context(|lua| {
+            let route = Route(Path(vec![Meters(1.5), Meters(2.0)]));
+            let value = to_value(lua, &route).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load("assert(#value == 2 and value[1] == 1.5)").exec().unwrap();
+            assert_eq!(from_value::<Route>(value).unwrap(), route);
+
+            let mut stops = HashMap::new();
+            stops.insert(Id(3), Meters(0.5));
+            let trip = Trip { route, stops };
+            let value = to_value(lua, &trip).unwrap();
+            assert_eq!(from_value::<Trip>(value).unwrap(), trip);
+
+            // Integers are read into float newtypes like into floats.
+            let value: Value = lua.load("return { 1, 2 }").eval().unwrap();
+            assert_eq!(from_value::<Path>(value).unwrap(), Path(vec![Meters(1.0), Meters(2.0)]));
+        });
+    }
+
+    #[test]
+    fn test_default_variants() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Source {
+            File { path: String },
+            Inline { text: String, lang: Option<String> },
+        }
+
+        let mut options = DeserializerOptions::default();
+        options.default_variants.insert("Source", "Inline");
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"return { text = "print(1)", lang = "lua" }"#).eval().unwrap();
+            let got: Source = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(got, Source::Inline { text: "print(1)".to_owned(), lang: Some("lua".to_owned()) });
+            assert!(from_value::<Source>(value).is_err());
+
+            let value: Value = lua.load(r#"return { text = "x" }"#).eval().unwrap();
+            let got: Source = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, Source::Inline { text: "x".to_owned(), lang: None });
+
+            // Tagged tables are read as usual.
+            let value: Value = lua.load(r#"return { File = { path = "a.lua" } }"#).eval().unwrap();
+            let got: Source = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, Source::File { path: "a.lua".to_owned() });
+
+            let value: Value = lua.load(r#"return { path = "a.lua" }"#).eval().unwrap();
+            let err = from_value_with::<Source>(value, options).unwrap_err();
+            assert!(err.to_string().contains("missing field `text`"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_int128() {
+        use ser::{SerializerOptions, Int128Encoding};
+        use to_value_with;
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Ids {
+            signed: i128,
+            unsigned: u128,
+        }
+
+        Lua::new().context(|lua| {
+            let words = SerializerOptions {
+                int128: Int128Encoding::Words,
+                ..SerializerOptions::default()
+            };
+            for &(signed, unsigned) in &[
+                (0, 0),
+                (-1, 1),
+                (i128::MIN, u128::MAX),
+                (i128::MAX, 1 << 127),
+                (i128::from(i64::MIN) - 1, u128::from(u64::MAX) + 1),
+            ] {
+                let ids = Ids { signed, unsigned };
+                let value = to_value(lua, &ids).unwrap();
+                assert_eq!(from_value::<Ids>(value).unwrap(), ids);
+                let value = to_value_with(lua, &ids, words.clone()).unwrap();
+                assert_eq!(from_value::<Ids>(value).unwrap(), ids);
+            }
+
+            // Plain Lua integers are accepted too.
+            let value: Value = lua.load("return { signed = -5, unsigned = 5.0 }").eval().unwrap();
+            assert_eq!(from_value::<Ids>(value).unwrap(), Ids { signed: -5, unsigned: 5 });
+
+            for (source, message) in &[
+                ("return -1", "integer `-1`, expected u128"),
+                ("return '-1'", "`-1` is out of range of u128"),
+                ("return '340282366920938463463374607431768211456'", "out of range of u128"),
+                ("return '12ab'", "expected a decimal integer string"),
+                ("return { 1 }", "expected a { high, low } sequence of two integers"),
+                ("return { 1, 'x' }", "expected a { high, low } sequence of two integers"),
+            ] {
+                let value: Value = lua.load(*source).eval().unwrap();
+                let err = from_value::<u128>(value).unwrap_err();
+                assert!(err.to_string().contains(message), "{}: {}", source, err);
+            }
+            let value: Value = lua.load("return '170141183460469231731687303715884105728'").eval().unwrap();
+            let err = from_value::<i128>(value).unwrap_err();
+            assert!(err.to_string().contains("out of range of i128"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_scalar_as_singleton_seq() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Rule {
+            tags: Vec<String>,
+            ports: Vec<u16>,
+        }
+
+        let options = DeserializerOptions {
+            scalar_as_singleton_seq: true,
+            ..DeserializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"return { tags = "x", ports = 80 }"#).eval().unwrap();
+            let got: Rule = from_value_with(value.clone(), options.clone()).unwrap();
+            assert_eq!(got, Rule { tags: vec!["x".to_owned()], ports: vec![80] });
+            assert!(from_value::<Rule>(value).is_err());
+
+            let value: Value = lua.load(r#"return { tags = { "x", "y" }, ports = {} }"#).eval().unwrap();
+            let got: Rule = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, Rule { tags: vec!["x".to_owned(), "y".to_owned()], ports: vec![] });
+
+            let value: Value = lua.load("return 'ab'").eval().unwrap();
+            let got: Vec<String> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(got, vec!["ab".to_owned()]);
+
+            // nil is not a scalar.
+            assert!(from_value_with::<Vec<String>>(Value::Nil, options.clone()).is_err());
+            let got: Vec<String> = from_value_with(Value::Nil, DeserializerOptions {
+                nil_as_empty_collection: true,
+                ..options.clone()
+            }).unwrap();
+            assert!(got.is_empty());
+
+            let value: Value = lua.load("return true").eval().unwrap();
+            assert!(from_value_with::<(bool, bool)>(value, options).is_err());
         });
     }
 }
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::sync::Arc;
 use std::error::Error as StdError;
 use std::result::Result as StdResult;
 
@@ -6,51 +7,182 @@ use serde;
 use rlua::Error as LuaError;
 
 
+/// Step of the path from the deserialized value to where an error occurred,
+/// see `Error::path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathSegment {
+    /// Non-integer key of a table, such as a struct field.
+    Key(String),
+    /// Integer key of a table, such as the (1-based) index of a sequence
+    /// element.
+    Index(i64),
+    /// Enum variant whose payload is being deserialized.
+    Variant(String),
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathSegment::Key(ref key) | PathSegment::Variant(ref key) => fmt.write_str(key),
+            PathSegment::Index(index) => write!(fmt, "[{}]", index),
+        }
+    }
+}
+
+
+/// Error of the `Serializer` and `Deserializer`.
+///
+/// The functions of this crate return `rlua::Error`, into which this converts:
+/// `Lua` errors are returned as is and all the others are wrapped in
+/// `ExternalError`, from which they can be recovered with `downcast_ref`:
+///
+/// ```
+/// extern crate rlua;
+/// extern crate rlua_serde;
+///
+/// use rlua_serde::error::Error;
+///
+/// fn main() {
+///     let value = rlua::Value::Integer(300);
+///     match rlua_serde::from_value::<u8>(value) {
+///         Err(rlua::Error::ExternalError(ref err)) => match err.downcast_ref::<Error>().map(Error::kind) {
+///             Some(Error::NumberOverflow(_)) => (),
+///             kind => panic!("unexpected error kind: {:?}", kind),
+///         },
+///         result => panic!("unexpected result: {:?}", result),
+///     }
+/// }
+/// ```
+///
+/// Earlier versions returned these errors as `FromLuaConversionError` or
+/// `ToLuaConversionError` with the message only, so code matching on those
+/// for errors of this crate needs to match on `ExternalError` instead.
 #[derive(Debug)]
-pub struct Error(LuaError);
+pub enum Error {
+    /// Serialization failed, e.g. with `serde::ser::Error::custom`.
+    Serialize(String),
+    /// Deserialization failed, e.g. with `serde::de::Error::custom`, which
+    /// serde also uses for type mismatches and missing fields.
+    Custom(String),
+    /// A Lua value of a type which can't be deserialized, such as a function.
+    UnsupportedType(&'static str),
+    /// A table key which is not a string where a name was expected, such as
+    /// a struct field.
+    KeyMustBeString,
+    /// A number out of range of the type it is deserialized into.
+    NumberOverflow(String),
+    /// An error from Lua itself, e.g. while creating a table.
+    Lua(LuaError),
+    /// A deserialization error which occurred within a table, at `path`.
+    /// Displayed as in `bar.baz[2]: message`.
+    AtPath {
+        path: Vec<PathSegment>,
+        error: Box<Error>,
+    },
+}
 
 pub type Result<T> = StdResult<T, Error>;
 
+impl Error {
+    /// Prefixes a serialization error with the name of the enum variant
+    /// being serialized.
+    pub(crate) fn variant(variant: &str, err: Error) -> Error {
+        match err {
+            Error::Serialize(message) => {
+                Error::Serialize(format!("in variant `{}`: {}", variant, message))
+            },
+            err => err,
+        }
+    }
+
+    /// Prepends a segment to the path of a deserialization error, as it
+    /// bubbles up from the table entry being deserialized. Lua and
+    /// serialization errors are left as is.
+    pub(crate) fn at(segment: PathSegment, err: Error) -> Error {
+        match err {
+            Error::AtPath { mut path, error } => {
+                path.insert(0, segment);
+                Error::AtPath { path, error }
+            },
+            err @ Error::Lua(_) | err @ Error::Serialize(_) => err,
+            err => Error::AtPath { path: vec![segment], error: Box::new(err) },
+        }
+    }
+
+    /// Path from the deserialized value to where the error occurred, empty if
+    /// it occurred at the value itself.
+    pub fn path(&self) -> &[PathSegment] {
+        match *self {
+            Error::AtPath { ref path, .. } => path,
+            _ => &[],
+        }
+    }
+
+    /// The error without the path to where it occurred.
+    pub fn kind(&self) -> &Error {
+        match *self {
+            Error::AtPath { ref error, .. } => error,
+            ref err => err,
+        }
+    }
+}
+
 impl From<LuaError> for Error {
     fn from(err: LuaError) -> Error {
-        Error(err)
+        Error::Lua(err)
     }
 }
 
 impl From<Error> for LuaError {
     fn from(err: Error) -> LuaError {
-        err.0
+        match err {
+            Error::Lua(err) => err,
+            err => LuaError::ExternalError(Arc::new(err)),
+        }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt(fmt)
+        match *self {
+            Error::Serialize(ref message)
+            | Error::Custom(ref message)
+            | Error::NumberOverflow(ref message) => fmt.write_str(message),
+            Error::UnsupportedType(ty) => write!(fmt, "cannot deserialize Lua {}", ty),
+            Error::KeyMustBeString => fmt.write_str("expected a string key"),
+            Error::Lua(ref err) => err.fmt(fmt),
+            Error::AtPath { ref path, ref error } => {
+                for (i, segment) in path.iter().enumerate() {
+                    match *segment {
+                        PathSegment::Index(_) => (),
+                        _ if i == 0 => (),
+                        _ => fmt.write_str(".")?,
+                    }
+                    segment.fmt(fmt)?;
+                }
+                write!(fmt, ": {}", error)
+            },
+        }
     }
 }
 
 impl StdError for Error {
-    fn description(&self) -> &'static str {
-        "Failed to serialize to Lua value"
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Lua(ref err) => Some(err),
+            _ => None,
+        }
     }
 }
 
 impl serde::ser::Error for Error {
     fn custom<T: fmt::Display>(msg: T) -> Self {
-        Error(LuaError::ToLuaConversionError {
-            from: "serialize",
-            to: "value",
-            message: Some(format!("{}", msg))
-        })
+        Error::Serialize(msg.to_string())
     }
 }
 
 impl serde::de::Error for Error {
     fn custom<T: fmt::Display>(msg: T) -> Self {
-        Error(LuaError::FromLuaConversionError {
-            from: "value",
-            to: "deserialize",
-            message: Some(format!("{}", msg))
-        })
+        Error::Custom(msg.to_string())
     }
 }
@@ -36,6 +36,35 @@
 //!     });
 //! }
 //! ```
+//!
+//! `Path` and `PathBuf` are represented as Lua strings. serde only
+//! serializes paths which are valid UTF-8, so a non-UTF-8 path on Unix (or an
+//! ill-formed UTF-16 path on Windows) fails to serialize with an error instead
+//! of being mangled, and a Lua string which is not valid UTF-8 can't be
+//! deserialized into a `PathBuf`.
+//!
+//! `None` serializes to nil, which Lua tables can't hold. A struct field set
+//! to `None` is therefore absent from its table, as with
+//! `skip_serializing_if = "Option::is_none"`, and reads back as `None`. A
+//! `None` element of a sequence leaves a hole at its index, so the elements
+//! after it keep their position but `#` and `ipairs` may stop at the hole,
+//! and so does deserializing it by default. Either write the length along
+//! with `SerializerOptions::seq_length_field` and read it back with
+//! `DeserializerOptions::seq_length_field`, leave out `None` elements
+//! altogether with `SerializerOptions::skip_none_in_seq`, or fail on them
+//! with `SerializerOptions::reject_nil_in_seq`.
+//!
+//! Tables nested more than `DEFAULT_MAX_DEPTH` (128) deep fail to serialize
+//! and deserialize, to catch cycles and hostile input before they overflow
+//! the stack. Earlier versions had no limit when `max_depth` was `None`; data
+//! which is legitimately deeper needs `max_depth: Some(usize::MAX)`, or a
+//! higher bound, in `SerializerOptions` and `DeserializerOptions`.
+//!
+//! Errors of this crate, such as type mismatches or integer overflows, are
+//! returned as `rlua::Error::ExternalError` wrapping an `error::Error`, which
+//! tells their kind. Earlier versions returned them as
+//! `FromLuaConversionError` or `ToLuaConversionError` with a message only.
+//! Errors from Lua itself are still returned as is.
 
 extern crate rlua;
 #[macro_use]
@@ -43,22 +72,521 @@ extern crate serde;
 #[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
+#[cfg(feature = "erased-serde")]
+extern crate erased_serde;
+#[cfg(feature = "bincode")]
+extern crate bincode;
 
 pub mod error;
 pub mod ser;
 pub mod de;
+pub mod value;
+pub mod registry;
+mod variadic;
+
+
+use std::marker::PhantomData;
+
+use rlua::{Context, Value, MultiValue, Table, Function, Variadic, Error, RegistryKey};
 
 
-use rlua::{Context, Value, Error};
+/// Function mapping a table key to another key, used by the `key_transform`
+/// serializer and deserializer options.
+pub type KeyTransform = std::rc::Rc<dyn Fn(&str) -> String>;
 
+/// Field set to `true` in the metatable of tables serialized from sequences
+/// when the `mark_arrays` serializer option is set.
+pub const ARRAY_MARKER: &str = "__rlua_serde_array";
 
+/// Nesting of tables past which serialization and deserialization fail,
+/// unless overridden by their `max_depth` option, which can be set to
+/// `Some(usize::MAX)` for no limit.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Function called with every table the serializer creates once it is fully
+/// populated, used by the `table_hook` serializer option.
+pub type TableHook = std::rc::Rc<dyn for<'lua> Fn(&Table<'lua>, Context<'lua>)>;
+
+/// Predicate on the name and serialized value of a struct field, used by the
+/// `skip_field_if` serializer option.
+pub type FieldFilter = std::rc::Rc<dyn for<'lua> Fn(&str, &Value<'lua>) -> bool>;
+
+/// How an enum variant and its payload are represented in Lua, used by the
+/// `enum_repr` serializer and deserializer options.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EnumRepr {
+    /// `{ Variant = payload }`, with unit variants as `"Variant"`.
+    #[default]
+    External,
+    /// `{ [tag] = "Variant", fields... }`, with the fields of a struct
+    /// variant or of the struct or map held by a newtype variant. Tuple
+    /// variants and newtype variants holding anything else can't be
+    /// serialized this way. When deserializing, the payload still holds the
+    /// tag field, which is ignored by structs but not by maps.
+    Internal { tag: String },
+    /// `{ [tag] = "Variant", [content] = payload }`, without `content` for
+    /// unit variants.
+    Adjacent { tag: String, content: String },
+    /// `{ "Variant", payload }`, with unit variants as `{ "Variant" }`.
+    Positional,
+}
+
+
+/// Serializes a value to Lua.
+///
+/// The unit value `()` serializes to `Nil`, as do unit structs and `None`, so
+/// `()` round-trips through `from_value` from `Nil` but can't be told apart
+/// from them once serialized; see `value::is_unit_value`.
+///
+/// `t` is taken by value, but since `&T` is `Serialize` whenever `T` is,
+/// passing `&t` serializes it without moving it, as does `to_value_ref`.
+///
+/// Errors are `rlua::Error`s, so inside a function created with
+/// `Context::create_function` a failure can be raised as a Lua error with `?`.
 pub fn to_value<T: serde::Serialize>(lua: Context, t: T) -> Result<Value, Error> {
-    let serializer = ser::Serializer { lua };
+    let serializer = ser::Serializer::new(lua);
+    Ok(t.serialize(serializer)?)
+}
+
+
+/// Like `to_value`, but borrowing the value, which may also be unsized such
+/// as a `str` or a slice.
+pub fn to_value_ref<'lua, T: ?Sized + serde::Serialize>(
+    lua: Context<'lua>, t: &T
+) -> Result<Value<'lua>, Error> {
+    to_value(lua, t)
+}
+
+
+/// Serializes a value for returning it from a function created with
+/// `Context::create_function`, so that a serialization error is raised in
+/// Lua, where `pcall` sees its message.
+///
+/// ```
+/// # extern crate rlua;
+/// # extern crate rlua_serde;
+/// # rlua::Lua::new().context(|lua| {
+/// let config = vec!["a", "b"];
+/// let get_config = lua.create_function(move |lua, ()| {
+///     rlua_serde::to_value_or_raise(lua, &config)
+/// }).unwrap();
+/// # });
+/// ```
+pub fn to_value_or_raise<'lua, T: ?Sized + serde::Serialize>(
+    lua: Context<'lua>, t: &T
+) -> Result<Value<'lua>, Error> {
+    to_value(lua, t)
+}
+
+
+/// Like `to_value`, with `options` controlling how values are represented:
+/// how enums, maps, byte strings, floats and out-of-range integers are
+/// written, how struct keys are renamed and which fields are skipped, how
+/// sequences mark their length and holes, and the limits on string length
+/// and nesting depth. See `ser::SerializerOptions` for each option.
+pub fn to_value_with<T: serde::Serialize>(
+    lua: Context, t: T, options: ser::SerializerOptions
+) -> Result<Value, Error> {
+    let serializer = ser::Serializer::with_options(lua, options);
+    Ok(t.serialize(serializer)?)
+}
+
+
+/// Serializes only the fields of `current` which differ from `baseline`,
+/// e.g. to produce a config patch. Nested structs and maps are diffed
+/// recursively; see `value::diff_values` for the exact rules. Returns `Nil`
+/// if nothing changed.
+pub fn to_value_diff<'lua, T: serde::Serialize>(
+    lua: Context<'lua>, current: &T, baseline: &T
+) -> Result<Value<'lua>, Error> {
+    let current = to_value(lua, current)?;
+    let baseline = to_value(lua, baseline)?;
+    value::diff_values(lua, &current, &baseline)
+}
+
+
+/// Serializes a type-erased value, e.g. one held as `Box<dyn Serialize>`.
+#[cfg(feature = "erased-serde")]
+pub fn to_value_erased<'lua>(
+    lua: Context<'lua>, t: &dyn erased_serde::Serialize
+) -> Result<Value<'lua>, Error> {
+    to_value(lua, t)
+}
+
+
+/// Serializes a trait object of a type registered in `registry` into a
+/// `{ type = name, data = ... }` table. See `registry::TypeRegistry`.
+pub fn to_value_dyn<'lua, T: ?Sized + 'static>(
+    lua: Context<'lua>, registry: &registry::TypeRegistry<T>, value: &dyn std::any::Any
+) -> Result<Value<'lua>, Error> {
+    registry.to_value(lua, value)
+}
+
+
+/// Serializes a tuple into multiple values, one per element, e.g. to return
+/// them from a Rust function called from Lua. The unit value `()` serializes
+/// to no values; anything other than a tuple or tuple struct is an error.
+pub fn to_variadic<T: serde::Serialize>(lua: Context, t: T) -> Result<Variadic<Value>, Error> {
+    to_variadic_with(lua, t, ser::SerializerOptions::default())
+}
+
+
+/// Like `to_variadic`, with `options` applying to each element as with
+/// `to_value_with`. The elements are separate values rather than a table, so
+/// options about the enclosing sequence, such as `seq_length_field` or
+/// `skip_none_in_seq`, have no effect on them.
+pub fn to_variadic_with<T: serde::Serialize>(
+    lua: Context, t: T, options: ser::SerializerOptions
+) -> Result<Variadic<Value>, Error> {
+    let serializer = variadic::VariadicSerializer::new(lua, options);
     Ok(t.serialize(serializer)?)
 }
 
 
+/// Serializes a value with bincode into an opaque Lua string, for passing
+/// data through Lua compactly rather than as a table.
+#[cfg(feature = "bincode")]
+pub fn to_binary_value<T: serde::Serialize>(lua: Context, t: T) -> Result<Value, Error> {
+    let bytes = bincode::serialize(&t).map_err(Error::external)?;
+    Ok(Value::String(lua.create_string(&bytes)?))
+}
+
+
+/// Deserializes a value from a Lua string produced by `to_binary_value`.
+#[cfg(feature = "bincode")]
+pub fn from_binary_value<T: serde::de::DeserializeOwned>(value: Value) -> Result<T, Error> {
+    match value {
+        Value::String(s) => bincode::deserialize(s.as_bytes()).map_err(Error::external),
+        _ => Err(Error::FromLuaConversionError {
+            from: "value",
+            to: "binary value",
+            message: Some("expected a string".to_owned()),
+        }),
+    }
+}
+
+
+/// Serializes a sequence, map, struct or enum variant into an existing table.
+///
+/// The fields or elements are written straight into `table`, such as the
+/// globals table, rather than into a new table which would then have to be
+/// copied over. Entries of `table` with other keys are left as is.
+pub fn serialize_into<'lua, T: serde::Serialize>(
+    lua: Context<'lua>, table: Table<'lua>, t: T
+) -> Result<(), Error> {
+    serialize_into_with(lua, table, t, ser::SerializerOptions::default())
+}
+
+
+/// Like `serialize_into`, with `options` as for `to_value_with`. They apply
+/// to `table` itself too, e.g. `table_hook` is called with it once it has
+/// been filled. Fails if the value doesn't serialize to a table, e.g. a
+/// number, or a unit variant unless `enum_repr` writes those as tables.
+pub fn serialize_into_with<'lua, T: serde::Serialize>(
+    lua: Context<'lua>, table: Table<'lua>, t: T, options: ser::SerializerOptions
+) -> Result<(), Error> {
+    let serializer = ser::Serializer::with_options(lua, options).target(table);
+    match t.serialize(serializer)? {
+        Value::Table(_) => Ok(()),
+        _ => Err(Error::ToLuaConversionError {
+            from: "serialize",
+            to: "table",
+            message: Some("value does not serialize to a table".to_owned()),
+        }),
+    }
+}
+
+
+/// Serializes the elements of a sequence one at a time, calling `sink` with
+/// each of them, so that Lua code can consume a large sequence without it
+/// being built as a whole table. Stops at the first error, including errors
+/// raised by `sink`.
+pub fn serialize_stream<'lua, I>(lua: Context<'lua>, iter: I, sink: Function<'lua>) -> Result<(), Error>
+    where I: IntoIterator, I::Item: serde::Serialize
+{
+    serialize_stream_with(lua, iter, sink, ser::SerializerOptions::default())
+}
+
+
+pub fn serialize_stream_with<'lua, I>(
+    lua: Context<'lua>, iter: I, sink: Function<'lua>, options: ser::SerializerOptions
+) -> Result<(), Error>
+    where I: IntoIterator, I::Item: serde::Serialize
+{
+    let options = std::rc::Rc::new(options);
+    for item in iter {
+        let serializer = ser::ConfiguredSerializer::from_parts(lua, &options);
+        sink.call::<_, ()>(serde::Serialize::serialize(&item, serializer)?)?;
+    }
+    Ok(())
+}
+
+
+/// Serializes a struct or map and recursively merges it into an existing
+/// table, e.g. to apply overrides onto a table of defaults. Nested tables
+/// are merged instead of replaced; see `value::merge_tables` for the rules.
+pub fn merge_into<'lua, T: serde::Serialize>(
+    lua: Context<'lua>, table: &Table<'lua>, t: T
+) -> Result<(), Error> {
+    merge_into_with(lua, table, t, value::MergeOptions::default())
+}
+
+
+/// Like `merge_into`, but with configurable conflict handling.
+pub fn merge_into_with<'lua, T: serde::Serialize>(
+    lua: Context<'lua>, table: &Table<'lua>, t: T, options: value::MergeOptions
+) -> Result<(), Error> {
+    match to_value(lua, t)? {
+        Value::Table(overlay) => value::merge_tables_with(table, &overlay, options),
+        _ => Err(Error::ToLuaConversionError {
+            from: "serialize",
+            to: "table",
+            message: Some("value does not serialize to a table".to_owned()),
+        }),
+    }
+}
+
+
+/// Creates an empty table whose array part has room for at least `len`
+/// elements, so that filling indices `1..=len` doesn't rehash.
+///
+/// rlua has no way to pass a size hint when creating a table, so the array
+/// part is grown by filling it once and then clearing it.
+pub fn table_with_capacity(lua: Context, len: usize) -> Result<Table, Error> {
+    let table = lua.create_table()?;
+    for idx in 1..=len {
+        table.raw_set(idx, true)?;
+    }
+    for idx in 1..=len {
+        table.raw_set(idx, Value::Nil)?;
+    }
+    Ok(table)
+}
+
+
 pub fn from_value<'de, T: serde::Deserialize<'de>>(value: Value<'de>) -> Result<T, Error> {
-    let deserializer = de::Deserializer { value };
+    let deserializer = de::Deserializer::new(value);
     Ok(T::deserialize(deserializer)?)
 }
+
+
+/// Like `from_value`, but borrowing the value, e.g. to try deserializing it
+/// into several candidate types in turn. Only the reference to the Lua value
+/// is cloned, not the value itself.
+pub fn from_value_ref<'de, T: serde::Deserialize<'de>>(value: &Value<'de>) -> Result<T, Error> {
+    from_value(value.clone())
+}
+
+
+pub fn from_value_with<'de, T: serde::Deserialize<'de>>(
+    value: Value<'de>, options: de::DeserializerOptions
+) -> Result<T, Error> {
+    let deserializer = de::Deserializer::with_options(value, options);
+    Ok(T::deserialize(deserializer)?)
+}
+
+
+/// Like `from_value_with`, but also returns the conversions between types
+/// which were applied, such as strings parsed into numbers with
+/// `coerce_numbers_from_str` or integers read into floats, e.g. to audit
+/// surprising conversions in hand-written data.
+pub fn from_value_with_report<'de, T: serde::Deserialize<'de>>(
+    value: Value<'de>, options: de::DeserializerOptions
+) -> Result<(T, Vec<de::Coercion>), Error> {
+    Ok(de::deserialize_with_report(value, options)?)
+}
+
+
+/// Conveniences on `Context` combining Lua evaluation with deserialization.
+pub trait ContextExt<'lua> {
+    /// Loads and evaluates a Lua chunk, such as a config script, and
+    /// deserializes its result. Lua errors and deserialization errors are
+    /// both returned as `rlua::Error`s.
+    fn eval_into<T: serde::Deserialize<'lua>>(&self, source: &str) -> Result<T, Error>;
+}
+
+impl<'lua> ContextExt<'lua> for Context<'lua> {
+    fn eval_into<T: serde::Deserialize<'lua>>(&self, source: &str) -> Result<T, Error> {
+        from_value(self.load(source).eval()?)
+    }
+}
+
+
+/// Deserializes a value and then runs `validate` on it, e.g. to check ranges
+/// or normalize fields. Errors of `validate` are reported like
+/// deserialization errors, with their message.
+pub fn from_value_then<'de, T, E, F>(value: Value<'de>, validate: F) -> Result<T, Error>
+    where T: serde::Deserialize<'de>,
+          E: std::fmt::Display,
+          F: FnOnce(&mut T) -> std::result::Result<(), E>,
+{
+    let mut t = from_value(value)?;
+    match validate(&mut t) {
+        Ok(()) => Ok(t),
+        Err(err) => Err(<error::Error as serde::de::Error>::custom(err).into()),
+    }
+}
+
+
+/// Deserializes a table written by `to_value_dyn` into a boxed trait object.
+pub fn from_value_dyn<T: ?Sized + 'static>(
+    registry: &registry::TypeRegistry<T>, value: Value
+) -> Result<Box<T>, Error> {
+    registry.from_value(value)
+}
+
+
+/// Handle to a value stored in the Lua registry by `to_registry`, which
+/// remembers the type it was serialized from so that `from_registry` reads
+/// it back as the same type.
+pub struct RegistryValue<T> {
+    key: RegistryKey,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> RegistryValue<T> {
+    /// The untyped key of the value, e.g. to pass it to
+    /// `Context::registry_value`.
+    pub fn key(&self) -> &RegistryKey {
+        &self.key
+    }
+
+    /// Gives up the type of the value, e.g. to remove it from the registry
+    /// with `Context::remove_registry_value`.
+    pub fn into_key(self) -> RegistryKey {
+        self.key
+    }
+}
+
+impl<T> std::fmt::Debug for RegistryValue<T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_tuple("RegistryValue").field(&self.key).finish()
+    }
+}
+
+/// Serializes a value and stores it in the Lua registry, keeping it alive
+/// independently of any Lua variable until the handle is dropped and
+/// `Context::expire_registry_values` is called.
+pub fn to_registry<T: serde::Serialize>(lua: Context, t: &T) -> Result<RegistryValue<T>, Error> {
+    let value = to_value(lua, t)?;
+    Ok(RegistryValue {
+        key: lua.create_registry_value(value)?,
+        marker: PhantomData,
+    })
+}
+
+/// Deserializes a value stored by `to_registry`. Fails with
+/// `Error::MismatchedRegistryKey` if it was stored by another Lua state.
+pub fn from_registry<'lua, T: serde::Deserialize<'lua>>(
+    lua: Context<'lua>, value: &RegistryValue<T>
+) -> Result<T, Error> {
+    from_value(lua.registry_value::<Value>(&value.key)?)
+}
+
+
+/// Deserializes a value with a `DeserializeSeed`, for deserialization which
+/// needs external state such as an interner or an arena.
+pub fn from_value_seed<'de, S: serde::de::DeserializeSeed<'de>>(
+    value: Value<'de>, seed: S
+) -> Result<S::Value, Error> {
+    let deserializer = de::Deserializer::new(value);
+    Ok(seed.deserialize(deserializer)?)
+}
+
+
+/// Deserializes a tuple or sequence from multiple values, e.g. the results of
+/// calling a Lua function, without packing them into a table first.
+pub fn from_multi_value<'de, T: serde::Deserialize<'de>>(
+    values: MultiValue<'de>
+) -> Result<T, Error> {
+    let deserializer = de::MultiValueDeserializer::new(values);
+    Ok(T::deserialize(deserializer)?)
+}
+
+
+/// Deserializes an enum from a table without a tag, picking the variant from
+/// the fields the table has. `variants` lists each variant with the fields
+/// which identify it, and is tried in order: the first variant whose fields
+/// are all present wins, even if a later one matches more fields. So list
+/// variants whose fields overlap most specific first, and a variant with no
+/// fields last as a fallback. The whole table is then deserialized as the
+/// payload of that variant.
+///
+/// Fails if `value` is not a table, or if no variant matches, with "no
+/// variant matches the fields of the table".
+pub fn from_value_by_fields<'de, T: serde::Deserialize<'de>>(
+    value: Value<'de>, variants: &[(&'static str, &[&str])]
+) -> Result<T, Error> {
+    let deserializer = de::FieldTaggedDeserializer::new(value, variants)?;
+    Ok(T::deserialize(deserializer)?)
+}
+
+
+/// Adapts a flat tagged table like `{ type = "Circle", radius = 5 }` into the
+/// externally tagged form `{ Circle = { radius = 5 } }` expected when
+/// deserializing enums, with `tag` naming the field holding the variant.
+///
+/// A table holding nothing but the tag becomes the bare variant name, so it
+/// deserializes as a unit variant. Values other than tables are returned
+/// unchanged.
+pub fn externally_tagged<'lua>(
+    lua: Context<'lua>, value: Value<'lua>, tag: &str
+) -> Result<Value<'lua>, Error> {
+    let table = match value {
+        Value::Table(table) => table,
+        value => return Ok(value),
+    };
+    let variant = match table.raw_get::<_, Value>(tag)? {
+        Value::String(variant) => variant,
+        Value::Nil => return Err(Error::FromLuaConversionError {
+            from: "table",
+            to: "enum",
+            message: Some(format!("missing tag field `{}`", tag)),
+        }),
+        _ => return Err(Error::FromLuaConversionError {
+            from: "table",
+            to: "enum",
+            message: Some(format!("tag field `{}` is not a string", tag)),
+        }),
+    };
+
+    let fields = lua.create_table()?;
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let is_tag = match key {
+            Value::String(ref key) => key.as_bytes() == tag.as_bytes(),
+            _ => false,
+        };
+        if !is_tag {
+            fields.raw_set(key, value)?;
+        }
+    }
+    if fields.clone().pairs::<Value, Value>().next().is_none() {
+        return Ok(Value::String(variant));
+    }
+
+    let tagged = lua.create_table()?;
+    tagged.raw_set(variant, fields)?;
+    Ok(Value::Table(tagged))
+}
+
+
+/// Deserializes a struct on a best-effort basis, returning the errors of the
+/// fields which failed to deserialize instead of failing as a whole, e.g. to
+/// load what is usable of a service's configuration.
+///
+/// The declared fields of the struct are read by name, in order; other keys
+/// of the table are ignored. A field which fails to deserialize is left out,
+/// so it takes its value from `T::default()` if `T` is marked with
+/// `#[serde(default)]`, and its error is collected. Otherwise, or if the
+/// value is not a table or the struct fails as a whole, the result is
+/// `T::default()`. Fields are only checked at the top level, so an error deep
+/// inside a nested struct resets that whole field.
+pub fn from_value_partial<'lua, T>(value: Value<'lua>) -> (T, Vec<Error>)
+    where T: Default + serde::Deserialize<'lua>
+{
+    let (t, errors) = de::deserialize_partial(value);
+    (t, errors.into_iter().map(Error::from).collect())
+}
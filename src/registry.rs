@@ -0,0 +1,224 @@
+//! Serialization of trait objects through a registry of concrete types.
+//!
+//! serde can't serialize a `Box<dyn Trait>` since it doesn't know the
+//! concrete type behind it. A `TypeRegistry` maps concrete types to names so
+//! that such values are written as `{ type = name, data = ... }` tables and
+//! can be read back into the right type.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+use serde;
+
+use rlua::{Context, Value, Table, Error};
+
+use {to_value, from_value};
+
+
+/// Field holding the registered name of the type.
+const TYPE_FIELD: &str = "type";
+/// Field holding the serialized value.
+const DATA_FIELD: &str = "data";
+
+type SerializeFn = for<'lua> fn(&dyn Any, Context<'lua>) -> Result<Value<'lua>, Error>;
+type DeserializeFn<T> = Box<dyn for<'lua> Fn(Value<'lua>) -> Result<Box<T>, Error>>;
+
+struct Entry<T: ?Sized> {
+    name: &'static str,
+    serialize: SerializeFn,
+    deserialize: DeserializeFn<T>,
+}
+
+/// Concrete types which can be serialized from and deserialized into a
+/// `Box<T>`, usually with `T` a trait object type such as `dyn Plugin`.
+///
+/// Values are serialized from `&dyn Any`, so the trait needs a way to get
+/// one, such as an `as_any(&self) -> &dyn Any` method.
+pub struct TypeRegistry<T: ?Sized> {
+    by_type: HashMap<TypeId, Entry<T>>,
+    by_name: HashMap<&'static str, TypeId>,
+}
+
+impl<T: ?Sized> Default for TypeRegistry<T> {
+    fn default() -> Self {
+        TypeRegistry {
+            by_type: HashMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for TypeRegistry<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = self.by_name.keys().collect();
+        fmt.debug_struct("TypeRegistry").field("types", &names).finish()
+    }
+}
+
+fn serialize_any<'lua, U: serde::Serialize + Any>(
+    value: &dyn Any, lua: Context<'lua>
+) -> Result<Value<'lua>, Error> {
+    match value.downcast_ref::<U>() {
+        Some(value) => to_value(lua, value),
+        None => Err(Error::ToLuaConversionError {
+            from: "trait object",
+            to: "value",
+            message: Some("type doesn't match its registration".to_owned()),
+        }),
+    }
+}
+
+impl<T: ?Sized + 'static> TypeRegistry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `U` under `name`, with `into_box` converting it into a
+    /// `Box<T>`, usually `|u| Box::new(u)`. Registering a type or a name
+    /// again replaces the previous registration.
+    pub fn register<U>(&mut self, name: &'static str, into_box: fn(U) -> Box<T>)
+        where U: serde::Serialize + serde::de::DeserializeOwned + Any,
+    {
+        let entry = Entry {
+            name,
+            serialize: serialize_any::<U>,
+            deserialize: Box::new(move |value| Ok(into_box(from_value::<U>(value)?))),
+        };
+        let type_id = TypeId::of::<U>();
+        if let Some(previous) = self.by_type.remove(&type_id) {
+            self.by_name.remove(previous.name);
+        }
+        if let Some(previous) = self.by_name.insert(name, type_id) {
+            self.by_type.remove(&previous);
+        }
+        self.by_type.insert(type_id, entry);
+    }
+
+    /// Serializes a value of a registered type into a table holding its
+    /// registered name in `type` and the value itself in `data`.
+    pub fn to_value<'lua>(&self, lua: Context<'lua>, value: &dyn Any) -> Result<Value<'lua>, Error> {
+        let entry = match self.by_type.get(&value.type_id()) {
+            Some(entry) => entry,
+            None => return Err(Error::ToLuaConversionError {
+                from: "trait object",
+                to: "value",
+                message: Some("type is not registered".to_owned()),
+            }),
+        };
+        let table = lua.create_table()?;
+        table.set(TYPE_FIELD, entry.name)?;
+        table.set(DATA_FIELD, (entry.serialize)(value, lua)?)?;
+        Ok(Value::Table(table))
+    }
+
+    /// Deserializes a table written by `to_value` into the type registered
+    /// under the name in its `type` field.
+    pub fn from_value<'lua>(&self, value: Value<'lua>) -> Result<Box<T>, Error> {
+        let table = match value {
+            Value::Table(table) => table,
+            _ => return Err(from_lua_error("expected a table")),
+        };
+        let name = type_name(&table)?;
+        match self.by_name.get(name.as_str()) {
+            Some(type_id) => (self.by_type[type_id].deserialize)(table.get(DATA_FIELD)?),
+            None => Err(from_lua_error(&format!("type `{}` is not registered", name))),
+        }
+    }
+}
+
+fn type_name(table: &Table) -> Result<String, Error> {
+    match table.get(TYPE_FIELD)? {
+        Value::String(name) => Ok(name.to_str()?.to_owned()),
+        _ => Err(from_lua_error("missing type name")),
+    }
+}
+
+fn from_lua_error(message: &str) -> Error {
+    Error::FromLuaConversionError {
+        from: "value",
+        to: "trait object",
+        message: Some(message.to_owned()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use rlua::Lua;
+
+    use {to_value_dyn, from_value_dyn};
+    use super::*;
+
+    trait Shape {
+        fn area(&self) -> f64;
+        fn as_any(&self) -> &dyn Any;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Circle {
+        radius: f64,
+    }
+
+    impl Shape for Circle {
+        fn area(&self) -> f64 {
+            3.0 * self.radius * self.radius
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Square {
+        side: f64,
+    }
+
+    impl Shape for Square {
+        fn area(&self) -> f64 {
+            self.side * self.side
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = TypeRegistry::<dyn Shape>::new();
+        registry.register("circle", |c: Circle| Box::new(c));
+        registry.register("square", |s: Square| Box::new(s));
+
+        let shapes: Vec<Box<dyn Shape>> = vec![
+            Box::new(Circle { radius: 2.0 }),
+            Box::new(Square { side: 3.0 }),
+        ];
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let values: Vec<Value> = shapes.iter()
+                .map(|shape| registry.to_value(lua, shape.as_any()).unwrap())
+                .collect();
+            lua.globals().set("circle", values[0].clone()).unwrap();
+            lua.globals().set("square", values[1].clone()).unwrap();
+            lua.load(r#"
+                assert(circle.type == "circle" and circle.data.radius == 2)
+                assert(square.type == "square" and square.data.side == 3)
+            "#).exec().unwrap();
+
+            let areas: Vec<f64> = values.into_iter()
+                .map(|value| from_value_dyn(&registry, value).unwrap().area())
+                .collect();
+            assert_eq!(areas, vec![12.0, 9.0]);
+
+            assert!(to_value_dyn(lua, &registry, &5).is_err());
+            let value = lua.load(r#"return { type = "triangle", data = {} }"#).eval().unwrap();
+            let err = registry.from_value(value).err().unwrap();
+            assert!(err.to_string().contains("type `triangle` is not registered"), "{}", err);
+        });
+    }
+}
@@ -1,15 +1,514 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::convert::TryFrom;
+
 use serde;
 
-use rlua::{Context, Value, Table, String as LuaString};
+use rlua;
+use rlua::{Context, Value, Table};
 
-use to_value;
+use {EnumRepr, KeyTransform, TableHook, FieldFilter, ARRAY_MARKER, DEFAULT_MAX_DEPTH};
 use error::{Error, Result};
+use value::integral_float;
+
+
+/// Options controlling how values are serialized to Lua.
+#[derive(Clone, Default)]
+pub struct SerializerOptions {
+    /// Transforms every struct field name and string map key before it is
+    /// written to a table, e.g. to convert `snake_case` names to `camelCase`.
+    pub key_transform: Option<KeyTransform>,
+    /// Serialize struct variants without fields, such as `Variant {}`, as
+    /// the variant name like unit variants, instead of `{ Variant = {} }`.
+    pub empty_struct_variant_as_unit: bool,
+    /// Serialize maps as a sequence of `{ key, value }` pairs in a canonical
+    /// order instead of as a table keyed by the map keys.
+    ///
+    /// Numeric keys come first in ascending order, followed by string keys in
+    /// byte order and then any other keys in the map's own order. The output
+    /// only depends on the map's contents, which makes it suitable for
+    /// fingerprinting. Read it back with `DeserializerOptions::map_from_pairs`.
+    pub map_as_sorted_pairs: bool,
+    /// Serialize structs as a sequence of `{ key, value }` pairs in field
+    /// declaration order, so that the order survives for order-sensitive
+    /// consumers. Read it back with `DeserializerOptions::map_from_pairs`.
+    pub struct_as_ordered_pairs: bool,
+    /// Called with every table created for a sequence, map, struct, enum
+    /// variant or byte string after it is populated, including the outer
+    /// `{ Variant = ... }` wrapper of enum variants. Useful to set a
+    /// metatable on every table, for example.
+    pub table_hook: Option<TableHook>,
+    /// Serialize `f32` values as strings of their shortest `f32`
+    /// representation, e.g. `"0.1"` for `0.1f32`.
+    ///
+    /// By default an `f32` is widened to a Lua float, which is exact and
+    /// reads back into the same `f32`, but shows up in Lua as its full `f64`
+    /// value, e.g. `0.10000000149011612`, and any arithmetic done in Lua
+    /// happens in double precision. With this option Lua sees the intended
+    /// decimal value; read it back with
+    /// `DeserializerOptions::coerce_numbers_from_str`.
+    pub f32_as_string: bool,
+    /// Set a metatable with `ARRAY_MARKER` on every table serialized from a
+    /// sequence, tuple or, with `bytes_as_table`, byte string, so that it is known to be an array
+    /// even when empty. `Deserializer::deserialize_any` then reads it as a
+    /// sequence rather than a map. The metatable has no metamethods, so
+    /// `ipairs` and `#` are unaffected.
+    pub mark_arrays: bool,
+    /// Write integer map keys as strings, e.g. `{ ["1"] = ... }` instead of
+    /// `{ [1] = ... }`, for consumers which only accept string keys.
+    pub stringify_keys: bool,
+    /// Write string map keys made of decimal digits as integers, e.g. so that
+    /// a `HashMap<String, T>` with keys `"1"`, `"2"` and `"3"` becomes a
+    /// sequence. Keys with leading zeros or out of `i64` range stay strings.
+    pub numeric_string_keys_as_int: bool,
+    /// Write the length of sequences and tuples in an `n` field, as
+    /// `table.pack` does, so that `None` elements don't cut them short when
+    /// read back with `DeserializerOptions::seq_length_field`.
+    pub seq_length_field: bool,
+    /// Leave out `None` elements of sequences and tuples instead of writing
+    /// them as holes, so that e.g. `vec![Some(1), None, Some(3)]` becomes
+    /// `{ 1, 3 }`. This loses the positions of the remaining elements. Other
+    /// elements serializing to nil, such as `()`, are left out too.
+    pub skip_none_in_seq: bool,
+    /// Fail on elements of sequences and tuples serializing to nil, such as
+    /// `None`, which would leave a hole where `#` and `ipairs` may stop,
+    /// instead of writing them as holes. Ignored with `skip_none_in_seq`,
+    /// and with `seq_length_field` which keeps the length despite holes.
+    pub reject_nil_in_seq: bool,
+    /// How to serialize NaN and infinite floats, which Lua stores as is but
+    /// which break comparisons in Lua code.
+    pub non_finite_floats: NonFinitePolicy,
+    /// How to serialize a `u64` above `i64::MAX`, which doesn't fit in a Lua
+    /// integer.
+    pub u64_overflow: U64OverflowPolicy,
+    /// How to serialize `i128` and `u128` values, which Lua integers can't
+    /// hold in general.
+    pub int128: Int128Encoding,
+    /// How enum variants are represented.
+    pub enum_repr: EnumRepr,
+    /// Serialize byte strings as sequences of integers, instead of Lua
+    /// strings which hold arbitrary bytes and take far less memory.
+    pub bytes_as_table: bool,
+    /// Serialize into the smallest tables, which only the same Rust types
+    /// can read back, with `DeserializerOptions::compact`.
+    ///
+    /// Structs become sequences of their field values in declaration order,
+    /// with holes for fields serializing to nil. Enum variants become their
+    /// zero-based index, or `{ index, payload }` when they have a payload.
+    /// This takes precedence over `enum_repr`, `struct_as_ordered_pairs` and
+    /// `key_transform` for struct fields. Fields skipped with
+    /// `skip_serializing_if` keep their position as a hole, but fields
+    /// skipped unconditionally must be skipped when deserializing too.
+    pub compact: bool,
+    /// Fail on strings and byte strings longer than this many bytes instead
+    /// of creating them in Lua, e.g. to protect a memory-constrained Lua
+    /// state from untrusted data.
+    pub max_string_bytes: Option<usize>,
+    /// Serialize unit variants as their zero-based index in declaration
+    /// order instead of their name, e.g. for Lua code expecting the values
+    /// of a C-like enum. Read it back with `DeserializerOptions::variant_index`.
+    ///
+    /// serde only exposes the index, so this matches the discriminants of
+    /// a `#[repr(i32)]` enum only if they count up from 0 without gaps.
+    pub variant_index: bool,
+    /// Leave out the fields of structs and struct variants for which this
+    /// returns true, given the field name and its serialized value, like
+    /// `skip_serializing_if` does for structs whose attributes can't be
+    /// changed. `value::is_empty_value` drops nil, empty strings and empty
+    /// tables, for example. Map entries are always written, as their keys
+    /// are data rather than field names.
+    pub skip_field_if: Option<FieldFilter>,
+    /// Maximum nesting of tables, to fail cleanly on values which nest
+    /// without end, such as a cycle of `Rc`s, instead of overflowing the
+    /// stack. `DEFAULT_MAX_DEPTH` if `None`; use `Some(usize::MAX)` to lift
+    /// the limit for values known to be deep.
+    pub max_depth: Option<usize>,
+}
+
+
+/// How to serialize a `u64` which is out of range of Lua integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum U64OverflowPolicy {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Write it as a Lua float, rounding to the nearest representable value.
+    Float,
+    /// Write it as the integer with the same bits, as with an `as` cast, so
+    /// that e.g. `u64::MAX` becomes `-1`. It reads back into the same `u64`
+    /// with `OverflowPolicy::Wrap`.
+    Wrap,
+}
+
+
+/// How to serialize an `i128` or `u128`, whatever its value. Both encodings
+/// are read back into `i128` and `u128`, as are plain Lua integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Int128Encoding {
+    /// A string of the value in decimal, e.g. `"-170141183460469231731687303715884105728"`.
+    #[default]
+    String,
+    /// A `{ high, low }` sequence of the upper and lower 64 bits of the
+    /// value, each as the Lua integer with the same bits. `high` is negative
+    /// for negative `i128`s, and for `u128`s from 2^127.
+    Words,
+}
+
+
+/// How to serialize a float which is NaN or infinite.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonFinitePolicy {
+    /// Write it as a Lua float like any other.
+    #[default]
+    Keep,
+    /// Fail with an error.
+    Error,
+    /// Write the given value instead.
+    Replace(f64),
+}
+
+impl SerializerOptions {
+    fn skip_field(&self, key: &str, value: &Value) -> bool {
+        match self.skip_field_if {
+            Some(ref skip) => skip(key, value),
+            None => false,
+        }
+    }
+
+    fn check_string_len(&self, len: usize) -> Result<()> {
+        match self.max_string_bytes {
+            Some(max) if len > max => Err(serde::ser::Error::custom(format_args!(
+                "string of {} bytes exceeds max_string_bytes of {}", len, max,
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn transform_key<'a>(&self, key: &'a str) -> ::std::borrow::Cow<'a, str> {
+        match self.key_transform {
+            Some(ref transform) => transform(key).into(),
+            None => key.into(),
+        }
+    }
+
+    fn finish_table<'lua>(&self, lua: Context<'lua>, table: Table<'lua>) -> Value<'lua> {
+        if let Some(ref hook) = self.table_hook {
+            hook(&table, lua);
+        }
+        Value::Table(table)
+    }
+
+    fn finish_array<'lua>(&self, lua: Context<'lua>, table: Table<'lua>) -> Result<Value<'lua>> {
+        if self.mark_arrays {
+            table.set_metatable(Some(array_metatable(lua)?));
+        }
+        Ok(self.finish_table(lua, table))
+    }
+
+    /// Whether to store the element of a sequence at `idx`, i.e. unless it is
+    /// nil and skipped with `skip_none_in_seq`. Fails on a nil element with
+    /// `reject_nil_in_seq`.
+    fn keep_seq_element(&self, idx: u64, value: &Value) -> Result<bool> {
+        if let Value::Nil = *value {
+            if self.skip_none_in_seq {
+                return Ok(false);
+            }
+            if self.reject_nil_in_seq && !self.seq_length_field {
+                return Err(serde::ser::Error::custom(format_args!(
+                    "sequence element {} serialized to nil, which would leave a hole", idx,
+                )));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Builds the value of an enum variant from its payload, `None` for unit
+    /// variants, according to `enum_repr`, or `compact`. Tables are written
+    /// into `outer` if given. An internally tagged payload table is tagged in
+    /// place only if `owned`, i.e. created for this variant, and copied
+    /// otherwise, as a newtype payload may be a table the caller still uses.
+    fn variant_value<'lua>(
+        &self, lua: Context<'lua>, outer: Option<Table<'lua>>,
+        index: u32, variant: &'static str, payload: Option<Value<'lua>>, owned: bool,
+    ) -> Result<Value<'lua>> {
+        if self.compact {
+            let payload = match payload {
+                Some(payload) => payload,
+                None => return Ok(Value::Integer(index.into())),
+            };
+            let table = match outer {
+                Some(table) => table,
+                None => lua.create_table()?,
+            };
+            table.raw_set(1, index)?;
+            table.raw_set(2, payload)?;
+            return Ok(self.finish_table(lua, table));
+        }
+        if self.variant_index && payload.is_none() {
+            return Ok(Value::Integer(index.into()));
+        }
+        let table = match (&self.enum_repr, outer) {
+            (EnumRepr::External, _) if payload.is_none() => {
+                return Ok(Value::String(lua.create_string(variant)?));
+            },
+            (EnumRepr::Internal { tag }, None) => {
+                match payload {
+                    Some(Value::Table(payload)) if payload.raw_len() == 0 => {
+                        check_tag_collision(&payload, tag, variant)?;
+                        let table = if owned {
+                            payload
+                        } else {
+                            let table = lua.create_table()?;
+                            for pair in payload.clone().pairs::<Value, Value>() {
+                                let (key, value) = pair?;
+                                table.raw_set(key, value)?;
+                            }
+                            table.set_metatable(payload.get_metatable());
+                            table
+                        };
+                        // The payload table already went through the hook, so
+                        // bypass any `__newindex` it installed.
+                        table.raw_set(tag.as_str(), variant)?;
+                        return Ok(Value::Table(table));
+                    },
+                    Some(_) => return Err(not_internally_taggable(variant)),
+                    None => lua.create_table()?,
+                }
+            },
+            (_, Some(table)) => table,
+            (_, None) => lua.create_table()?,
+        };
+        match self.enum_repr {
+            EnumRepr::External => table.set(variant, payload)?,
+            EnumRepr::Internal { ref tag } => {
+                match payload {
+                    Some(Value::Table(payload)) if payload.raw_len() == 0 => {
+                        check_tag_collision(&payload, tag, variant)?;
+                        for pair in payload.pairs::<Value, Value>() {
+                            let (key, value) = pair?;
+                            table.set(key, value)?;
+                        }
+                    },
+                    Some(_) => return Err(not_internally_taggable(variant)),
+                    None => (),
+                }
+                table.set(tag.as_str(), variant)?;
+            },
+            EnumRepr::Adjacent { ref tag, ref content } => {
+                table.set(tag.as_str(), variant)?;
+                // Unit variants have no content key at all.
+                if let Some(payload) = payload {
+                    table.set(content.as_str(), payload)?;
+                }
+            },
+            EnumRepr::Positional => {
+                table.raw_set(1, variant)?;
+                table.raw_set(2, payload)?;
+            },
+        }
+        Ok(self.finish_table(lua, table))
+    }
+}
+
+fn not_internally_taggable(variant: &str) -> Error {
+    serde::ser::Error::custom(format_args!(
+        "can't tag variant `{}` internally, as its payload is not a struct or map", variant,
+    ))
+}
+
+/// Fails if the payload of an internally tagged variant has a field named
+/// like the tag, which the tag would overwrite.
+fn check_tag_collision(payload: &Table, tag: &str, variant: &str) -> Result<()> {
+    match payload.raw_get::<_, Value>(tag)? {
+        Value::Nil => Ok(()),
+        _ => Err(serde::ser::Error::custom(format_args!(
+            "can't tag variant `{}` internally, as its payload has a field named like the tag `{}`",
+            variant, tag,
+        ))),
+    }
+}
+
+/// Integer keys whose strings are cached by `small_key_strings`.
+const SMALL_KEYS: ::std::ops::RangeInclusive<i64> = 0..=255;
+
+/// Returns a sequence of the strings of `SMALL_KEYS`, shared through the
+/// registry, so that stringifying small keys doesn't format and create them
+/// over and over.
+fn small_key_strings(lua: Context) -> Result<Table> {
+    const KEY: &str = "rlua_serde.small_key_strings";
+    if let Some(strings) = lua.named_registry_value::<_, Option<Table>>(KEY)? {
+        return Ok(strings);
+    }
+    let strings = lua.create_sequence_from(SMALL_KEYS.map(|n| n.to_string()))?;
+    lua.set_named_registry_value(KEY, strings.clone())?;
+    Ok(strings)
+}
+
+/// Parses a key made only of decimal digits, without leading zeros, into an
+/// integer, for the `numeric_string_keys_as_int` option.
+fn numeric_key(key: &[u8]) -> Option<i64> {
+    match key {
+        [] | [b'0', _, ..] => None,
+        _ if key.iter().all(u8::is_ascii_digit) => {
+            ::std::str::from_utf8(key).ok()?.parse().ok()
+        },
+        _ => None,
+    }
+}
+
+/// Returns the metatable marking arrays, shared through the registry.
+fn array_metatable(lua: Context) -> Result<Table> {
+    const KEY: &str = "rlua_serde.array_metatable";
+    if let Some(metatable) = lua.named_registry_value::<_, Option<Table>>(KEY)? {
+        return Ok(metatable);
+    }
+    let metatable = lua.create_table()?;
+    metatable.raw_set(ARRAY_MARKER, true)?;
+    lua.set_named_registry_value(KEY, metatable.clone())?;
+    Ok(metatable)
+}
+
+impl fmt::Debug for SerializerOptions {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SerializerOptions")
+            .field("key_transform", &self.key_transform.as_ref().map(|_| "Fn"))
+            .field("empty_struct_variant_as_unit", &self.empty_struct_variant_as_unit)
+            .field("map_as_sorted_pairs", &self.map_as_sorted_pairs)
+            .field("struct_as_ordered_pairs", &self.struct_as_ordered_pairs)
+            .field("table_hook", &self.table_hook.as_ref().map(|_| "Fn"))
+            .field("f32_as_string", &self.f32_as_string)
+            .field("mark_arrays", &self.mark_arrays)
+            .field("stringify_keys", &self.stringify_keys)
+            .field("numeric_string_keys_as_int", &self.numeric_string_keys_as_int)
+            .field("seq_length_field", &self.seq_length_field)
+            .field("skip_none_in_seq", &self.skip_none_in_seq)
+            .field("reject_nil_in_seq", &self.reject_nil_in_seq)
+            .field("non_finite_floats", &self.non_finite_floats)
+            .field("u64_overflow", &self.u64_overflow)
+            .field("int128", &self.int128)
+            .field("enum_repr", &self.enum_repr)
+            .field("bytes_as_table", &self.bytes_as_table)
+            .field("compact", &self.compact)
+            .field("max_string_bytes", &self.max_string_bytes)
+            .field("variant_index", &self.variant_index)
+            .field("skip_field_if", &self.skip_field_if.as_ref().map(|_| "Fn"))
+            .field("max_depth", &self.max_depth)
+            .finish()
+    }
+}
+
+
+/// Customizes how a type is converted to Lua, overriding its `Serialize`
+/// implementation when serialized through `AsLua` or `lua_serialize`.
+///
+/// Serializers other than `Serializer` still use the `Serialize`
+/// implementation, e.g. when the same struct is also written to JSON, and
+/// so do some of serde's attributes, see `AsLua`.
+pub trait LuaSerialize {
+    fn to_lua<'lua>(&self, lua: Context<'lua>) -> ::std::result::Result<Value<'lua>, rlua::Error>;
+}
+
+/// Serializes the wrapped value with its `LuaSerialize` implementation.
+///
+/// serde gives a serializer no way to receive a Lua value, so `AsLua`
+/// passes the value to `Serializer` aside while it runs, which some of
+/// serde's own serializers get in the way of:
+///
+/// - The tuple variants of an enum in a `#[serde(flatten)]` field are
+///   buffered and serialized once `AsLua` has returned, which fails with an
+///   error.
+/// - The payload of a newtype variant of an internally tagged enum is
+///   serialized without its newtype wrapper, so the `Serialize`
+///   implementation is used instead of `LuaSerialize`.
+///
+/// Flattened structs and the variants of adjacently tagged and untagged
+/// enums are not affected.
+pub struct AsLua<'a, T: 'a>(pub &'a T);
+
+/// For use with `#[serde(serialize_with = "rlua_serde::ser::lua_serialize")]`
+/// on fields whose type implements `LuaSerialize`.
+///
+/// Has the limitations of `AsLua` within flattened enums and internally
+/// tagged enums.
+pub fn lua_serialize<T, S>(value: &T, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    where T: LuaSerialize + serde::Serialize, S: serde::Serializer,
+{
+    serde::Serialize::serialize(&AsLua(value), serializer)
+}
+
+const LUA_SERIALIZE_TOKEN: &str = "$rlua_serde::LuaSerialize";
+
+type ToLuaFn = for<'lua> unsafe fn(*const (), Context<'lua>) -> ::std::result::Result<Value<'lua>, rlua::Error>;
+
+thread_local! {
+    /// The value being serialized by `AsLua::serialize`, if any, and how to
+    /// convert it.
+    static LUA_SERIALIZE: Cell<Option<(*const (), ToLuaFn)>> = const { Cell::new(None) };
+}
+
+unsafe fn to_lua_erased<'lua, T: LuaSerialize>(
+    ptr: *const (), lua: Context<'lua>
+) -> ::std::result::Result<Value<'lua>, rlua::Error> {
+    (*(ptr as *const T)).to_lua(lua)
+}
+
+impl<'a, T: LuaSerialize + serde::Serialize> serde::Serialize for AsLua<'a, T> {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: serde::Serializer,
+    {
+        // The serializers of this crate pick the value up from `LUA_SERIALIZE`
+        // when they see the token, others serialize the value as a newtype.
+        struct Restore(Option<(*const (), ToLuaFn)>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                LUA_SERIALIZE.with(|cell| cell.set(self.0));
+            }
+        }
 
+        let current = (self.0 as *const T as *const (), to_lua_erased::<T> as ToLuaFn);
+        // Restores the previous value on return and unwinding alike, so the
+        // cell never points to a value which is gone.
+        let _restore = Restore(LUA_SERIALIZE.with(|cell| cell.replace(Some(current))));
+        serializer.serialize_newtype_struct(LUA_SERIALIZE_TOKEN, self.0)
+    }
+}
 
+
+/// Serializer to Lua values with the default options.
 pub struct Serializer<'lua> {
     pub lua: Context<'lua>,
 }
 
+impl<'lua> Serializer<'lua> {
+    pub fn new(lua: Context<'lua>) -> Self {
+        Serializer { lua }
+    }
+
+    /// Creates a serializer with the given options.
+    pub fn with_options(lua: Context<'lua>, options: SerializerOptions) -> ConfiguredSerializer<'lua> {
+        ConfiguredSerializer::with_options(lua, options)
+    }
+
+    /// Makes the serializer write into `table`, see `ConfiguredSerializer::target`.
+    pub fn target(self, table: Table<'lua>) -> ConfiguredSerializer<'lua> {
+        ConfiguredSerializer::new(self.lua).target(table)
+    }
+}
+
+macro_rules! forward_to_configured {
+    ($($method:ident($($arg:ident: $ty:ty),*) -> $ok:ty;)*) => {
+        $(
+            #[inline]
+            fn $method(self, $($arg: $ty),*) -> Result<$ok> {
+                serde::Serializer::$method(ConfiguredSerializer::new(self.lua), $($arg),*)
+            }
+        )*
+    }
+}
+
 impl<'lua> serde::Serializer for Serializer<'lua> {
     type Ok = Value<'lua>;
     type Error = Error;
@@ -22,6 +521,163 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
     type SerializeStruct        = SerializeMap<'lua>;
     type SerializeStructVariant = SerializeStructVariant<'lua>;
 
+    forward_to_configured! {
+        serialize_bool(value: bool) -> Value<'lua>;
+        serialize_i8(value: i8) -> Value<'lua>;
+        serialize_i16(value: i16) -> Value<'lua>;
+        serialize_i32(value: i32) -> Value<'lua>;
+        serialize_i64(value: i64) -> Value<'lua>;
+        serialize_i128(value: i128) -> Value<'lua>;
+        serialize_u8(value: u8) -> Value<'lua>;
+        serialize_u16(value: u16) -> Value<'lua>;
+        serialize_u32(value: u32) -> Value<'lua>;
+        serialize_u64(value: u64) -> Value<'lua>;
+        serialize_u128(value: u128) -> Value<'lua>;
+        serialize_f32(value: f32) -> Value<'lua>;
+        serialize_f64(value: f64) -> Value<'lua>;
+        serialize_char(value: char) -> Value<'lua>;
+        serialize_str(value: &str) -> Value<'lua>;
+        serialize_bytes(value: &[u8]) -> Value<'lua>;
+        serialize_unit() -> Value<'lua>;
+        serialize_unit_struct(name: &'static str) -> Value<'lua>;
+        serialize_unit_variant(name: &'static str, variant_index: u32, variant: &'static str) -> Value<'lua>;
+        serialize_none() -> Value<'lua>;
+        serialize_seq(len: Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(len: usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(name: &'static str, len: usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(
+            name: &'static str, variant_index: u32, variant: &'static str, len: usize
+        ) -> Self::SerializeTupleVariant;
+        serialize_map(len: Option<usize>) -> Self::SerializeMap;
+        serialize_struct(name: &'static str, len: usize) -> Self::SerializeStruct;
+        serialize_struct_variant(
+            name: &'static str, variant_index: u32, variant: &'static str, len: usize
+        ) -> Self::SerializeStructVariant;
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Value<'lua>>
+        where T: ?Sized + serde::Serialize,
+    {
+        serde::Serializer::serialize_newtype_struct(ConfiguredSerializer::new(self.lua), name, value)
+    }
+
+    #[inline]
+    fn serialize_newtype_variant<T>(
+        self, name: &'static str, variant_index: u32,
+        variant: &'static str, value: &T,
+    ) -> Result<Value<'lua>>
+        where T: ?Sized + serde::Serialize,
+    {
+        serde::Serializer::serialize_newtype_variant(
+            ConfiguredSerializer::new(self.lua), name, variant_index, variant, value,
+        )
+    }
+
+    #[inline]
+    fn serialize_some<T>(self, value: &T) -> Result<Value<'lua>>
+        where T: ?Sized + serde::Serialize,
+    {
+        serde::Serializer::serialize_some(ConfiguredSerializer::new(self.lua), value)
+    }
+}
+
+
+/// Serializer to Lua values with `SerializerOptions`, which are passed on to
+/// the serializers of nested values.
+pub struct ConfiguredSerializer<'lua> {
+    lua: Context<'lua>,
+    target: Option<Table<'lua>>,
+    options: Rc<SerializerOptions>,
+    /// Number of tables the value being serialized is nested in.
+    depth: usize,
+    /// The internally tagged variant whose payload is being serialized,
+    /// which can't be a sequence.
+    tagged_variant: Option<&'static str>,
+}
+
+impl<'lua> ConfiguredSerializer<'lua> {
+    pub fn new(lua: Context<'lua>) -> Self {
+        Self::with_options(lua, SerializerOptions::default())
+    }
+
+    pub fn with_options(lua: Context<'lua>, options: SerializerOptions) -> Self {
+        ConfiguredSerializer { lua, target: None, options: Rc::new(options), depth: 0, tagged_variant: None }
+    }
+
+    /// Creates a serializer sharing the options of another one.
+    pub(crate) fn from_parts(lua: Context<'lua>, options: &Rc<SerializerOptions>) -> Self {
+        ConfiguredSerializer { lua, target: None, options: options.clone(), depth: 0, tagged_variant: None }
+    }
+
+    /// Makes the serializer write the top-level sequence, map, struct or
+    /// enum variant into `table` instead of creating a new table.
+    ///
+    /// Existing entries of `table` are kept unless overwritten. Sequences are
+    /// written to indices `1..=n`, so a table whose array part was sized in
+    /// advance (see `table_with_capacity`) is filled without rehashing.
+    pub fn target(mut self, table: Table<'lua>) -> Self {
+        self.target = Some(table);
+        self
+    }
+
+    /// Writes a 128-bit integer, given in decimal and as 64-bit words, as
+    /// chosen by the `int128` option.
+    fn int128(self, decimal: &dyn fmt::Display, high: i64, low: i64) -> Result<Value<'lua>> {
+        match self.options.int128 {
+            Int128Encoding::String => {
+                Ok(Value::String(self.lua.create_string(&decimal.to_string())?))
+            },
+            Int128Encoding::Words => {
+                let table = self.lua.create_sequence_from(vec![high, low])?;
+                self.options.finish_array(self.lua, table)
+            },
+        }
+    }
+
+    /// Checks that a table can be created at the current depth.
+    fn enter(&self) -> Result<()> {
+        let max_depth = self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        if self.depth >= max_depth {
+            return Err(serde::ser::Error::custom(format_args!(
+                "recursion limit exceeded: more than {} nested tables", max_depth,
+            )));
+        }
+        Ok(())
+    }
+
+    fn table(&self) -> Result<Table<'lua>> {
+        self.enter()?;
+        match self.target {
+            Some(ref table) => Ok(table.clone()),
+            None => Ok(self.lua.create_table()?),
+        }
+    }
+}
+
+/// Serializes a value nested in `depth` tables.
+fn to_value<'lua, T>(
+    lua: Context<'lua>, options: &Rc<SerializerOptions>, depth: usize, value: &T
+) -> Result<Value<'lua>>
+    where T: ?Sized + serde::Serialize,
+{
+    value.serialize(ConfiguredSerializer {
+        lua, target: None, options: options.clone(), depth, tagged_variant: None,
+    })
+}
+
+impl<'lua> serde::Serializer for ConfiguredSerializer<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    type SerializeSeq           = SerializeVec<'lua>;
+    type SerializeTuple         = SerializeVec<'lua>;
+    type SerializeTupleStruct   = SerializeVec<'lua>;
+    type SerializeTupleVariant  = SerializeTupleVariant<'lua>;
+    type SerializeMap           = SerializeMap<'lua>;
+    type SerializeStruct        = SerializeMap<'lua>;
+    type SerializeStructVariant = SerializeStructVariant<'lua>;
+
     #[inline]
     fn serialize_bool(self, value: bool) -> Result<Value<'lua>> {
         Ok(Value::Boolean(value))
@@ -64,17 +720,47 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
 
     #[inline]
     fn serialize_u64(self, value: u64) -> Result<Value<'lua>> {
-        self.serialize_i64(value as i64)
+        if let Ok(value) = i64::try_from(value) {
+            return self.serialize_i64(value);
+        }
+        match self.options.u64_overflow {
+            U64OverflowPolicy::Error => Err(serde::ser::Error::custom(format_args!(
+                "u64 {} is out of range of Lua integers", value,
+            ))),
+            U64OverflowPolicy::Float => Ok(Value::Number(value as f64)),
+            U64OverflowPolicy::Wrap => self.serialize_i64(value as i64),
+        }
+    }
+
+    fn serialize_i128(self, value: i128) -> Result<Value<'lua>> {
+        self.int128(&value, (value >> 64) as i64, value as i64)
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<Value<'lua>> {
+        self.int128(&value, (value >> 64) as i64, value as i64)
     }
 
     #[inline]
     fn serialize_f32(self, value: f32) -> Result<Value<'lua>> {
+        let keep = value.is_finite() || self.options.non_finite_floats == NonFinitePolicy::Keep;
+        if self.options.f32_as_string && keep {
+            return Ok(Value::String(self.lua.create_string(&value.to_string())?));
+        }
         self.serialize_f64(f64::from(value))
     }
 
     #[inline]
     fn serialize_f64(self, value: f64) -> Result<Value<'lua>> {
-        Ok(Value::Number(value))
+        if value.is_finite() {
+            return Ok(Value::Number(value));
+        }
+        match self.options.non_finite_floats {
+            NonFinitePolicy::Keep => Ok(Value::Number(value)),
+            NonFinitePolicy::Error => Err(serde::ser::Error::custom(format_args!(
+                "can't serialize non-finite float {}", value,
+            ))),
+            NonFinitePolicy::Replace(replacement) => Ok(Value::Number(replacement)),
+        }
     }
 
     #[inline]
@@ -86,12 +772,18 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
 
     #[inline]
     fn serialize_str(self, value: &str) -> Result<Value<'lua>> {
+        self.options.check_string_len(value.len())?;
         Ok(Value::String(self.lua.create_string(value)?))
     }
 
     #[inline]
     fn serialize_bytes(self, value: &[u8]) -> Result<Value<'lua>> {
-        Ok(Value::Table(self.lua.create_sequence_from(value.iter().cloned())?))
+        self.options.check_string_len(value.len())?;
+        if !self.options.bytes_as_table {
+            return Ok(Value::String(self.lua.create_string(value)?));
+        }
+        let table = self.lua.create_sequence_from(value.iter().cloned())?;
+        self.options.finish_array(self.lua, table)
     }
 
     #[inline]
@@ -106,31 +798,51 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
 
     #[inline]
     fn serialize_unit_variant(
-        self, _name: &'static str, _variant_index: u32, variant: &'static str
+        self, _name: &'static str, variant_index: u32, variant: &'static str
     ) -> Result<Value<'lua>> {
-        self.serialize_str(variant)
+        self.options.variant_value(self.lua, self.target, variant_index, variant, None, false)
     }
 
     #[inline]
     fn serialize_newtype_struct<T>(
-        self, _name: &'static str, value: &T
+        self, name: &'static str, value: &T
     ) -> Result<Value<'lua>>
         where T: ?Sized + serde::Serialize,
     {
+        if name == LUA_SERIALIZE_TOKEN {
+            return match LUA_SERIALIZE.with(|cell| cell.take()) {
+                // Safety: set by `AsLua::serialize` with a pointer to the
+                // value it holds, which is borrowed for the whole call.
+                Some((ptr, to_lua)) => Ok(unsafe { to_lua(ptr, self.lua) }?),
+                // The value was serialized into a buffer and is replayed after
+                // `AsLua::serialize` returned, e.g. by `#[serde(flatten)]`.
+                None => Err(serde::ser::Error::custom(
+                    "a LuaSerialize value can't be buffered, as with tuple variants \
+                     of enums in `#[serde(flatten)]` fields",
+                )),
+            };
+        }
         value.serialize(self)
     }
 
     fn serialize_newtype_variant<T>(
-        self, _name: &'static str, _variant_index: u32,
+        self, _name: &'static str, variant_index: u32,
         variant: &'static str, value: &T,
     ) -> Result<Value<'lua>>
         where T: ?Sized + serde::Serialize,
     {
-        let table = self.lua.create_table()?;
-        let variant = self.lua.create_string(variant)?;
-        let value = to_value(self.lua, value)?;
-        table.set(variant, value)?;
-        Ok(Value::Table(table))
+        let tagged_variant = match self.options.enum_repr {
+            EnumRepr::Internal { .. } if !self.options.compact => Some(variant),
+            _ => None,
+        };
+        let value = value.serialize(ConfiguredSerializer {
+            lua: self.lua,
+            target: None,
+            options: self.options.clone(),
+            depth: self.depth + 1,
+            tagged_variant,
+        })?;
+        self.options.variant_value(self.lua, self.target, variant_index, variant, Some(value), false)
     }
 
     #[inline]
@@ -146,11 +858,17 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        let table = self.lua.create_table()?;
+        // Rejected even when empty, which the payload table alone can't tell.
+        if let Some(variant) = self.tagged_variant {
+            return Err(not_internally_taggable(variant));
+        }
+        let table = self.table()?;
         Ok(SerializeVec {
             lua: self.lua,
             idx: 1,
             table,
+            options: self.options,
+            depth: self.depth,
         })
     }
 
@@ -165,42 +883,82 @@ impl<'lua> serde::Serializer for Serializer<'lua> {
     }
 
     fn serialize_tuple_variant(
-        self, _name: &'static str, _variant_index: u32,
+        self, _name: &'static str, variant_index: u32,
         variant: &'static str, _len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        let name = self.lua.create_string(variant)?;
+        if let EnumRepr::Internal { .. } = self.options.enum_repr {
+            if !self.options.compact {
+                return Err(not_internally_taggable(variant));
+            }
+        }
+        self.enter()?;
         let table = self.lua.create_table()?;
         Ok(SerializeTupleVariant {
             lua: self.lua,
             idx: 1,
-            name,
-            table
+            index: variant_index,
+            name: variant,
+            table,
+            outer: self.target,
+            options: self.options,
+            depth: self.depth,
         })
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        let table = self.lua.create_table()?;
+        let table = self.table()?;
+        let pairs = if self.options.map_as_sorted_pairs { Some(Vec::new()) } else { None };
         Ok(SerializeMap {
             lua: self.lua,
             next_key: None,
             table,
+            pairs,
+            sort_pairs: true,
+            small_keys: None,
+            float_keys: false,
+            next_index: None,
+            options: self.options,
+            depth: self.depth,
         })
     }
 
-    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
-        self.serialize_map(Some(len))
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        let table = self.table()?;
+        let pairs = if self.options.struct_as_ordered_pairs && !self.options.compact {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        Ok(SerializeMap {
+            lua: self.lua,
+            next_key: None,
+            table,
+            pairs,
+            sort_pairs: false,
+            small_keys: None,
+            float_keys: false,
+            next_index: if self.options.compact { Some(1) } else { None },
+            options: self.options,
+            depth: self.depth,
+        })
     }
 
     fn serialize_struct_variant(
-        self, _name: &'static str, _variant_index: u32,
-        variant: &'static str, _len: usize,
+        self, _name: &'static str, variant_index: u32,
+        variant: &'static str, len: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        let name = self.lua.create_string(variant)?;
+        self.enter()?;
         let table = self.lua.create_table()?;
         Ok(SerializeStructVariant {
             lua: self.lua,
-            name,
+            index: variant_index,
+            name: variant,
+            next_index: if self.options.compact { Some(1) } else { None },
             table,
+            as_unit: len == 0 && self.options.empty_struct_variant_as_unit,
+            outer: self.target,
+            options: self.options,
+            depth: self.depth,
         })
     }
 
@@ -211,6 +969,8 @@ pub struct SerializeVec<'lua> {
     lua: Context<'lua>,
     table: Table<'lua>,
     idx: u64,
+    options: Rc<SerializerOptions>,
+    depth: usize,
 }
 
 impl<'lua> serde::ser::SerializeSeq for SerializeVec<'lua> {
@@ -220,13 +980,19 @@ impl<'lua> serde::ser::SerializeSeq for SerializeVec<'lua> {
     fn serialize_element<T>(&mut self, value: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        self.table.set(self.idx, to_value(self.lua, value)?)?;
-        self.idx += 1;
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)?;
+        if self.options.keep_seq_element(self.idx, &value)? {
+            self.table.set(self.idx, value)?;
+            self.idx += 1;
+        }
         Ok(())
     }
 
     fn end(self) -> Result<Value<'lua>> {
-        Ok(Value::Table(self.table))
+        if self.options.seq_length_field {
+            self.table.raw_set("n", self.idx - 1)?;
+        }
+        self.options.finish_array(self.lua, self.table)
     }
 }
 
@@ -263,9 +1029,13 @@ impl<'lua> serde::ser::SerializeTupleStruct for SerializeVec<'lua> {
 
 pub struct SerializeTupleVariant<'lua> {
     lua: Context<'lua>,
-    name: LuaString<'lua>,
+    index: u32,
+    name: &'static str,
     table: Table<'lua>,
     idx: u64,
+    outer: Option<Table<'lua>>,
+    options: Rc<SerializerOptions>,
+    depth: usize,
 }
 
 impl<'lua> serde::ser::SerializeTupleVariant for SerializeTupleVariant<'lua> {
@@ -275,15 +1045,21 @@ impl<'lua> serde::ser::SerializeTupleVariant for SerializeTupleVariant<'lua> {
     fn serialize_field<T>(&mut self, value: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        self.table.set(self.idx, to_value(self.lua, value)?)?;
-        self.idx += 1;
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)
+            .map_err(|err| Error::variant(self.name, err))?;
+        if self.options.keep_seq_element(self.idx, &value).map_err(|err| Error::variant(self.name, err))? {
+            self.table.set(self.idx, value)?;
+            self.idx += 1;
+        }
         Ok(())
     }
 
     fn end(self) -> Result<Value<'lua>> {
-        let table = self.lua.create_table()?;
-        table.set(self.name, self.table)?;
-        Ok(Value::Table(table))
+        if self.options.seq_length_field {
+            self.table.raw_set("n", self.idx - 1)?;
+        }
+        let inner = self.options.finish_array(self.lua, self.table)?;
+        self.options.variant_value(self.lua, self.outer, self.index, self.name, Some(inner), true)
     }
 }
 
@@ -291,7 +1067,99 @@ impl<'lua> serde::ser::SerializeTupleVariant for SerializeTupleVariant<'lua> {
 pub struct SerializeMap<'lua> {
     lua: Context<'lua>,
     table: Table<'lua>,
-    next_key: Option<Value<'lua>>
+    next_key: Option<Value<'lua>>,
+    /// Entries collected to be written as pairs at the end.
+    pairs: Option<Vec<(Value<'lua>, Value<'lua>)>>,
+    sort_pairs: bool,
+    /// Loaded on the first small key to stringify.
+    small_keys: Option<Table<'lua>>,
+    /// Position of the next field of a `compact` struct.
+    next_index: Option<u64>,
+    /// Whether a float key was stored as an integer, after which integer
+    /// keys are checked for collisions.
+    float_keys: bool,
+    options: Rc<SerializerOptions>,
+    depth: usize,
+}
+
+impl<'lua> SerializeMap<'lua> {
+    fn stringify_key(&mut self, key: i64) -> Result<Value<'lua>> {
+        if !SMALL_KEYS.contains(&key) {
+            return Ok(Value::String(self.lua.create_string(&key.to_string())?));
+        }
+        if let Some(ref strings) = self.small_keys {
+            return Ok(strings.raw_get(key + 1)?);
+        }
+        let strings = small_key_strings(self.lua)?;
+        let key = strings.raw_get(key + 1)?;
+        self.small_keys = Some(strings);
+        Ok(key)
+    }
+
+    /// Serializes and checks a map key.
+    fn key<T>(&mut self, key: &T) -> Result<Value<'lua>>
+        where T: ?Sized + serde::Serialize,
+    {
+        let mut key = match to_value(self.lua, &self.options, self.depth + 1, key)? {
+            Value::String(ref key) if self.options.key_transform.is_some() => {
+                let key = self.options.transform_key(key.to_str()?);
+                Value::String(self.lua.create_string(key.as_ref())?)
+            },
+            Value::Integer(key) if self.options.stringify_keys => self.stringify_key(key)?,
+            key => key,
+        };
+        if self.options.numeric_string_keys_as_int {
+            if let Value::String(ref s) = key {
+                if let Some(n) = numeric_key(s.as_bytes()) {
+                    key = Value::Integer(n);
+                }
+            }
+        }
+        check_key(&key, self.pairs.is_some())?;
+        // Lua stores floats with an integral value under the equal integer,
+        // so `2.0` and `2` would silently end up in the same entry.
+        if let Value::Number(n) = key {
+            if let Some(n) = integral_float(n) {
+                key = Value::Integer(n);
+                self.float_keys = true;
+            }
+        }
+        Ok(key)
+    }
+
+    /// Stores an entry with the key given to `serialize_key`.
+    fn insert_pending(&mut self, value: Value<'lua>) -> Result<()> {
+        // A bug in a `Serialize` implementation, but panicking here would
+        // unwind through Lua.
+        let key = match self.next_key.take() {
+            Some(key) => key,
+            None => return Err(serde::ser::Error::custom(
+                "serialize_value called before serialize_key",
+            )),
+        };
+        self.insert(key, value)
+    }
+
+    /// Stores an entry.
+    fn insert(&mut self, key: Value<'lua>, value: Value<'lua>) -> Result<()> {
+        match self.pairs {
+            Some(ref mut pairs) => pairs.push((key, value)),
+            None => {
+                if let Value::Integer(n) = key {
+                    let taken = self.float_keys
+                        && !matches!(self.table.raw_get(n)?, Value::Nil);
+                    if taken {
+                        return Err(serde::ser::Error::custom(format_args!(
+                            "duplicate map key {}, as Lua stores floats with an integral \
+                             value under the equal integer", n,
+                        )));
+                    }
+                }
+                self.table.set(key, value)?
+            },
+        }
+        Ok(())
+    }
 }
 
 impl<'lua> serde::ser::SerializeMap for SerializeMap<'lua> {
@@ -301,23 +1169,75 @@ impl<'lua> serde::ser::SerializeMap for SerializeMap<'lua> {
     fn serialize_key<T>(&mut self, key: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        self.next_key = Some(to_value(self.lua, key)?);
+        self.next_key = Some(self.key(key)?);
         Ok(())
     }
 
     fn serialize_value<T>(&mut self, value: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        let key = self.next_key.take();
-        // Panic because this indicates a bug in the program rather than an
-        // expected failure.
-        let key = key.expect("serialize_value called before serialize_key");
-        self.table.set(key, to_value(self.lua, value)?)?;
-        Ok(())
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)?;
+        self.insert_pending(value)
+    }
+
+    fn serialize_entry<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+        where K: ?Sized + serde::Serialize, V: ?Sized + serde::Serialize,
+    {
+        let key = self.key(key)?;
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)?;
+        self.insert(key, value)
     }
 
     fn end(self) -> Result<Value<'lua>> {
-        Ok(Value::Table(self.table))
+        if let Some(mut pairs) = self.pairs {
+            if self.sort_pairs {
+                pairs.sort_by(|a, b| key_order(&a.0, &b.0));
+            }
+            for (idx, (key, value)) in pairs.into_iter().enumerate() {
+                let pair = self.lua.create_sequence_from(vec![key, value])?;
+                self.table.set(idx + 1, self.options.finish_table(self.lua, pair))?;
+            }
+        }
+        Ok(self.options.finish_table(self.lua, self.table))
+    }
+}
+
+/// Fails on map keys which Lua tables can't hold, instead of leaving Lua to
+/// raise an error when the entry is inserted.
+///
+/// Keys other than strings and numbers are rejected too unless they are
+/// written as pairs, since a table keyed by booleans or by the identity of
+/// tables can't usefully be indexed from Lua.
+fn check_key(key: &Value, as_pairs: bool) -> Result<()> {
+    let kind = match *key {
+        Value::Nil => "nil",
+        Value::Number(n) if n.is_nan() => "NaN",
+        Value::String(_) | Value::Integer(_) | Value::Number(_) => return Ok(()),
+        _ if as_pairs => return Ok(()),
+        _ => return Err(serde::ser::Error::custom("map key must be a string or number")),
+    };
+    Err(serde::ser::Error::custom(format_args!(
+        "map key serialized to {}, which is not a valid Lua table key", kind,
+    )))
+}
+
+/// Orders numbers first, then strings, then everything else as equal.
+pub(crate) fn key_order(a: &Value, b: &Value) -> Ordering {
+    fn rank(key: &Value) -> u8 {
+        match *key {
+            Value::Integer(_) | Value::Number(_) => 0,
+            Value::String(_) => 1,
+            _ => 2,
+        }
+    }
+
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        (Value::Integer(a), Value::Number(b)) => (*a as f64).total_cmp(b),
+        (Value::Number(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)),
+        (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        _ => rank(a).cmp(&rank(b)),
     }
 }
 
@@ -328,8 +1248,27 @@ impl<'lua> serde::ser::SerializeStruct for SerializeMap<'lua> {
     fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        serde::ser::SerializeMap::serialize_key(self, key)?;
-        serde::ser::SerializeMap::serialize_value(self, value)
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)?;
+        let skip = self.options.skip_field(key, &value);
+        if let Some(ref mut index) = self.next_index {
+            if !skip {
+                self.table.set(*index, value)?;
+            }
+            *index += 1;
+            return Ok(());
+        }
+        if !skip {
+            let key = self.key(key)?;
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn skip_field(&mut self, _key: &'static str) -> Result<()> {
+        if let Some(ref mut index) = self.next_index {
+            *index += 1;
+        }
+        Ok(())
     }
 
     fn end(self) -> Result<Value<'lua>> {
@@ -340,8 +1279,15 @@ impl<'lua> serde::ser::SerializeStruct for SerializeMap<'lua> {
 
 pub struct SerializeStructVariant<'lua> {
     lua: Context<'lua>,
-    name: LuaString<'lua>,
+    index: u32,
+    name: &'static str,
+    /// Position of the next field of a `compact` variant.
+    next_index: Option<u64>,
     table: Table<'lua>,
+    as_unit: bool,
+    outer: Option<Table<'lua>>,
+    options: Rc<SerializerOptions>,
+    depth: usize,
 }
 
 impl<'lua> serde::ser::SerializeStructVariant for SerializeStructVariant<'lua> {
@@ -351,22 +1297,51 @@ impl<'lua> serde::ser::SerializeStructVariant for SerializeStructVariant<'lua> {
     fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
         where T: ?Sized + serde::Serialize,
     {
-        self.table
-            .set(key, to_value(self.lua, value)?)?;
+        let value = to_value(self.lua, &self.options, self.depth + 1, value)
+            .map_err(|err| Error::variant(self.name, err))?;
+        let skip = self.options.skip_field(key, &value);
+        match self.next_index {
+            Some(ref mut index) => {
+                if !skip {
+                    self.table.set(*index, value)?;
+                }
+                *index += 1;
+            },
+            None if skip => (),
+            None => self.table.set(self.options.transform_key(key).as_ref(), value)?,
+        }
+        Ok(())
+    }
+
+    fn skip_field(&mut self, _key: &'static str) -> Result<()> {
+        if let Some(ref mut index) = self.next_index {
+            *index += 1;
+        }
         Ok(())
     }
 
     fn end(self) -> Result<Value<'lua>> {
-        let table = self.lua.create_table()?;
-        table.set(self.name, self.table)?;
-        Ok(Value::Table(table))
+        if self.as_unit {
+            return self.options.variant_value(self.lua, self.outer, self.index, self.name, None, false);
+        }
+        let inner = self.options.finish_table(self.lua, self.table);
+        self.options.variant_value(self.lua, self.outer, self.index, self.name, Some(inner), true)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::result::Result as StdResult;
+
     use rlua::Lua;
     use super::*;
+    use {
+        to_value, to_value_ref, to_value_with, to_value_or_raise, from_value, from_value_with, serialize_into, table_with_capacity,
+        to_variadic, to_value_diff, merge_into, merge_into_with, to_variadic_with,
+        serialize_into_with, serialize_stream,
+    };
+    use value::MergeOptions;
+    use de::DeserializerOptions;
 
     #[test]
     fn test_struct() {
@@ -391,6 +1366,21 @@ mod tests {
         }).unwrap()
     }
 
+    #[test]
+    fn test_serializer_literal() {
+        #[derive(Serialize)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        Lua::new().context(|lua| {
+            let value = serde::Serialize::serialize(&Point { x: 1, y: 2 }, Serializer { lua }).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(r#"assert(value.x == 1 and value.y == 2)"#).exec().unwrap();
+        });
+    }
+
     #[test]
     fn test_num() {
         #[derive(Serialize)]
@@ -434,4 +1424,1749 @@ mod tests {
             "#).exec()
         }).unwrap();
     }
+
+    #[test]
+    fn test_variant_error() {
+        struct Fail;
+
+        impl serde::Serialize for Fail {
+            fn serialize<S>(&self, _serializer: S) -> StdResult<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                Err(serde::ser::Error::custom("unsupported"))
+            }
+        }
+
+        #[derive(Serialize)]
+        enum E {
+            Tuple(u32, Fail),
+            Struct { a: u32, b: Fail },
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let t = E::Tuple(1, Fail);
+            let err = to_value(lua, &t).unwrap_err();
+            let msg = err.to_string();
+            assert!(msg.contains("Tuple"), "{}", msg);
+            assert!(msg.contains("unsupported"), "{}", msg);
+
+            let s = E::Struct { a: 1, b: Fail };
+            let err = to_value(lua, &s).unwrap_err();
+            let msg = err.to_string();
+            assert!(msg.contains("Struct"), "{}", msg);
+            assert!(msg.contains("unsupported"), "{}", msg);
+        });
+    }
+
+    #[test]
+    fn test_serialize_into() {
+        #[derive(Serialize)]
+        struct Test {
+            int: u32,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let table = table_with_capacity(lua, 3).unwrap();
+            assert_eq!(0, table.raw_len());
+            serialize_into(lua, table.clone(), [1, 2, 3]).unwrap();
+            lua.globals().set("seq", table).unwrap();
+
+            let table = lua.create_table().unwrap();
+            table.set("keep", true).unwrap();
+            serialize_into(lua, table.clone(), Test { int: 1 }).unwrap();
+            lua.globals().set("map", table.clone()).unwrap();
+
+            assert!(serialize_into(lua, table, 1).is_err());
+
+            lua.load(r#"
+                assert(#seq == 3)
+                assert(seq[1] == 1 and seq[3] == 3)
+                assert(map["int"] == 1)
+                assert(map["keep"] == true)
+            "#).exec()
+        }).unwrap();
+
+        lua.context(|lua| {
+            serialize_into(lua, lua.globals(), Test { int: 7 }).unwrap();
+            lua.load("assert(int == 7 and type(print) == 'function')").exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_key_transform() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Test {
+            user_name: String,
+            max_retries: u32,
+            tags: ::std::collections::HashMap<String, bool>,
+        }
+
+        fn to_camel(key: &str) -> String {
+            let mut parts = key.split('_');
+            let mut out = parts.next().unwrap_or("").to_owned();
+            for part in parts {
+                let mut chars = part.chars();
+                if let Some(c) = chars.next() {
+                    out.extend(c.to_uppercase());
+                    out.push_str(chars.as_str());
+                }
+            }
+            out
+        }
+
+        fn to_snake(key: &str) -> String {
+            let mut out = String::new();
+            for c in key.chars() {
+                if c.is_uppercase() {
+                    out.push('_');
+                    out.extend(c.to_lowercase());
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+
+        let test = Test {
+            user_name: "lua".to_owned(),
+            max_retries: 3,
+            tags: vec![("is_admin".to_owned(), true)].into_iter().collect(),
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let options = SerializerOptions {
+                key_transform: Some(Rc::new(to_camel)),
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, &test, options).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"
+                assert(value["userName"] == "lua")
+                assert(value["maxRetries"] == 3)
+                assert(value["tags"]["isAdmin"] == true)
+                assert(value["user_name"] == nil)
+            "#).exec().unwrap();
+
+            let options = DeserializerOptions {
+                key_transform: Some(Rc::new(to_snake)),
+                ..DeserializerOptions::default()
+            };
+            let got: Test = from_value_with(value, options).unwrap();
+            assert_eq!(test, got);
+        });
+    }
+
+    #[test]
+    fn test_empty_struct_variant() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum E {
+            Unit,
+            Empty {},
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value(lua, E::Empty {}).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"
+                assert(type(value["Empty"]) == "table")
+                assert(next(value["Empty"]) == nil)
+            "#).exec().unwrap();
+            assert_eq!(E::Empty {}, from_value::<E>(value).unwrap());
+
+            let options = SerializerOptions {
+                empty_struct_variant_as_unit: true,
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, E::Empty {}, options.clone()).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"
+                assert(value == "Empty")
+            "#).exec().unwrap();
+            assert_eq!(E::Empty {}, from_value::<E>(value).unwrap());
+
+            let value = to_value_with(lua, E::Unit, options).unwrap();
+            assert_eq!(E::Unit, from_value::<E>(value).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_seq_elements() {
+        #[derive(Serialize)]
+        enum E {
+            A,
+            B(i8),
+        }
+
+        #[derive(Serialize)]
+        struct Wrapper(u32);
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let ints = to_value(lua, vec![i64::MIN, -1, 0, i64::MAX]).unwrap();
+            let bools = to_value(lua, [true, false]).unwrap();
+            let floats = to_value(lua, [0.5f64, 2.0]).unwrap();
+            let mixed = to_value(lua, (1u8, "a", Wrapper(2), E::A, E::B(-3))).unwrap();
+            lua.globals().set("ints", ints).unwrap();
+            lua.globals().set("bools", bools).unwrap();
+            lua.globals().set("floats", floats).unwrap();
+            lua.globals().set("mixed", mixed).unwrap();
+            lua.load(r#"
+                assert(math.type(ints[1]) == "integer" and ints[1] == math.mininteger)
+                assert(ints[2] == -1 and ints[3] == 0 and ints[4] == math.maxinteger)
+                assert(bools[1] == true and bools[2] == false)
+                assert(math.type(floats[2]) == "float" and floats[1] == 0.5)
+                assert(mixed[1] == 1 and mixed[2] == "a" and mixed[3] == 2)
+                assert(mixed[4] == "A" and mixed[5]["B"] == -3)
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_seq_element_serialized_once() {
+        use std::cell::RefCell;
+        use std::vec::IntoIter;
+
+        /// Yields its elements only the first time it is serialized.
+        struct Once(RefCell<Option<IntoIter<i32>>>);
+
+        impl serde::Serialize for Once {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                let iter = self.0.borrow_mut().take().expect("serialized twice");
+                serializer.collect_seq(iter)
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let once = |elements: Vec<i32>| Once(RefCell::new(Some(elements.into_iter())));
+            let seq = vec![once(vec![1, 2]), once(vec![3])];
+            let got: Vec<Vec<i32>> = from_value(to_value(lua, &seq).unwrap()).unwrap();
+            assert_eq!(got, vec![vec![1, 2], vec![3]]);
+        });
+    }
+
+    #[cfg(feature = "erased-serde")]
+    #[test]
+    fn test_erased() {
+        use erased_serde;
+        use to_value_erased;
+
+        #[derive(Serialize)]
+        struct Plugin {
+            name: &'static str,
+        }
+
+        let values: Vec<Box<dyn erased_serde::Serialize>> = vec![
+            Box::new(Plugin { name: "a" }),
+            Box::new(vec![1, 2]),
+        ];
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let plugin = to_value_erased(lua, &*values[0]).unwrap();
+            let seq = to_value_erased(lua, &*values[1]).unwrap();
+            lua.globals().set("plugin", plugin).unwrap();
+            lua.globals().set("seq", seq).unwrap();
+            lua.load(r#"
+                assert(plugin["name"] == "a")
+                assert(seq[1] == 1 and seq[2] == 2)
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_transparent() {
+        #[derive(Serialize)]
+        #[serde(transparent)]
+        struct Meters(f64);
+
+        #[derive(Serialize)]
+        struct Inner {
+            x: u32,
+        }
+
+        #[derive(Serialize)]
+        #[serde(transparent)]
+        struct Wrapper {
+            inner: Inner,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let meters = to_value(lua, Meters(1.5)).unwrap();
+            let wrapper = to_value(lua, Wrapper { inner: Inner { x: 7 } }).unwrap();
+            lua.globals().set("meters", meters).unwrap();
+            lua.globals().set("wrapper", wrapper).unwrap();
+            lua.load(
+                r#"
+                assert(meters == 1.5)
+                assert(wrapper.x == 7)
+                assert(wrapper.inner == nil)
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_variadic() {
+        #[derive(Serialize)]
+        struct Point {
+            x: u32,
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let values = to_variadic(lua, (1, "two", Point { x: 3 })).unwrap();
+            assert_eq!(3, values.len());
+            let check = lua.load(r#"
+                return function(a, b, c)
+                    assert(a == 1)
+                    assert(b == "two")
+                    assert(c.x == 3)
+                end
+            "#).eval::<rlua::Function>().unwrap();
+            check.call::<_, ()>(values).unwrap();
+
+            assert_eq!(0, to_variadic(lua, ()).unwrap().len());
+            assert!(to_variadic(lua, vec![1, 2, 3]).is_err());
+            assert!(to_variadic(lua, 1).is_err());
+        });
+    }
+
+    #[test]
+    fn test_map_as_sorted_pairs() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+        #[serde(untagged)]
+        enum Key {
+            Int(i64),
+            Str(String),
+        }
+
+        let map: HashMap<Key, u32> = vec![
+            (Key::Str("b".to_owned()), 1),
+            (Key::Int(10), 2),
+            (Key::Str("a".to_owned()), 3),
+            (Key::Int(-1), 4),
+            (Key::Int(2), 5),
+        ].into_iter().collect();
+
+        let options = SerializerOptions {
+            map_as_sorted_pairs: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, &map, options).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(
+                r#"
+                local keys = {}
+                for i, pair in ipairs(value) do keys[i] = tostring(pair[1]) end
+                assert(table.concat(keys, ",") == "-1,2,10,a,b", table.concat(keys, ","))
+                assert(value[1][2] == 4 and value[5][2] == 1)
+            "#).exec().unwrap();
+
+            let options = DeserializerOptions {
+                map_from_pairs: true,
+                ..DeserializerOptions::default()
+            };
+            let got: HashMap<Key, u32> = from_value_with(value, options.clone()).unwrap();
+            assert_eq!(map, got);
+
+            let shuffled = lua.load(r#"return { { "b", 1 }, { 10, 2 } }"#).eval().unwrap();
+            let got: HashMap<Key, u32> = from_value_with(shuffled, options).unwrap();
+            assert_eq!(2, got.len());
+            assert_eq!(Some(&2), got.get(&Key::Int(10)));
+        });
+    }
+
+    #[test]
+    fn test_table_hook() {
+        #[derive(Serialize)]
+        enum E {
+            Tuple(u32, u32),
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            seq: Vec<Vec<u32>>,
+            variant: E,
+            map: ::std::collections::BTreeMap<&'static str, u32>,
+        }
+
+        let test = Test {
+            seq: vec![vec![1], vec![]],
+            variant: E::Tuple(1, 2),
+            map: vec![("a", 1)].into_iter().collect(),
+        };
+
+        let options = SerializerOptions {
+            table_hook: Some(Rc::new(|table: &Table, lua: Context| {
+                let metatable = lua.create_table().unwrap();
+                metatable.set("__name", "serde").unwrap();
+                table.set_metatable(Some(metatable));
+            })),
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, &test, options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(
+                r#"
+                local function tagged(t)
+                    local mt = getmetatable(t)
+                    return mt ~= nil and mt.__name == "serde"
+                end
+                assert(tagged(value))
+                assert(tagged(value.seq) and tagged(value.seq[1]) and tagged(value.seq[2]))
+                assert(tagged(value.variant) and tagged(value.variant.Tuple))
+                assert(tagged(value.map))
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_value_diff() {
+        #[derive(Serialize, Clone)]
+        struct Server {
+            host: &'static str,
+            port: u16,
+        }
+
+        #[derive(Serialize, Clone)]
+        struct Config {
+            name: &'static str,
+            server: Server,
+            tags: Vec<&'static str>,
+        }
+
+        let baseline = Config {
+            name: "app",
+            server: Server { host: "localhost", port: 80 },
+            tags: vec!["a", "b"],
+        };
+        let mut current = baseline.clone();
+        current.server.port = 8080;
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let diff = to_value_diff(lua, &current, &baseline).unwrap();
+            lua.globals().set("diff", diff).unwrap();
+            lua.load(
+                r#"
+                assert(diff.name == nil and diff.tags == nil)
+                assert(diff.server.port == 8080 and diff.server.host == nil)
+            "#).exec().unwrap();
+
+            current.tags.push("c");
+            let diff = to_value_diff(lua, &current, &baseline).unwrap();
+            lua.globals().set("diff", diff).unwrap();
+            lua.load(r#"assert(#diff.tags == 3)"#).exec().unwrap();
+
+            let diff = to_value_diff(lua, &baseline, &baseline).unwrap();
+            match diff {
+                Value::Nil => (),
+                _ => panic!("expected an empty diff"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_merge_into() {
+        #[derive(Serialize)]
+        struct Server {
+            port: u16,
+        }
+
+        #[derive(Serialize)]
+        struct Overrides {
+            name: &'static str,
+            server: Server,
+        }
+
+        #[derive(Serialize)]
+        struct Conflict {
+            server: u16,
+        }
+
+        let overrides = Overrides { name: "prod", server: Server { port: 443 } };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let base: Table = lua.load(r#"
+                return {
+                    name = "dev",
+                    debug = true,
+                    server = { host = "localhost", port = 80 },
+                }
+            "#).eval().unwrap();
+            merge_into(lua, &base, &overrides).unwrap();
+            lua.globals().set("base", base.clone()).unwrap();
+            lua.load(
+                r#"
+                assert(base.name == "prod" and base.debug == true)
+                assert(base.server.host == "localhost" and base.server.port == 443)
+            "#).exec().unwrap();
+
+            let err = merge_into(lua, &base, Conflict { server: 1 }).unwrap_err();
+            assert!(err.to_string().contains("at key `server`"), "{}", err);
+
+            let options = MergeOptions { overwrite_conflicts: true };
+            merge_into_with(lua, &base, Conflict { server: 1 }, options).unwrap();
+            assert_eq!(1, base.get::<_, u16>("server").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_f32() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value(lua, 0.1f32).unwrap();
+            match value {
+                Value::Number(n) => assert_eq!(f64::from(0.1f32), n),
+                _ => panic!("expected a number"),
+            }
+            assert_eq!(0.1f32, from_value::<f32>(value).unwrap());
+
+            let options = SerializerOptions {
+                f32_as_string: true,
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, 0.1f32, options).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"assert(value == "0.1")"#).exec().unwrap();
+
+            let options = DeserializerOptions {
+                coerce_numbers_from_str: true,
+                ..DeserializerOptions::default()
+            };
+            assert_eq!(0.1f32, from_value_with::<f32>(value, options).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_mark_arrays() {
+        use std::collections::HashMap;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Collection {
+            Map(HashMap<String, u32>),
+            Seq(Vec<u32>),
+        }
+
+        let options = SerializerOptions {
+            mark_arrays: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let empty: Vec<u32> = vec![];
+
+            let value = to_value(lua, &empty).unwrap();
+            assert_eq!(Collection::Map(HashMap::new()), from_value(value).unwrap());
+
+            let value = to_value_with(lua, &empty, options.clone()).unwrap();
+            assert_eq!(Collection::Seq(vec![]), from_value(value).unwrap());
+
+            let value = to_value_with(lua, vec![1, 2, 3], options.clone()).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(
+                r#"
+                assert(#value == 3)
+                local sum = 0
+                for _, v in ipairs(value) do sum = sum + v end
+                assert(sum == 6)
+            "#).exec().unwrap();
+            assert_eq!(Collection::Seq(vec![1, 2, 3]), from_value(value).unwrap());
+
+            let value = to_value_with(lua, vec![vec![1], vec![]], options).unwrap();
+            let got: Vec<Collection> = from_value(value).unwrap();
+            assert_eq!(vec![Collection::Seq(vec![1]), Collection::Seq(vec![])], got);
+        });
+    }
+
+    #[test]
+    fn test_struct_as_ordered_pairs() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Test {
+            zeta: u32,
+            alpha: String,
+            mid: Vec<u32>,
+        }
+
+        let test = Test { zeta: 1, alpha: "a".to_owned(), mid: vec![2, 3] };
+
+        let options = SerializerOptions {
+            struct_as_ordered_pairs: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, &test, options).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(
+                r#"
+                assert(#value == 3)
+                assert(value[1][1] == "zeta" and value[1][2] == 1)
+                assert(value[2][1] == "alpha" and value[2][2] == "a")
+                assert(value[3][1] == "mid" and value[3][2][2] == 3)
+            "#).exec().unwrap();
+
+            let options = DeserializerOptions {
+                map_from_pairs: true,
+                ..DeserializerOptions::default()
+            };
+            assert_eq!(test, from_value_with(value, options.clone()).unwrap());
+
+            let value = lua.load(r#"return { zeta = 1, alpha = "a", mid = { 2, 3 } }"#).eval().unwrap();
+            assert_eq!(test, from_value_with(value, options).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_lua_serialize() {
+        #[derive(Serialize)]
+        struct Color(u8, u8, u8);
+
+        impl LuaSerialize for Color {
+            fn to_lua<'lua>(&self, lua: Context<'lua>) -> StdResult<Value<'lua>, rlua::Error> {
+                let table = lua.create_table()?;
+                table.set("r", self.0)?;
+                table.set("g", self.1)?;
+                table.set("b", self.2)?;
+                Ok(Value::Table(table))
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Theme {
+            #[serde(serialize_with = "lua_serialize")]
+            fg: Color,
+            bg: Color,
+            #[serde(serialize_with = "lua_serialize")]
+            accent: Color,
+        }
+
+        let theme = Theme { fg: Color(1, 2, 3), bg: Color(4, 5, 6), accent: Color(7, 8, 9) };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value(lua, &theme).unwrap();
+            let color = to_value(lua, AsLua(&Color(10, 11, 12))).unwrap();
+            let colors = to_value(lua, vec![AsLua(&theme.fg), AsLua(&theme.bg)]).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.globals().set("color", color).unwrap();
+            lua.globals().set("colors", colors).unwrap();
+            lua.load(
+                r#"
+                assert(value.fg.r == 1 and value.fg.b == 3)
+                assert(value.bg[1] == 4 and value.bg.r == nil)
+                assert(value.accent.g == 8)
+                assert(color.r == 10)
+                assert(colors[1].r == 1 and colors[2].r == 4)
+            "#).exec()
+        }).unwrap();
+
+        // Tuple variants of flattened enums are buffered and replayed once
+        // `AsLua::serialize` returned.
+        #[derive(Serialize)]
+        enum Paint {
+            Solid(#[serde(serialize_with = "lua_serialize")] Color, u8),
+        }
+
+        #[derive(Serialize)]
+        struct Layer {
+            #[serde(flatten)]
+            paint: Paint,
+        }
+
+        // Newtype variants of internally tagged enums lose the newtype.
+        #[derive(Serialize)]
+        struct Named {
+            r: u8,
+        }
+
+        impl LuaSerialize for Named {
+            fn to_lua<'lua>(&self, lua: Context<'lua>) -> StdResult<Value<'lua>, rlua::Error> {
+                Ok(Value::String(lua.create_string("to_lua")?))
+            }
+        }
+
+        #[derive(Serialize)]
+        #[serde(tag = "kind")]
+        enum Tagged {
+            Named(#[serde(serialize_with = "lua_serialize")] Named),
+        }
+
+        Lua::new().context(|lua| {
+            let err = to_value(lua, Layer { paint: Paint::Solid(Color(1, 2, 3), 255) }).unwrap_err();
+            assert!(err.to_string().contains("can't be buffered"), "{}", err);
+
+            let value = to_value(lua, Tagged::Named(Named { r: 1 })).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(r#"assert(value.kind == "Named" and value.r == 1)"#).exec().unwrap();
+            assert!(LUA_SERIALIZE.with(|cell| cell.get()).is_none());
+        });
+
+        // Other serializers use the `Serialize` implementation.
+        #[cfg(feature = "bincode")]
+        assert_eq!(
+            ::bincode::serialize(&theme.fg).unwrap(),
+            ::bincode::serialize(&AsLua(&theme.fg)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_lua_serialize_unwind() {
+        use std::panic;
+
+        struct Boom;
+
+        impl serde::Serialize for Boom {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> StdResult<S::Ok, S::Error> {
+                panic!("serialization panicked")
+            }
+        }
+
+        impl LuaSerialize for Boom {
+            fn to_lua<'lua>(&self, _: Context<'lua>) -> StdResult<Value<'lua>, rlua::Error> {
+                Ok(Value::Nil)
+            }
+        }
+
+        let result = panic::catch_unwind(|| ::serde_json::to_string(&AsLua(&Boom)));
+        assert!(result.is_err());
+        assert!(LUA_SERIALIZE.with(|cell| cell.get()).is_none());
+    }
+
+    #[test]
+    fn test_error_in_callback() {
+        #[derive(Serialize)]
+        enum E {
+            Fails(#[serde(serialize_with = "fail")] u32),
+        }
+
+        fn fail<S: serde::Serializer>(_: &u32, _: S) -> StdResult<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("cannot serialize"))
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let make = lua.create_function(|lua, ok: bool| {
+                if ok {
+                    to_value_or_raise(lua, &vec![1, 2])
+                } else {
+                    to_value_or_raise(lua, &E::Fails(1))
+                }
+            }).unwrap();
+            lua.globals().set("make", make).unwrap();
+            lua.load(
+                r#"
+                assert(make(true)[2] == 2)
+                local ok, err = pcall(make, false)
+                assert(not ok)
+                assert(string.find(tostring(err), "cannot serialize"), tostring(err))
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_stringify_keys() {
+        use std::collections::BTreeMap;
+
+        let map: BTreeMap<i64, u32> = vec![(0, 1), (255, 2), (256, 3), (-1, 4)].into_iter().collect();
+        let options = SerializerOptions {
+            stringify_keys: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, &map, options.clone()).unwrap();
+            let again = to_value_with(lua, &map, options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.globals().set("again", again).unwrap();
+            lua.load(
+                r#"
+                assert(value["0"] == 1 and value["255"] == 2)
+                assert(value["256"] == 3 and value["-1"] == 4)
+                assert(value[0] == nil and value[255] == nil)
+                assert(again["0"] == 1 and again["255"] == 2)
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_numeric_string_keys_as_int() {
+        use std::collections::HashMap;
+
+        let map: HashMap<String, &str> = vec![
+            ("1", "a"), ("2", "b"), ("3", "c"), ("01", "d"), ("-4", "e"),
+            ("99999999999999999999", "f"), ("1x", "g"),
+        ].into_iter().map(|(k, v)| (k.to_owned(), v)).collect();
+        let options = SerializerOptions {
+            numeric_string_keys_as_int: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, &map, options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(
+                r#"
+                assert(#value == 3)
+                assert(table.concat(value) == "abc")
+                assert(value["1"] == nil)
+                assert(value["01"] == "d" and value["-4"] == "e")
+                assert(value["99999999999999999999"] == "f" and value["1x"] == "g")
+            "#).exec()
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_skip_none_in_seq() {
+        let options = SerializerOptions {
+            skip_none_in_seq: true,
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, vec![Some(1), None, Some(3)], options.clone()).unwrap();
+            let got: Vec<i32> = from_value(value).unwrap();
+            assert_eq!(got, vec![1, 3]);
+
+            let value = to_value_with(lua, vec![None::<i32>, None], options.clone()).unwrap();
+            let got: Vec<i32> = from_value(value).unwrap();
+            assert!(got.is_empty());
+
+            let value = to_value(lua, vec![Some(1), None, Some(3)]).unwrap();
+            let got: Vec<Option<i32>> = from_value(value).unwrap();
+            assert_eq!(got, vec![Some(1)]);
+
+            #[derive(Serialize)]
+            enum E {
+                Tuple(Option<i32>, Option<i32>, Option<i32>),
+            }
+
+            let value = to_value_with(lua, E::Tuple(Some(1), None, Some(3)), options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value.Tuple[1] == 1 and value.Tuple[2] == 3)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_none_layout() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Test {
+            a: Option<u32>,
+            b: u32,
+        }
+
+        Lua::new().context(|lua| {
+            let test = Test { a: None, b: 1 };
+            let value = to_value(lua, &test).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load("assert(next(value) == 'b' and next(value, 'b') == nil)").exec().unwrap();
+            assert_eq!(from_value::<Test>(value).unwrap(), test);
+
+            // Elements keep their index, leaving a hole.
+            let value = to_value(lua, vec![Some(1), None, Some(3)]).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[1] == 1 and value[2] == nil and value[3] == 3)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_reject_nil_in_seq() {
+        let options = SerializerOptions {
+            reject_nil_in_seq: true,
+            ..SerializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let err = to_value_with(lua, vec![Some(1), None, Some(3)], options.clone()).unwrap_err();
+            assert!(err.to_string().contains("sequence element 2 serialized to nil"), "{}", err);
+            assert!(to_value_with(lua, (1, None::<i32>), options.clone()).is_err());
+
+            let value = to_value_with(lua, vec![Some(1), Some(3)], options.clone()).unwrap();
+            assert_eq!(from_value::<Vec<Option<i32>>>(value).unwrap(), vec![Some(1), Some(3)]);
+
+            let value = to_value_with(lua, vec![Some(1), None, Some(3)], SerializerOptions {
+                seq_length_field: true,
+                ..options.clone()
+            }).unwrap();
+            let de_options = DeserializerOptions {
+                seq_length_field: true,
+                ..DeserializerOptions::default()
+            };
+            let got: Vec<Option<i32>> = from_value_with(value, de_options.clone()).unwrap();
+            assert_eq!(got, vec![Some(1), None, Some(3)]);
+
+            // Tuple variants are sequences too.
+            #[derive(Serialize, Deserialize, PartialEq, Debug)]
+            enum E {
+                Tuple(Option<i32>, Option<i32>, Option<i32>),
+            }
+
+            let holed = E::Tuple(Some(1), None, Some(3));
+            let err = to_value_with(lua, &holed, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("sequence element 2 serialized to nil"), "{}", err);
+            let value = to_value_with(lua, &holed, SerializerOptions {
+                seq_length_field: true,
+                ..options
+            }).unwrap();
+            assert_eq!(from_value_with::<E>(value, de_options).unwrap(), holed);
+        });
+    }
+
+    #[test]
+    fn test_int128() {
+        let words = SerializerOptions {
+            int128: Int128Encoding::Words,
+            ..SerializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value = to_value(lua, u128::MAX).unwrap();
+            assert_eq!(from_value::<String>(value).unwrap(), "340282366920938463463374607431768211455");
+            let value = to_value(lua, i128::MIN).unwrap();
+            assert_eq!(from_value::<String>(value).unwrap(), i128::MIN.to_string());
+            let value = to_value(lua, 0u128).unwrap();
+            assert_eq!(from_value::<String>(value).unwrap(), "0");
+
+            let cases: &[(i128, i64, i64)] = &[
+                (0, 0, 0),
+                (-1, -1, -1),
+                (1 << 64, 1, 0),
+                (i128::MAX, i64::MAX, -1),
+                (i128::MIN, i64::MIN, 0),
+                (u64::MAX.into(), 0, -1),
+            ];
+            for &(n, high, low) in cases {
+                let value = to_value_with(lua, n, words.clone()).unwrap();
+                assert_eq!(from_value::<(i64, i64)>(value).unwrap(), (high, low), "{}", n);
+            }
+            let value = to_value_with(lua, u128::MAX, words.clone()).unwrap();
+            assert_eq!(from_value::<(i64, i64)>(value).unwrap(), (-1, -1));
+            let value = to_value_with(lua, 1u128 << 127, words).unwrap();
+            assert_eq!(from_value::<(i64, i64)>(value).unwrap(), (i64::MIN, 0));
+        });
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            for &n in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+                match to_value(lua, n).unwrap() {
+                    Value::Number(got) => assert!(got.is_nan() && n.is_nan() || got == n),
+                    _ => panic!("expected a number"),
+                }
+
+                let options = SerializerOptions {
+                    non_finite_floats: NonFinitePolicy::Error,
+                    ..SerializerOptions::default()
+                };
+                let err = to_value_with(lua, n, options.clone()).unwrap_err();
+                assert!(err.to_string().contains("non-finite float"), "{}", err);
+                assert!(to_value_with(lua, vec![1.0, n], options.clone()).is_err());
+                assert!(to_value_with(lua, n as f32, options.clone()).is_err());
+                let options = SerializerOptions { f32_as_string: true, ..options };
+                assert!(to_value_with(lua, n as f32, options).is_err());
+
+                let options = SerializerOptions {
+                    non_finite_floats: NonFinitePolicy::Replace(0.0),
+                    ..SerializerOptions::default()
+                };
+                match to_value_with(lua, n, options).unwrap() {
+                    Value::Number(got) => assert_eq!(got, 0.0),
+                    _ => panic!("expected a number"),
+                }
+            }
+
+            let options = SerializerOptions {
+                non_finite_floats: NonFinitePolicy::Error,
+                ..SerializerOptions::default()
+            };
+            assert!(to_value_with(lua, 1.5, options).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_u64_overflow() {
+        use de::OverflowPolicy;
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let max = i64::MAX as u64;
+            match to_value(lua, max).unwrap() {
+                Value::Integer(n) => assert_eq!(n, i64::MAX),
+                _ => panic!("expected an integer"),
+            }
+
+            let err = to_value(lua, u64::MAX).unwrap_err();
+            assert!(err.to_string().contains("out of range of Lua integers"), "{}", err);
+            assert!(to_value(lua, max + 1).is_err());
+            assert!(to_value(lua, vec![1, u64::MAX]).is_err());
+
+            let options = SerializerOptions {
+                u64_overflow: U64OverflowPolicy::Float,
+                ..SerializerOptions::default()
+            };
+            match to_value_with(lua, u64::MAX, options).unwrap() {
+                Value::Number(n) => assert_eq!(n, u64::MAX as f64),
+                _ => panic!("expected a float"),
+            }
+
+            let options = SerializerOptions {
+                u64_overflow: U64OverflowPolicy::Wrap,
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, u64::MAX, options).unwrap();
+            match value {
+                Value::Integer(n) => assert_eq!(n, -1),
+                _ => panic!("expected an integer"),
+            }
+            let de_options = DeserializerOptions {
+                on_int_overflow: OverflowPolicy::Wrap,
+                ..DeserializerOptions::default()
+            };
+            assert_eq!(u64::MAX, from_value_with::<u64>(value, de_options).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_enum_repr() {
+        use EnumRepr;
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        struct Inner {
+            x: u32,
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        enum E {
+            Unit,
+            Newtype(Inner),
+            Tuple(u32, u32),
+            Struct { a: u32 },
+        }
+
+        let reprs = vec![
+            EnumRepr::External,
+            EnumRepr::Internal { tag: "type".to_owned() },
+            EnumRepr::Adjacent { tag: "t".to_owned(), content: "c".to_owned() },
+            EnumRepr::Positional,
+        ];
+        let values = vec![
+            E::Unit,
+            E::Newtype(Inner { x: 1 }),
+            E::Tuple(2, 3),
+            E::Struct { a: 4 },
+        ];
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            for repr in &reprs {
+                let options = SerializerOptions {
+                    enum_repr: repr.clone(),
+                    ..SerializerOptions::default()
+                };
+                let de_options = DeserializerOptions {
+                    enum_repr: repr.clone(),
+                    ..DeserializerOptions::default()
+                };
+                for value in &values {
+                    let serialized = to_value_with(lua, value, options.clone());
+                    if let (EnumRepr::Internal { .. }, E::Tuple(..)) = (repr, value) {
+                        let err = serialized.unwrap_err();
+                        assert!(err.to_string().contains("can't tag variant `Tuple` internally"), "{}", err);
+                        continue;
+                    }
+                    let serialized = serialized.unwrap();
+                    let got: E = from_value_with(serialized, de_options.clone()).unwrap();
+                    assert_eq!(&got, value, "{:?}", repr);
+                }
+            }
+
+            let check = |repr: &EnumRepr, value: &E, check: &str| {
+                let options = SerializerOptions {
+                    enum_repr: repr.clone(),
+                    ..SerializerOptions::default()
+                };
+                lua.globals().set("value", to_value_with(lua, value, options).unwrap()).unwrap();
+                lua.load(check).exec().unwrap();
+            };
+            check(&reprs[0], &values[0], r#"assert(value == "Unit")"#);
+            check(&reprs[0], &values[3], r#"assert(value.Struct.a == 4)"#);
+            check(&reprs[1], &values[0], r#"assert(value.type == "Unit")"#);
+            check(&reprs[1], &values[1], r#"assert(value.type == "Newtype" and value.x == 1)"#);
+            check(&reprs[1], &values[3], r#"assert(value.type == "Struct" and value.a == 4)"#);
+            check(&reprs[2], &values[0], r#"assert(value.t == "Unit" and value.c == nil)"#);
+            check(&reprs[2], &values[2], r#"assert(value.t == "Tuple" and value.c[2] == 3)"#);
+            check(&reprs[3], &values[0], r#"assert(value[1] == "Unit" and #value == 1)"#);
+            check(&reprs[3], &values[1], r#"assert(value[1] == "Newtype" and value[2].x == 1)"#);
+
+            let options = DeserializerOptions {
+                enum_repr: reprs[1].clone(),
+                ..DeserializerOptions::default()
+            };
+            let value = lua.load(r#"return { a = 4 }"#).eval().unwrap();
+            assert!(from_value_with::<E>(value, options.clone()).is_err());
+            let value = lua.load(r#"return "Unit""#).eval().unwrap();
+            assert_eq!(from_value_with::<E>(value, options).unwrap(), E::Unit);
+
+            // A payload field named like the tag would be overwritten.
+            #[derive(Serialize)]
+            enum Clash {
+                Struct {
+                    #[serde(rename = "type")]
+                    kind: u32,
+                },
+            }
+
+            let options = SerializerOptions {
+                enum_repr: reprs[1].clone(),
+                ..SerializerOptions::default()
+            };
+            let err = to_value_with(lua, Clash::Struct { kind: 1 }, options.clone()).unwrap_err();
+            assert!(err.to_string().contains("field named like the tag `type`"), "{}", err);
+            let table = lua.create_table().unwrap();
+            let err = serialize_into_with(lua, table, Clash::Struct { kind: 1 }, options).unwrap_err();
+            assert!(err.to_string().contains("field named like the tag `type`"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_internal_tag_payload() {
+        use EnumRepr;
+
+        #[derive(Serialize)]
+        struct Shared;
+
+        impl LuaSerialize for Shared {
+            fn to_lua<'lua>(&self, lua: Context<'lua>) -> StdResult<Value<'lua>, rlua::Error> {
+                lua.globals().get("shared")
+            }
+        }
+
+        #[derive(Serialize)]
+        enum E {
+            Shared(#[serde(serialize_with = "lua_serialize")] Shared),
+            Seq(Vec<u32>),
+            Tuple(),
+        }
+
+        let options = SerializerOptions {
+            enum_repr: EnumRepr::Internal { tag: "type".to_owned() },
+            ..SerializerOptions::default()
+        };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            lua.load(r#"shared = { x = 1 }"#).exec().unwrap();
+            let value = to_value_with(lua, E::Shared(Shared), options.clone()).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(r#"
+                assert(value.type == "Shared" and value.x == 1)
+                assert(shared.type == nil and value ~= shared)
+            "#).exec().unwrap();
+
+            // Sequences are rejected whether empty or not.
+            for value in [E::Seq(Vec::new()), E::Seq(vec![1])].iter() {
+                let err = to_value_with(lua, value, options.clone()).unwrap_err();
+                assert!(err.to_string().contains("can't tag variant `Seq` internally"), "{}", err);
+            }
+            let err = to_value_with(lua, E::Tuple(), options.clone()).unwrap_err();
+            assert!(err.to_string().contains("can't tag variant `Tuple` internally"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_bytes() {
+        struct Bytes<'a>(&'a [u8]);
+
+        impl<'a> serde::Serialize for Bytes<'a> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        let bytes = [0u8, 255, 128, b'a'];
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value(lua, Bytes(&bytes)).unwrap();
+            match value {
+                Value::String(ref s) => assert_eq!(s.as_bytes(), &bytes),
+                _ => panic!("expected a string"),
+            }
+
+            let options = SerializerOptions {
+                bytes_as_table: true,
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, Bytes(&bytes), options).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"assert(#value == 4 and value[2] == 255)"#).exec().unwrap();
+            assert_eq!(from_value::<Vec<u8>>(value).unwrap(), bytes);
+        });
+    }
+
+    #[test]
+    fn test_options_in_nested_values() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        enum E {
+            Tuple(Vec<Option<f64>>, u64),
+            Struct { map: BTreeMap<String, Vec<Option<u32>>> },
+        }
+
+        let options = SerializerOptions {
+            skip_none_in_seq: true,
+            non_finite_floats: NonFinitePolicy::Replace(-1.0),
+            u64_overflow: U64OverflowPolicy::Float,
+            ..SerializerOptions::default()
+        };
+        let tuple = E::Tuple(vec![Some(f64::NAN), None, Some(2.0)], u64::MAX);
+        let map = vec![("a".to_owned(), vec![None, Some(1)])].into_iter().collect();
+        let strukt = E::Struct { map };
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value_with(lua, vec![&tuple, &strukt], options.clone()).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load(r#"
+                local tuple = value[1].Tuple
+                assert(#tuple[1] == 2 and tuple[1][1] == -1 and tuple[1][2] == 2)
+                assert(math.type(tuple[2]) == "float")
+                assert(#value[2].Struct.map.a == 1)
+            "#).exec().unwrap();
+
+            let values = to_variadic_with(lua, (f64::INFINITY, vec![None, Some(1)]), options.clone()).unwrap();
+            lua.globals().set("values", values.into_iter().collect::<Vec<_>>()).unwrap();
+            lua.load(r#"assert(values[1] == -1 and #values[2] == 1)"#).exec().unwrap();
+
+            let table = lua.create_table().unwrap();
+            serialize_into_with(lua, table.clone(), vec![None, Some(f64::NAN)], options).unwrap();
+            lua.globals().set("table", table).unwrap();
+            lua.load(r#"assert(#table == 1 and table[1] == -1)"#).exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_invalid_map_keys() {
+        use std::collections::BTreeMap;
+
+        struct Entries(Vec<(f64, u32)>);
+
+        impl serde::Serialize for Entries {
+            fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                serializer.collect_map(self.0.iter().cloned())
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let mut map: BTreeMap<Option<u32>, u32> = BTreeMap::new();
+            map.insert(None, 1);
+            let err = to_value(lua, &map).unwrap_err();
+            assert!(err.to_string().contains("map key serialized to nil"), "{}", err);
+
+            let err = to_value(lua, Entries(vec![(f64::NAN, 1)])).unwrap_err();
+            assert!(err.to_string().contains("map key serialized to NaN"), "{}", err);
+
+            let options = SerializerOptions {
+                map_as_sorted_pairs: true,
+                ..SerializerOptions::default()
+            };
+            assert!(to_value_with(lua, &map, options).is_err());
+
+            let mut map = BTreeMap::new();
+            map.insert(Some(2), 1);
+            let value = to_value(lua, &map).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[2] == 1)").exec().unwrap();
+            let value = to_value(lua, Entries(vec![(1.5, 1)])).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[1.5] == 1)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_skip_field_if() {
+        use value::is_empty_value;
+
+        #[derive(Serialize)]
+        struct Profile {
+            name: String,
+            bio: String,
+            tags: Vec<String>,
+            age: Option<u32>,
+            score: u32,
+        }
+
+        #[derive(Serialize)]
+        enum Event {
+            Joined { name: String, tags: Vec<String> },
+        }
+
+        let options = SerializerOptions {
+            skip_field_if: Some(Rc::new(|_, value| is_empty_value(value))),
+            ..SerializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let profile = Profile {
+                name: "ann".to_owned(),
+                bio: String::new(),
+                tags: Vec::new(),
+                age: None,
+                score: 0,
+            };
+            let value = to_value_with(lua, &profile, options.clone()).unwrap();
+            let table = match value {
+                Value::Table(table) => table,
+                _ => panic!("expected a table"),
+            };
+            let mut keys = table.pairs::<String, Value>()
+                .map(|pair| pair.unwrap().0)
+                .collect::<Vec<_>>();
+            keys.sort();
+            assert_eq!(keys, vec!["name", "score"]);
+
+            let event = Event::Joined { name: "ann".to_owned(), tags: Vec::new() };
+            let value = to_value_with(lua, &event, options.clone()).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value.Joined.name == 'ann' and value.Joined.tags == nil)").exec().unwrap();
+
+            // Map entries are kept.
+            let mut map = ::std::collections::BTreeMap::new();
+            map.insert("bio", "");
+            let value = to_value_with(lua, &map, options.clone()).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value.bio == '')").exec().unwrap();
+
+            // Skipped fields leave a hole in compact structs.
+            let value = to_value_with(lua, &profile, SerializerOptions {
+                compact: true,
+                ..options
+            }).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[1] == 'ann' and value[2] == nil and value[5] == 0)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_serialize_stream() {
+        #[derive(Serialize)]
+        struct Item {
+            id: u32,
+            name: String,
+        }
+
+        Lua::new().context(|lua| {
+            let sink: rlua::Function = lua.load(r#"
+                count, sum = 0, 0
+                return function(item)
+                    count = count + 1
+                    sum = sum + item.id
+                    assert(item.name == "item" .. item.id)
+                end
+            "#).eval().unwrap();
+            let items = (1..=10_000).map(|id| Item { id, name: format!("item{}", id) });
+            serialize_stream(lua, items, sink).unwrap();
+            lua.load("assert(count == 10000 and sum == 50005000)").exec().unwrap();
+
+            let sink: rlua::Function = lua.load(r#"
+                seen = 0
+                return function(n)
+                    seen = seen + 1
+                    if n == 3 then error("full") end
+                end
+            "#).eval().unwrap();
+            let numbers = vec![1, 2, 3, 4, 5];
+            let err = serialize_stream(lua, &numbers, sink).unwrap_err();
+            assert!(err.to_string().contains("full"), "{}", err);
+            lua.load("assert(seen == 3)").exec().unwrap();
+            assert_eq!(numbers.len(), 5);
+        });
+    }
+
+    #[test]
+    fn test_variant_index() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+        #[repr(i32)]
+        enum Level {
+            Low = 0,
+            Mid = 1,
+            High = 2,
+        }
+
+        #[derive(Serialize)]
+        enum Shape {
+            Point,
+            Circle(f64),
+        }
+
+        let options = SerializerOptions {
+            variant_index: true,
+            ..SerializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let levels = vec![Level::Low, Level::High, Level::Mid];
+            let value = to_value_with(lua, &levels, options.clone()).unwrap();
+            let got: Vec<i32> = from_value(value.clone()).unwrap();
+            assert_eq!(got, levels.iter().map(|&level| level as i32).collect::<Vec<_>>());
+
+            let de_options = DeserializerOptions {
+                variant_index: true,
+                ..DeserializerOptions::default()
+            };
+            assert_eq!(from_value_with::<Vec<Level>>(value, de_options).unwrap(), levels);
+
+            // Variants with a payload keep their usual form.
+            let value = to_value_with(lua, vec![Shape::Point, Shape::Circle(1.5)], options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[1] == 0 and value[2].Circle == 1.5)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_atomic_entries() {
+        use serde::ser::{SerializeMap as _, SerializeStruct as _, Serializer as _};
+
+        Lua::new().context(|lua| {
+            let mut state = Serializer::new(lua).serialize_struct("Test", 1).unwrap();
+            state.serialize_field("a", &1).unwrap();
+            assert!(state.next_key.is_none());
+            assert!(state.serialize_field("b", &f64::NAN).is_ok());
+            assert!(state.next_key.is_none());
+            let value = serde::ser::SerializeStruct::end(state).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value.a == 1 and value.b ~= value.b)").exec().unwrap();
+
+            let mut state = Serializer::new(lua).serialize_map(None).unwrap();
+            state.serialize_entry("a", &1).unwrap();
+            assert!(state.next_key.is_none());
+            assert!(state.serialize_entry(&(), &2).is_err());
+            assert!(state.next_key.is_none());
+            let value = serde::ser::SerializeMap::end(state).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value.a == 1)").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_map_value_without_key() {
+        use serde::ser::SerializeMap;
+
+        struct ValueFirst;
+
+        impl serde::Serialize for ValueFirst {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_value(&1)?;
+                map.end()
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let err = to_value(lua, ValueFirst).unwrap_err();
+            assert!(err.to_string().contains("serialize_value called before serialize_key"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_max_string_bytes() {
+        struct Bytes<'a>(&'a [u8]);
+
+        impl<'a> serde::Serialize for Bytes<'a> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        let options = SerializerOptions {
+            max_string_bytes: Some(8),
+            ..SerializerOptions::default()
+        };
+
+        Lua::new().context(|lua| {
+            let value = to_value_with(lua, "12345678", options.clone()).unwrap();
+            assert_eq!(from_value::<String>(value).unwrap(), "12345678");
+
+            let err = to_value_with(lua, "123456789", options.clone()).unwrap_err();
+            assert!(err.to_string().contains("string of 9 bytes exceeds max_string_bytes of 8"), "{}", err);
+
+            let body = [0u8; 100];
+            let err = to_value_with(lua, (1, Bytes(&body)), options.clone()).unwrap_err();
+            assert!(err.to_string().contains("string of 100 bytes"), "{}", err);
+            assert!(to_value(lua, Bytes(&body)).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_to_value_ref() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Config {
+            name: String,
+            ports: Vec<u16>,
+        }
+
+        Lua::new().context(|lua| {
+            let config = Config { name: "main".to_owned(), ports: vec![80, 443] };
+            let value = to_value_ref(lua, &config).unwrap();
+            assert_eq!(from_value::<Config>(value).unwrap(), config);
+
+            let value = to_value_ref(lua, "name").unwrap();
+            assert_eq!(from_value::<String>(value).unwrap(), "name");
+            let value = to_value_ref(lua, &config.ports[..]).unwrap();
+            assert_eq!(from_value::<Vec<u16>>(value).unwrap(), config.ports);
+        });
+    }
+
+    #[test]
+    fn test_non_scalar_map_keys() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize, PartialEq, Eq, Hash)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        Lua::new().context(|lua| {
+            let mut map = HashMap::new();
+            map.insert(Point { x: 1, y: 2 }, "a");
+            let err = to_value(lua, &map).unwrap_err();
+            assert!(err.to_string().contains("map key must be a string or number"), "{}", err);
+
+            let mut map = HashMap::new();
+            map.insert(true, "a");
+            let err = to_value(lua, &map).unwrap_err();
+            assert!(err.to_string().contains("map key must be a string or number"), "{}", err);
+
+            // Pairs can hold any key.
+            let options = SerializerOptions {
+                map_as_sorted_pairs: true,
+                ..SerializerOptions::default()
+            };
+            let value = to_value_with(lua, &map, options).unwrap();
+            lua.globals().set("value", value).unwrap();
+            lua.load("assert(value[1][1] == true and value[1][2] == 'a')").exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_numeric_map_keys() {
+        use std::collections::HashMap;
+        use std::hash::{Hash, Hasher};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+        #[serde(transparent)]
+        struct FloatKey(f64);
+
+        impl Eq for FloatKey {}
+
+        impl Hash for FloatKey {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.0.to_bits().hash(state)
+            }
+        }
+
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Key {
+            Int(i64),
+            Float(f64),
+        }
+
+        struct Entries(Vec<(Key, u32)>);
+
+        impl serde::Serialize for Entries {
+            fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                serializer.collect_map(self.0.iter().map(|&(ref k, v)| (k, v)))
+            }
+        }
+
+        Lua::new().context(|lua| {
+            let ints: HashMap<i64, u32> = vec![(-1, 1), (0, 2), (2, 3), (i64::MAX, 4)]
+                .into_iter().collect();
+            let value = to_value(lua, &ints).unwrap();
+            assert_eq!(from_value::<HashMap<i64, u32>>(value).unwrap(), ints);
+
+            let strings: HashMap<String, u32> = vec![("1".to_owned(), 1), ("a".to_owned(), 2)]
+                .into_iter().collect();
+            let value = to_value(lua, &strings).unwrap();
+            assert_eq!(from_value::<HashMap<String, u32>>(value).unwrap(), strings);
+
+            // Whole-number floats are stored as integers, and read back as
+            // the same float.
+            let floats: HashMap<FloatKey, u32> = vec![(FloatKey(2.0), 1), (FloatKey(2.5), 2)]
+                .into_iter().collect();
+            let value = to_value(lua, &floats).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load("assert(value[2] == 1 and math.type(next({[2.0] = 0})) == 'integer')")
+                .exec().unwrap();
+            assert_eq!(from_value::<HashMap<FloatKey, u32>>(value).unwrap(), floats);
+
+            for entries in [
+                vec![(Key::Int(2), 1), (Key::Float(2.0), 2)],
+                vec![(Key::Float(2.0), 1), (Key::Int(2), 2)],
+                vec![(Key::Float(2.0), 1), (Key::Float(2.0), 2)],
+            ] {
+                let err = to_value(lua, Entries(entries)).unwrap_err();
+                assert!(err.to_string().contains("duplicate map key 2"), "{}", err);
+            }
+            let value = to_value(lua, Entries(vec![(Key::Int(2), 1), (Key::Float(2.5), 2)]));
+            assert!(value.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_compact() {
+        use value::value_stats;
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        enum Shape {
+            Empty,
+            Circle(f64),
+            Rect { width: u32, height: u32 },
+            Path(Vec<(i32, i32)>, bool),
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        struct Layer {
+            name: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            label: Option<String>,
+            shapes: Vec<Shape>,
+            visible: bool,
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        struct Drawing {
+            title: Option<String>,
+            layers: Vec<Layer>,
+            background: Shape,
+        }
+
+        let drawing = Drawing {
+            title: None,
+            layers: vec![
+                Layer {
+                    name: "base".to_owned(),
+                    label: None,
+                    shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { width: 2, height: 3 }],
+                    visible: true,
+                },
+                Layer {
+                    name: "top".to_owned(),
+                    label: Some("overlay".to_owned()),
+                    shapes: vec![Shape::Path(vec![(0, 0), (1, -1)], false)],
+                    visible: false,
+                },
+            ],
+            background: Shape::Empty,
+        };
+        let options = SerializerOptions { compact: true, ..SerializerOptions::default() };
+        let de_options = DeserializerOptions { compact: true, ..DeserializerOptions::default() };
+
+        Lua::new().context(|lua| {
+            let value = to_value_with(lua, &drawing, options.clone()).unwrap();
+            lua.globals().set("value", value.clone()).unwrap();
+            lua.load(r#"
+                assert(value[1] == nil and value[3] == 0)
+                local base = value[2][1]
+                assert(base[1] == "base" and base[2] == nil and base[4] == true)
+                assert(base[3][1] == 0 and base[3][2][1] == 1 and base[3][2][2] == 1.5)
+                assert(base[3][3][1] == 2 and base[3][3][2][1] == 2 and base[3][3][2][2] == 3)
+                assert(value[2][2][2] == "overlay")
+            "#).exec().unwrap();
+
+            let got: Drawing = from_value_with(value.clone(), de_options.clone()).unwrap();
+            assert_eq!(got, drawing);
+
+            let default = to_value(lua, &drawing).unwrap();
+            let compact = value_stats(lua, &value).unwrap();
+            let default = value_stats(lua, &default).unwrap();
+            assert!(compact.tables <= default.tables, "{:?} vs {:?}", compact, default);
+            assert!(compact.string_bytes * 4 < default.string_bytes, "{:?} vs {:?}", compact, default);
+
+            assert!(from_value::<Drawing>(value).is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_depth() {
+        use std::cell::RefCell;
+        use serde::ser::SerializeSeq;
+
+        /// Sequence nested `.0` levels deep, without building it.
+        struct Nested(usize);
+
+        impl serde::Serialize for Nested {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                let mut seq = serializer.serialize_seq(Some(1))?;
+                if self.0 > 1 {
+                    seq.serialize_element(&Nested(self.0 - 1))?;
+                }
+                seq.end()
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Node {
+            name: &'static str,
+            children: Vec<Shared>,
+        }
+
+        struct Shared(Rc<RefCell<Node>>);
+
+        impl serde::Serialize for Shared {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+                self.0.borrow().serialize(serializer)
+            }
+        }
+
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value = to_value(lua, Nested(128)).unwrap();
+            assert_eq!(::value::value_stats(lua, &value).unwrap().max_depth, 128);
+
+            let err = to_value(lua, Nested(129)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(to_value(lua, Nested(100_000)).is_err());
+
+            let options = SerializerOptions { max_depth: Some(2), ..SerializerOptions::default() };
+            assert!(to_value_with(lua, Nested(2), options.clone()).is_ok());
+            assert!(to_value_with(lua, Nested(3), options.clone()).is_err());
+            assert!(to_value_with(lua, vec![vec![vec![1]]], options.clone()).is_err());
+            assert!(to_variadic_with(lua, (1, Nested(2)), options).is_ok());
+
+            let root = Rc::new(RefCell::new(Node { name: "root", children: Vec::new() }));
+            root.borrow_mut().children.push(Shared(root.clone()));
+            let err = to_value(lua, Shared(root.clone())).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            root.borrow_mut().children.clear();
+        });
+    }
 }
@@ -0,0 +1,906 @@
+//! Utilities for inspecting `rlua::Value` trees.
+
+use std::os::raw::c_void;
+
+use rlua::{Context, Value, Table, Error, LightUserData};
+
+use DEFAULT_MAX_DEPTH;
+
+
+/// Options for `values_equal_with`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompareOptions {
+    /// Treat `Integer(n)` and `Number(n as f64)` as equal, as a value
+    /// might change subtype when round-tripping through serde.
+    pub unify_numbers: bool,
+}
+
+
+/// Options for `merge_tables_with`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MergeOptions {
+    /// Replace a base value with an overlay value when exactly one of them is
+    /// a table, instead of failing.
+    pub overwrite_conflicts: bool,
+}
+
+
+/// Structurally compares two Lua values.
+///
+/// Tables are compared deeply and regardless of iteration order. Table keys
+/// are looked up with Lua raw equality, so table-valued keys only match the
+/// same table. Functions, threads and userdata compare by identity.
+/// Self-referential tables are handled: a pair of tables already being
+/// compared further up is assumed to be equal. Tables nested more than
+/// `DEFAULT_MAX_DEPTH` deep are an error.
+pub fn values_equal<'lua>(
+    lua: Context<'lua>, a: &Value<'lua>, b: &Value<'lua>
+) -> Result<bool, Error> {
+    values_equal_with(lua, a, b, CompareOptions::default())
+}
+
+
+/// Like `values_equal`, but with configurable comparison rules.
+pub fn values_equal_with<'lua>(
+    lua: Context<'lua>, a: &Value<'lua>, b: &Value<'lua>, options: CompareOptions
+) -> Result<bool, Error> {
+    let comparer = Comparer {
+        lua,
+        options,
+        in_progress: lua.create_table()?,
+        identity: lua.create_table()?,
+    };
+    comparer.equal(a, b, 0)
+}
+
+
+/// Whether a value is what `()`, unit structs and `None` serialize to, i.e.
+/// `Nil`.
+pub fn is_unit_value(value: &Value) -> bool {
+    matches!(value, Value::Nil)
+}
+
+
+/// Whether a value is nil, an empty string or a table without entries, e.g.
+/// for `SerializerOptions::skip_field_if`.
+pub fn is_empty_value(value: &Value) -> bool {
+    match *value {
+        Value::Nil => true,
+        Value::String(ref s) => s.as_bytes().is_empty(),
+        Value::Table(ref t) => t.clone().pairs::<Value, Value>().next().is_none(),
+        _ => false,
+    }
+}
+
+
+/// Returns the parts of `current` which differ from `baseline`.
+///
+/// Tables without a sequence part on both sides are diffed key by key, keeping
+/// only the keys whose values differ, recursively; keys missing from
+/// `current` are not represented since Lua tables can't hold nil. Any other
+/// differing value, including sequences, is returned as a whole. Equal values
+/// yield `Nil`. Tables of `current` which contain themselves or are nested
+/// more than `DEFAULT_MAX_DEPTH` deep are an error.
+pub fn diff_values<'lua>(
+    lua: Context<'lua>, current: &Value<'lua>, baseline: &Value<'lua>
+) -> Result<Value<'lua>, Error> {
+    let differ = Differ {
+        lua,
+        on_path: lua.create_table()?,
+    };
+    differ.diff(current, baseline, 0)
+}
+
+
+/// Recursively merges `overlay` into `base`.
+///
+/// Values of `overlay` overwrite those of `base`, except where both are
+/// tables without a sequence part, which are merged in turn. Sequences are
+/// replaced as a whole. A table over a non-table or the other way round is an
+/// error, as is merging tables which contain themselves or are nested more
+/// than `DEFAULT_MAX_DEPTH` deep.
+pub fn merge_tables<'lua>(base: &Table<'lua>, overlay: &Table<'lua>) -> Result<(), Error> {
+    merge_tables_with(base, overlay, MergeOptions::default())
+}
+
+
+/// Like `merge_tables`, but with configurable conflict handling.
+pub fn merge_tables_with<'lua>(
+    base: &Table<'lua>, overlay: &Table<'lua>, options: MergeOptions
+) -> Result<(), Error> {
+    merge_nested(base, overlay, options, 0)
+}
+
+
+/// Key marking the tables of `base` being merged into, without a Lua
+/// context to keep a set of them in. Merging only recurses where both sides
+/// hold a table, so a cycle which would make it recurse forever goes through
+/// a table of `base` which is already marked.
+static MERGE_MARKER: u8 = 0;
+
+fn merge_marker() -> LightUserData {
+    LightUserData(&MERGE_MARKER as *const u8 as *mut c_void)
+}
+
+fn is_merge_marker(key: &Value) -> bool {
+    match *key {
+        Value::LightUserData(marker) => marker == merge_marker(),
+        _ => false,
+    }
+}
+
+/// `depth` is the number of tables enclosing `base`.
+fn merge_nested<'lua>(
+    base: &Table<'lua>, overlay: &Table<'lua>, options: MergeOptions, depth: usize
+) -> Result<(), Error> {
+    if let Value::Boolean(true) = base.raw_get(merge_marker())? {
+        return Err(self_reference_error("merged table"));
+    }
+    if depth >= DEFAULT_MAX_DEPTH {
+        return Err(nesting_error("merged table"));
+    }
+    base.raw_set(merge_marker(), true)?;
+    let merged = merge_entries(base, overlay, options, depth);
+    base.raw_set(merge_marker(), Value::Nil)?;
+    merged
+}
+
+fn merge_entries<'lua>(
+    base: &Table<'lua>, overlay: &Table<'lua>, options: MergeOptions, depth: usize
+) -> Result<(), Error> {
+    // Collected first, as marking a nested table of `base` adds a key to it,
+    // which must not happen while it is traversed if it is also `overlay`.
+    let pairs = overlay.clone().pairs::<Value, Value>().collect::<Result<Vec<_>, _>>()?;
+    for (key, value) in pairs {
+        if is_merge_marker(&key) {
+            continue;
+        }
+        match (base.raw_get::<_, Value>(key.clone())?, value) {
+            (Value::Table(ref base), Value::Table(ref value))
+                if base.raw_len() == 0 && value.raw_len() == 0 =>
+            {
+                merge_nested(base, value, options, depth + 1)?;
+            },
+            (Value::Nil, value) => base.raw_set(key, value)?,
+            (Value::Table(_), Value::Table(value)) => base.raw_set(key, value)?,
+            (Value::Table(_), _) | (_, Value::Table(_)) if !options.overwrite_conflicts => {
+                return Err(Error::FromLuaConversionError {
+                    from: "table",
+                    to: "table",
+                    message: Some(format!(
+                        "can't merge a table with a non-table value at key {}", key_name(&key),
+                    )),
+                });
+            },
+            (_, value) => base.raw_set(key, value)?,
+        }
+    }
+    Ok(())
+}
+
+
+/// Size statistics of a Lua value, see `value_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValueStats {
+    /// Number of distinct tables.
+    pub tables: usize,
+    /// Total number of keys of all distinct tables.
+    pub keys: usize,
+    /// Greatest nesting of tables, zero for a value which is not a table.
+    pub max_depth: usize,
+    /// Total length of all strings, keys included, counted once per occurrence.
+    pub string_bytes: usize,
+    /// Whether a table contains itself, directly or not.
+    pub cycle: bool,
+}
+
+
+/// Walks a Lua value and collects size statistics, e.g. to estimate the
+/// cost of deserializing it. Tables reachable by several paths are counted
+/// and walked once. Tables nested more than `DEFAULT_MAX_DEPTH` deep are an
+/// error, as they would be when deserializing.
+pub fn value_stats<'lua>(lua: Context<'lua>, value: &Value<'lua>) -> Result<ValueStats, Error> {
+    let mut walker = StatsWalker {
+        stats: ValueStats::default(),
+        seen: lua.create_table()?,
+        on_path: lua.create_table()?,
+    };
+    let depth = walker.walk(value, 0)?;
+    walker.stats.max_depth = depth;
+    Ok(walker.stats)
+}
+
+
+struct StatsWalker<'lua> {
+    stats: ValueStats,
+    /// Maps each table already walked to its depth.
+    seen: Table<'lua>,
+    on_path: Table<'lua>,
+}
+
+impl<'lua> StatsWalker<'lua> {
+    /// Returns the depth of `value`, which is enclosed in `level` tables.
+    fn walk(&mut self, value: &Value<'lua>, level: usize) -> Result<usize, Error> {
+        let table = match *value {
+            Value::String(ref s) => {
+                self.stats.string_bytes += s.as_bytes().len();
+                return Ok(0);
+            },
+            Value::Table(ref table) => table,
+            _ => return Ok(0),
+        };
+        if self.on_path.raw_get(table.clone())? {
+            self.stats.cycle = true;
+            return Ok(0);
+        }
+        if let Some(depth) = self.seen.raw_get::<_, Option<usize>>(table.clone())? {
+            return Ok(depth);
+        }
+        if level >= DEFAULT_MAX_DEPTH {
+            return Err(nesting_error("value stats"));
+        }
+        self.on_path.raw_set(table.clone(), true)?;
+        self.stats.tables += 1;
+
+        let mut depth = 0;
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            self.stats.keys += 1;
+            depth = depth.max(self.walk(&key, level + 1)?).max(self.walk(&value, level + 1)?);
+        }
+
+        self.on_path.raw_set(table.clone(), Value::Nil)?;
+        self.seen.raw_set(table.clone(), depth + 1)?;
+        Ok(depth + 1)
+    }
+}
+
+
+/// Rebuilds a value into a canonical form, e.g. to fingerprint
+/// configuration with `canonical_bytes`.
+///
+/// Floats with an integral value within `i64` range become integers and
+/// every table becomes a sequence of `{ key, value }` pairs sorted by the
+/// canonical bytes of their key, so that equal tables canonicalize
+/// identically whatever their iteration order. Strings and other scalars are
+/// kept as is. Tables reachable by several paths are canonicalized once and
+/// shared. Self-referential tables, functions, threads and userdata have no
+/// canonical form and are an error, as are tables nested more than
+/// `DEFAULT_MAX_DEPTH` deep.
+pub fn canonicalize<'lua>(lua: Context<'lua>, value: &Value<'lua>) -> Result<Value<'lua>, Error> {
+    let canonicalizer = Canonicalizer {
+        lua,
+        done: lua.create_table()?,
+        on_path: lua.create_table()?,
+    };
+    canonicalizer.canonicalize(value, 0)
+}
+
+
+/// Encodes the canonical form of a value, see `canonicalize`, into bytes
+/// which don't depend on table iteration order nor on the Lua state.
+///
+/// Each value is written as a tag byte followed by its contents: big-endian
+/// 64-bit integers and floats, and strings and tables prefixed with their
+/// length as a big-endian `u64`.
+pub fn canonical_bytes<'lua>(lua: Context<'lua>, value: &Value<'lua>) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    encode_canonical(&canonicalize(lua, value)?, &mut bytes)?;
+    Ok(bytes)
+}
+
+
+/// Copies a value, replacing every value within it, tables included, for
+/// which `f` returns `Some`, e.g. to redact secrets from configuration.
+///
+/// `f` is called on the value itself and then on the values of each table,
+/// recursively, but not on table keys. Values it replaces are not looked
+/// into. Tables are copied along with their metatable, once even if they
+/// are reachable by several paths, and self-referential tables are an error,
+/// as are tables nested more than `DEFAULT_MAX_DEPTH` deep. `value` itself is
+/// left unchanged.
+pub fn transform<'lua>(
+    lua: Context<'lua>, value: &Value<'lua>,
+    f: &mut dyn FnMut(&Value<'lua>) -> Option<Value<'lua>>,
+) -> Result<Value<'lua>, Error> {
+    let mut transformer = Transformer {
+        lua,
+        f,
+        done: lua.create_table()?,
+        on_path: lua.create_table()?,
+    };
+    transformer.transform(value, 0)
+}
+
+
+struct Transformer<'lua, 'f> {
+    lua: Context<'lua>,
+    f: &'f mut dyn FnMut(&Value<'lua>) -> Option<Value<'lua>>,
+    /// Maps each table already copied to its copy.
+    done: Table<'lua>,
+    on_path: Table<'lua>,
+}
+
+impl<'lua, 'f> Transformer<'lua, 'f> {
+    /// `depth` is the number of tables enclosing `value`.
+    fn transform(&mut self, value: &Value<'lua>, depth: usize) -> Result<Value<'lua>, Error> {
+        if let Some(value) = (self.f)(value) {
+            return Ok(value);
+        }
+        let table = match *value {
+            Value::Table(ref table) => table,
+            _ => return Ok(value.clone()),
+        };
+        if let Some(copy) = self.done.raw_get::<_, Option<Table>>(table.clone())? {
+            return Ok(Value::Table(copy));
+        }
+        if self.on_path.raw_get(table.clone())? {
+            return Err(self_reference_error("transformed value"));
+        }
+        if depth >= DEFAULT_MAX_DEPTH {
+            return Err(nesting_error("transformed value"));
+        }
+        self.on_path.raw_set(table.clone(), true)?;
+
+        let copy = self.lua.create_table()?;
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            copy.raw_set(key, self.transform(&value, depth + 1)?)?;
+        }
+        copy.set_metatable(table.get_metatable());
+        self.on_path.raw_set(table.clone(), Value::Nil)?;
+        self.done.raw_set(table.clone(), copy.clone())?;
+        Ok(Value::Table(copy))
+    }
+}
+
+
+struct Differ<'lua> {
+    lua: Context<'lua>,
+    on_path: Table<'lua>,
+}
+
+impl<'lua> Differ<'lua> {
+    /// `depth` is the number of tables enclosing `current`.
+    fn diff(
+        &self, current: &Value<'lua>, baseline: &Value<'lua>, depth: usize
+    ) -> Result<Value<'lua>, Error> {
+        if let (Value::Table(current), Value::Table(baseline)) = (current, baseline) {
+            if current.raw_len() == 0 && baseline.raw_len() == 0 {
+                return self.diff_tables(current, baseline, depth);
+            }
+        }
+        if values_equal(self.lua, current, baseline)? {
+            Ok(Value::Nil)
+        } else {
+            Ok(current.clone())
+        }
+    }
+
+    fn diff_tables(
+        &self, current: &Table<'lua>, baseline: &Table<'lua>, depth: usize
+    ) -> Result<Value<'lua>, Error> {
+        if self.on_path.raw_get(current.clone())? {
+            return Err(self_reference_error("diff"));
+        }
+        if depth >= DEFAULT_MAX_DEPTH {
+            return Err(nesting_error("diff"));
+        }
+        self.on_path.raw_set(current.clone(), true)?;
+
+        let diff = self.lua.create_table()?;
+        for pair in current.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let base = baseline.raw_get::<_, Value>(key.clone())?;
+            match self.diff(&value, &base, depth + 1)? {
+                Value::Nil => (),
+                changed => diff.raw_set(key, changed)?,
+            }
+        }
+        self.on_path.raw_set(current.clone(), Value::Nil)?;
+        Ok(match diff.clone().pairs::<Value, Value>().next() {
+            Some(_) => Value::Table(diff),
+            None => Value::Nil,
+        })
+    }
+}
+
+
+struct Canonicalizer<'lua> {
+    lua: Context<'lua>,
+    /// Maps each table already canonicalized to its canonical form.
+    done: Table<'lua>,
+    on_path: Table<'lua>,
+}
+
+impl<'lua> Canonicalizer<'lua> {
+    /// `depth` is the number of tables enclosing `value`.
+    fn canonicalize(&self, value: &Value<'lua>, depth: usize) -> Result<Value<'lua>, Error> {
+        let table = match *value {
+            Value::Number(n) => return Ok(match integral_float(n) {
+                Some(i) => Value::Integer(i),
+                None => Value::Number(n),
+            }),
+            Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::String(_) => {
+                return Ok(value.clone());
+            },
+            Value::Table(ref table) => table,
+            _ => return Err(canonical_error(value)),
+        };
+        if let Some(canonical) = self.done.raw_get::<_, Option<Table>>(table.clone())? {
+            return Ok(Value::Table(canonical));
+        }
+        if depth >= DEFAULT_MAX_DEPTH {
+            return Err(nesting_error("canonical value"));
+        }
+        if self.on_path.raw_get(table.clone())? {
+            return Err(self_reference_error("canonical value"));
+        }
+        self.on_path.raw_set(table.clone(), true)?;
+
+        let mut pairs = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = self.canonicalize(&key, depth + 1)?;
+            let value = self.canonicalize(&value, depth + 1)?;
+            // Distinct table keys may be equal once canonicalized, in which
+            // case their values decide. Encodings are self-delimiting, so
+            // appending the value's keeps the order by key first.
+            let mut sort_key = Vec::new();
+            encode_canonical(&key, &mut sort_key)?;
+            if let Value::Table(_) = key {
+                encode_canonical(&value, &mut sort_key)?;
+            }
+            pairs.push((sort_key, key, value));
+        }
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let canonical = self.lua.create_table()?;
+        for (i, (_, key, value)) in pairs.into_iter().enumerate() {
+            canonical.raw_set(i + 1, self.lua.create_sequence_from(vec![key, value])?)?;
+        }
+        self.on_path.raw_set(table.clone(), Value::Nil)?;
+        self.done.raw_set(table.clone(), canonical.clone())?;
+        Ok(Value::Table(canonical))
+    }
+}
+
+
+fn encode_canonical(value: &Value, bytes: &mut Vec<u8>) -> Result<(), Error> {
+    match *value {
+        Value::Nil => bytes.push(0),
+        Value::Boolean(b) => bytes.extend_from_slice(&[1, b as u8]),
+        Value::Integer(i) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&i.to_be_bytes());
+        },
+        Value::Number(n) => {
+            let n = if n.is_nan() { f64::NAN } else { n };
+            bytes.push(3);
+            bytes.extend_from_slice(&n.to_bits().to_be_bytes());
+        },
+        Value::String(ref s) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&(s.as_bytes().len() as u64).to_be_bytes());
+            bytes.extend_from_slice(s.as_bytes());
+        },
+        Value::Table(ref table) => {
+            let len = table.raw_len();
+            bytes.push(5);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+            for i in 1..=len {
+                encode_canonical(&table.raw_get::<_, Value>(i)?, bytes)?;
+            }
+        },
+        _ => return Err(canonical_error(value)),
+    }
+    Ok(())
+}
+
+
+fn canonical_error(value: &Value) -> Error {
+    let from = match *value {
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        _ => "userdata",
+    };
+    Error::FromLuaConversionError {
+        from,
+        to: "canonical value",
+        message: Some("only nil, booleans, numbers, strings and tables have a canonical form".to_owned()),
+    }
+}
+
+
+struct Comparer<'lua> {
+    lua: Context<'lua>,
+    options: CompareOptions,
+    /// Maps each table of `a` currently being compared to the set of
+    /// tables of `b` it is being compared against.
+    in_progress: Table<'lua>,
+    /// Scratch set for comparing functions, threads and userdata by
+    /// identity, which rlua only exposes through table keys.
+    identity: Table<'lua>,
+}
+
+impl<'lua> Comparer<'lua> {
+    /// `depth` is the number of tables enclosing `a` and `b`.
+    fn equal(&self, a: &Value<'lua>, b: &Value<'lua>, depth: usize) -> Result<bool, Error> {
+        Ok(match (a, b) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::LightUserData(a), Value::LightUserData(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(i), Value::Number(n)) | (Value::Number(n), Value::Integer(i)) => {
+                self.options.unify_numbers && integral_float_eq(*n, *i)
+            },
+            (Value::String(a), Value::String(b)) => a.as_bytes() == b.as_bytes(),
+            (Value::Table(a), Value::Table(b)) => self.tables_equal(a, b, depth)?,
+            (Value::Function(_), Value::Function(_))
+            | (Value::Thread(_), Value::Thread(_))
+            | (Value::UserData(_), Value::UserData(_)) => self.identical(a, b)?,
+            _ => false,
+        })
+    }
+
+    fn identical(&self, a: &Value<'lua>, b: &Value<'lua>) -> Result<bool, Error> {
+        self.identity.raw_set(a.clone(), true)?;
+        let identical = self.identity.raw_get(b.clone());
+        self.identity.raw_set(a.clone(), Value::Nil)?;
+        identical
+    }
+
+    fn tables_equal(&self, a: &Table<'lua>, b: &Table<'lua>, depth: usize) -> Result<bool, Error> {
+        let against = match self.in_progress.raw_get::<_, Option<Table>>(a.clone())? {
+            Some(against) => against,
+            None => {
+                let against = self.lua.create_table()?;
+                self.in_progress.raw_set(a.clone(), against.clone())?;
+                against
+            },
+        };
+        if against.raw_get(b.clone())? {
+            return Ok(true);
+        }
+        if depth >= DEFAULT_MAX_DEPTH {
+            return Err(nesting_error("compared value"));
+        }
+        against.raw_set(b.clone(), true)?;
+
+        let equal = self.entries_equal(a, b, depth)?;
+        against.raw_set(b.clone(), Value::Nil)?;
+        Ok(equal)
+    }
+
+    fn entries_equal(&self, a: &Table<'lua>, b: &Table<'lua>, depth: usize) -> Result<bool, Error> {
+        let mut count = 0usize;
+        for pair in a.clone().pairs::<Value, Value>() {
+            let (key, a_value) = pair?;
+            let b_value = b.raw_get::<_, Value>(key)?;
+            if let Value::Nil = b_value {
+                return Ok(false);
+            }
+            if !self.equal(&a_value, &b_value, depth + 1)? {
+                return Ok(false);
+            }
+            count += 1;
+        }
+        Ok(b.clone().pairs::<Value, Value>().count() == count)
+    }
+}
+
+
+fn self_reference_error(to: &'static str) -> Error {
+    Error::FromLuaConversionError {
+        from: "table",
+        to,
+        message: Some("table contains itself".to_owned()),
+    }
+}
+
+
+fn nesting_error(to: &'static str) -> Error {
+    Error::FromLuaConversionError {
+        from: "table",
+        to,
+        message: Some(format!(
+            "recursion limit exceeded: more than {} nested tables", DEFAULT_MAX_DEPTH,
+        )),
+    }
+}
+
+
+fn key_name(key: &Value) -> String {
+    match *key {
+        Value::String(ref s) => format!("`{}`", String::from_utf8_lossy(s.as_bytes())),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        _ => "of non-scalar type".to_owned(),
+    }
+}
+
+
+fn integral_float_eq(n: f64, i: i64) -> bool {
+    integral_float(n) == Some(i)
+}
+
+
+pub(crate) fn integral_float(n: f64) -> Option<i64> {
+    // `i64::MAX as f64` rounds up to 2^63, which is out of `i64` range.
+    const RANGE: ::std::ops::Range<f64> = -9_223_372_036_854_775_808.0..9_223_372_036_854_775_808.0;
+    if n.fract() == 0.0 && RANGE.contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rlua::{Lua, Value};
+
+    use to_value;
+    use super::*;
+
+    /// Tables nested `depth` deep, built without the parser's nesting limit.
+    fn nested<'lua>(lua: Context<'lua>, depth: usize) -> Value<'lua> {
+        let code = format!("local t = {{}} for _ = 2, {} do t = {{ t = t }} end return t", depth);
+        lua.load(&code).eval().unwrap()
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        Lua::new().context(|lua| {
+            let (base, overlay): (Table, Table) = lua.load(r#"
+                return { a = 1, b = { c = 2, d = 3 } }, { b = { d = 4, e = 5 }, f = 6 }
+            "#).eval().unwrap();
+            merge_tables(&base, &overlay).unwrap();
+            let expected = lua.load("return { a = 1, b = { c = 2, d = 4, e = 5 }, f = 6 }").eval().unwrap();
+            assert!(values_equal(lua, &Value::Table(base), &expected).unwrap());
+
+            let cyclic: Table = lua.load("local t = { x = 1 } t.t = t return t").eval().unwrap();
+            let err = merge_tables(&cyclic, &cyclic).unwrap_err();
+            assert!(err.to_string().contains("table contains itself"), "{}", err);
+            let (a, b): (Table, Table) = lua.load(r#"
+                local a, b = {}, {}
+                a.t, b.t = a, b
+                return a, b
+            "#).eval().unwrap();
+            assert!(merge_tables(&a, &b).is_err());
+            // Markers don't outlive a failed merge.
+            assert_eq!(cyclic.clone().pairs::<Value, Value>().count(), 2);
+            assert_eq!(a.clone().pairs::<Value, Value>().count(), 1);
+
+            let table = |value| match value {
+                Value::Table(table) => table,
+                _ => unreachable!(),
+            };
+            let deep = table(nested(lua, DEFAULT_MAX_DEPTH + 1));
+            let err = merge_tables(&deep, &table(nested(lua, DEFAULT_MAX_DEPTH + 1))).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(merge_tables(&table(nested(lua, 100_000)), &table(nested(lua, 100_000))).is_err());
+            let deep = table(nested(lua, DEFAULT_MAX_DEPTH));
+            assert!(merge_tables(&deep, &table(nested(lua, DEFAULT_MAX_DEPTH))).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_diff_values() {
+        Lua::new().context(|lua| {
+            let (current, baseline): (Value, Value) = lua.load(r#"
+                return { a = 1, b = { c = 2, d = 3 }, list = { 1, 2 } },
+                    { a = 1, b = { c = 2, d = 4 }, list = { 1 } }
+            "#).eval().unwrap();
+            let diff = diff_values(lua, &current, &baseline).unwrap();
+            let expected = lua.load("return { b = { d = 3 }, list = { 1, 2 } }").eval().unwrap();
+            assert!(values_equal(lua, &diff, &expected).unwrap());
+            assert!(is_unit_value(&diff_values(lua, &current, &current).unwrap()));
+
+            let cyclic: Value = lua.load("local t = {} t.t = t return t").eval().unwrap();
+            let err = diff_values(lua, &cyclic, &cyclic).unwrap_err();
+            assert!(err.to_string().contains("table contains itself"), "{}", err);
+
+            let deep = nested(lua, DEFAULT_MAX_DEPTH + 1);
+            let err = diff_values(lua, &deep, &nested(lua, DEFAULT_MAX_DEPTH + 1)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(diff_values(lua, &nested(lua, 100_000), &nested(lua, 100_000)).is_err());
+            assert!(diff_values(lua, &nested(lua, DEFAULT_MAX_DEPTH), &nested(lua, DEFAULT_MAX_DEPTH)).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_values_equal() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let (a, b, c): (Value, Value, Value) = lua.load(r#"
+                local a = { 1, 2, { x = "x", y = { true } }, k = 4 }
+                local b = { k = 4 }
+                b[3] = { y = { true }, x = "x" }
+                b[2] = 2
+                b[1] = 1
+                local c = { 1, 2, { x = "x", y = { false } }, k = 4 }
+                return a, b, c
+            "#).eval().unwrap();
+            assert!(values_equal(lua, &a, &b).unwrap());
+            assert!(!values_equal(lua, &a, &c).unwrap());
+
+            let (a, b): (Value, Value) = lua.load(r#"
+                return { 1, n = 2 }, { 1.0, n = 2 }
+            "#).eval().unwrap();
+            assert!(!values_equal(lua, &a, &b).unwrap());
+            let options = CompareOptions { unify_numbers: true };
+            assert!(values_equal_with(lua, &a, &b, options).unwrap());
+
+            let (a, b): (Value, Value) = lua.load(r#"
+                return { 1, 2 }, { 1, 2, 3 }
+            "#).eval().unwrap();
+            assert!(!values_equal(lua, &a, &b).unwrap());
+            assert!(!values_equal(lua, &b, &a).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_values_equal_cycle() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let (a, b, c): (Value, Value, Value) = lua.load(r#"
+                local a = { name = "a" }
+                a.self = a
+                local b = { name = "a" }
+                b.self = b
+                local c = { name = "c" }
+                c.self = c
+                return a, b, c
+            "#).eval().unwrap();
+            assert!(values_equal(lua, &a, &b).unwrap());
+            assert!(!values_equal(lua, &a, &c).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_values_equal_depth() {
+        Lua::new().context(|lua| {
+            let depth = DEFAULT_MAX_DEPTH;
+            assert!(values_equal(lua, &nested(lua, depth), &nested(lua, depth)).unwrap());
+            let err = values_equal(lua, &nested(lua, depth + 1), &nested(lua, depth + 1)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(values_equal(lua, &nested(lua, 100_000), &nested(lua, 100_000)).is_err());
+
+            let (f, g, t): (Value, Value, Value) = lua.load(r#"
+                local f, g = function() end, function() end
+                return f, g, { f, f }
+            "#).eval().unwrap();
+            assert!(values_equal(lua, &f, &f.clone()).unwrap());
+            assert!(!values_equal(lua, &f, &g).unwrap());
+            assert!(values_equal(lua, &t, &t.clone()).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_value_stats() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let value: Value = lua.load(r#"
+                local shared = { "xy" }
+                return { name = "abc", list = { 1, 2, { shared } }, other = shared }
+            "#).eval().unwrap();
+            let expected = ValueStats {
+                tables: 4,
+                keys: 8,
+                max_depth: 4,
+                string_bytes: 2 + 4 + 3 + 4 + 5,
+                cycle: false,
+            };
+            assert_eq!(expected, value_stats(lua, &value).unwrap());
+
+            let value: Value = lua.load(r#"
+                local a = { b = {} }
+                a.b.a = a
+                return a
+            "#).eval().unwrap();
+            let stats = value_stats(lua, &value).unwrap();
+            assert!(stats.cycle);
+            assert_eq!(2, stats.tables);
+
+            let value = Value::Integer(1);
+            assert_eq!(ValueStats::default(), value_stats(lua, &value).unwrap());
+
+            assert_eq!(value_stats(lua, &nested(lua, DEFAULT_MAX_DEPTH)).unwrap().max_depth, DEFAULT_MAX_DEPTH);
+            let err = value_stats(lua, &nested(lua, DEFAULT_MAX_DEPTH + 1)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(value_stats(lua, &nested(lua, 100_000)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_is_unit_value() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            assert!(is_unit_value(&to_value(lua, ()).unwrap()));
+            assert!(is_unit_value(&Value::Nil));
+            assert!(!is_unit_value(&Value::Boolean(false)));
+            assert!(!is_unit_value(&Value::Integer(0)));
+            assert!(!is_unit_value(&Value::Table(lua.create_table().unwrap())));
+        });
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let (a, b): (Value, Value) = lua.load(r#"
+                local a = { name = "x", list = { 1, 2.0, 3.5 }, nested = { p = true, q = false } }
+                local b = { nested = {} }
+                b.nested.q = false
+                b.nested.p = true
+                b.list = { 1.0, 2, 3.5 }
+                b.name = "x"
+                return a, b
+            "#).eval().unwrap();
+            assert_eq!(canonical_bytes(lua, &a).unwrap(), canonical_bytes(lua, &b).unwrap());
+
+            let canonical = canonicalize(lua, &a).unwrap();
+            lua.globals().set("canonical", canonical).unwrap();
+            lua.load(r#"
+                assert(#canonical == 3)
+                assert(canonical[1][1] == "list" and math.type(canonical[1][2][2][2]) == "integer")
+                assert(canonical[2][1] == "name" and canonical[2][2] == "x")
+                assert(canonical[3][1] == "nested")
+            "#).exec().unwrap();
+
+            let c: Value = lua.load(r#"return { name = "y" }"#).eval().unwrap();
+            assert_ne!(canonical_bytes(lua, &a).unwrap(), canonical_bytes(lua, &c).unwrap());
+
+            let cyclic: Value = lua.load("local t = {} t.t = t return t").eval().unwrap();
+            assert!(canonicalize(lua, &cyclic).is_err());
+            assert!(canonicalize(lua, &nested(lua, DEFAULT_MAX_DEPTH)).is_ok());
+            let err = canonicalize(lua, &nested(lua, DEFAULT_MAX_DEPTH + 1)).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(canonical_bytes(lua, &nested(lua, 100_000)).is_err());
+            let function: Value = lua.load("return { f = print }").eval().unwrap();
+            assert!(canonical_bytes(lua, &function).is_err());
+        });
+    }
+
+    #[test]
+    fn test_transform() {
+        Lua::new().context(|lua| {
+            let value: Value = lua.load(r#"
+                local shared = { token = "secret-1" }
+                return {
+                    name = "main",
+                    db = { password = "secret-2", port = 5432, replicas = { shared, shared } },
+                    keys = setmetatable({ "secret-3", "public" }, { __name = "keys" }),
+                }
+            "#).eval().unwrap();
+            let redacted = Value::String(lua.create_string("***").unwrap());
+            let mut redact = |value: &Value| match *value {
+                Value::String(ref s) if s.as_bytes().starts_with(b"secret-") => Some(redacted.clone()),
+                _ => None,
+            };
+            let copy = transform(lua, &value, &mut redact).unwrap();
+            lua.globals().set("original", value).unwrap();
+            lua.globals().set("copy", copy).unwrap();
+            lua.load(r#"
+                assert(copy.name == "main" and copy.db.port == 5432)
+                assert(copy.db.password == "***" and copy.db.replicas[1].token == "***")
+                assert(copy.db.replicas[1] == copy.db.replicas[2])
+                assert(copy.keys[1] == "***" and copy.keys[2] == "public")
+                assert(getmetatable(copy.keys).__name == "keys")
+                assert(original.db.password == "secret-2")
+            "#).exec().unwrap();
+
+            let value: Value = lua.load("local t = {} t.t = t return t").eval().unwrap();
+            let err = transform(lua, &value, &mut |_| None).unwrap_err();
+            assert!(err.to_string().contains("table contains itself"), "{}", err);
+
+            assert!(transform(lua, &nested(lua, DEFAULT_MAX_DEPTH), &mut |_| None).is_ok());
+            let err = transform(lua, &nested(lua, DEFAULT_MAX_DEPTH + 1), &mut |_| None).unwrap_err();
+            assert!(err.to_string().contains("recursion limit exceeded"), "{}", err);
+            assert!(transform(lua, &nested(lua, 100_000), &mut |_| None).is_err());
+        });
+    }
+}
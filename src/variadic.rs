@@ -0,0 +1,217 @@
+//! Serialization of tuples into Lua multiple values.
+//!
+//! `VariadicSerializer` accepts only tuples, tuple structs and the unit
+//! value, and serializes each element into a separate value with
+//! `ser::Serializer`. Everything else is rejected, since it has no natural
+//! multi-value form.
+
+use std::rc::Rc;
+
+use serde;
+use serde::ser::Impossible;
+
+use rlua::{Context, Value, Variadic};
+
+use error::{Error, Result};
+use ser::{ConfiguredSerializer, SerializerOptions};
+
+
+pub struct VariadicSerializer<'lua> {
+    lua: Context<'lua>,
+    options: Rc<SerializerOptions>,
+}
+
+impl<'lua> VariadicSerializer<'lua> {
+    pub fn new(lua: Context<'lua>, options: SerializerOptions) -> Self {
+        VariadicSerializer { lua, options: Rc::new(options) }
+    }
+}
+
+fn not_a_tuple() -> Error {
+    serde::ser::Error::custom("expected a tuple to serialize into multiple values")
+}
+
+impl<'lua> serde::Serializer for VariadicSerializer<'lua> {
+    type Ok = Variadic<Value<'lua>>;
+    type Error = Error;
+
+    type SerializeSeq           = Impossible<Variadic<Value<'lua>>, Error>;
+    type SerializeTuple         = SerializeVariadic<'lua>;
+    type SerializeTupleStruct   = SerializeVariadic<'lua>;
+    type SerializeTupleVariant  = Impossible<Variadic<Value<'lua>>, Error>;
+    type SerializeMap           = Impossible<Variadic<Value<'lua>>, Error>;
+    type SerializeStruct        = Impossible<Variadic<Value<'lua>>, Error>;
+    type SerializeStructVariant = Impossible<Variadic<Value<'lua>>, Error>;
+
+    fn serialize_bool(self, _value: bool) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_i8(self, _value: i8) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_i16(self, _value: i16) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_i32(self, _value: i32) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_i64(self, _value: i64) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_u8(self, _value: u8) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_u16(self, _value: u16) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_u32(self, _value: u32) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_u64(self, _value: u64) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_f32(self, _value: f32) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_f64(self, _value: f64) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_char(self, _value: char) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_str(self, _value: &str) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_bytes(self, _value: &[u8]) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<Self::Ok>
+        where T: ?Sized + serde::Serialize,
+    {
+        Err(not_a_tuple())
+    }
+
+    // The unit value is the empty tuple, so it serializes to no values.
+    fn serialize_unit(self) -> Result<Self::Ok> {
+        Ok(Variadic::new())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_unit_variant(
+        self, _name: &'static str, _variant_index: u32, _variant: &'static str
+    ) -> Result<Self::Ok> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self, _name: &'static str, _value: &T
+    ) -> Result<Self::Ok>
+        where T: ?Sized + serde::Serialize,
+    {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_newtype_variant<T>(
+        self, _name: &'static str, _variant_index: u32,
+        _variant: &'static str, _value: &T,
+    ) -> Result<Self::Ok>
+        where T: ?Sized + serde::Serialize,
+    {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Ok(SerializeVariadic { lua: self.lua, values: Variadic::new(), options: self.options })
+    }
+
+    fn serialize_tuple_struct(
+        self, _name: &'static str, len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(
+        self, _name: &'static str, _variant_index: u32,
+        _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(not_a_tuple())
+    }
+
+    fn serialize_struct_variant(
+        self, _name: &'static str, _variant_index: u32,
+        _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(not_a_tuple())
+    }
+}
+
+
+pub struct SerializeVariadic<'lua> {
+    lua: Context<'lua>,
+    values: Variadic<Value<'lua>>,
+    options: Rc<SerializerOptions>,
+}
+
+impl<'lua> serde::ser::SerializeTuple for SerializeVariadic<'lua> {
+    type Ok = Variadic<Value<'lua>>;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize,
+    {
+        self.values.push(value.serialize(ConfiguredSerializer::from_parts(self.lua, &self.options))?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(self.values)
+    }
+}
+
+impl<'lua> serde::ser::SerializeTupleStruct for SerializeVariadic<'lua> {
+    type Ok = Variadic<Value<'lua>>;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize,
+    {
+        serde::ser::SerializeTuple::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        serde::ser::SerializeTuple::end(self)
+    }
+}